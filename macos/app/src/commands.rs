@@ -1,23 +1,31 @@
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use serde::Serialize;
 use tauri::State;
 use tokio::sync::{Mutex, RwLock};
 
-use uclip_core::clipboard;
+use uclip_core::clipboard::{self, SelectionSettings};
+use uclip_core::config::{self, ReloadReport};
+use uclip_core::confirm::Decision;
+use uclip_core::delivery::Delivery;
+use uclip_core::discovery::{self, Peer};
 use uclip_core::events::AppState;
-use uclip_core::protocol::Message;
-use uclip_core::server;
+use uclip_core::history::make_preview;
+use uclip_core::imaging;
+use uclip_core::policy::DevicePolicy;
+use uclip_core::protocol::{DEFAULT_IMAGE_TYPE, MAX_TEXT_SIZE};
+use uclip_core::queue::{self, Outbound, OutboundContent, QueuedItem};
+use uclip_core::stats::SessionStats;
 
 static NEXT_ID: AtomicU64 = AtomicU64::new(1);
 
 const MAX_CLIPBOARD_ITEMS: usize = 5;
-const PREVIEW_MAX_CHARS: usize = 80;
-/// Max payload size for Noise transport: 65535 - 16 (AEAD tag) - 5 (message header)
-const MAX_SEND_BYTES: usize = 65514;
+
+/// How long sending an item waits for the device to acknowledge it.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Clone, Serialize)]
 pub struct ClipboardItem {
@@ -30,6 +38,7 @@ pub struct ClipboardItem {
     pub size_bytes: Option<u64>,
     pub width: Option<u32>,
     pub height: Option<u32>,
+    pub mime_type: Option<String>,
 }
 
 pub type ImageStore = Arc<Mutex<HashMap<u64, Vec<u8>>>>;
@@ -40,9 +49,18 @@ pub type ClipboardItems = Arc<RwLock<Vec<ClipboardItem>>>;
 #[derive(Serialize)]
 pub struct StatusInfo {
     pub pairing_code: String,
+    /// Port the server is listening on; `None` if it is not (yet) running.
+    pub listen_port: Option<u16>,
+    /// Configured port, which the server may have replaced with a free one.
     pub port: u16,
+    /// Why the server could not listen, if it failed to.
+    pub bind_error: Option<String>,
+    /// Why the config file was rejected; defaults or the last good settings are in use.
+    pub config_error: Option<String>,
     pub device_name: String,
     pub connected_device: Option<String>,
+    /// Items sent while no device is connected are queued for it.
+    pub queue_enabled: bool,
 }
 
 #[derive(Serialize)]
@@ -51,19 +69,6 @@ pub struct DeviceInfo {
     pub key_prefix: String,
 }
 
-fn make_preview(text: &str) -> String {
-    let single_line: String = text
-        .chars()
-        .map(|c| if c == '\n' || c == '\r' { ' ' } else { c })
-        .collect();
-    let trimmed = single_line.trim();
-    if trimmed.chars().count() > PREVIEW_MAX_CHARS {
-        trimmed.chars().take(PREVIEW_MAX_CHARS).collect::<String>() + "…"
-    } else {
-        trimmed.to_string()
-    }
-}
-
 fn now_millis() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -76,9 +81,13 @@ pub async fn get_status(state: State<'_, Arc<AppState>>) -> Result<StatusInfo, S
     let connected = state.connected_device.read().await.clone();
     Ok(StatusInfo {
         pairing_code: state.pairing_code.clone(),
+        listen_port: state.listen_port(),
         port: state.port,
+        bind_error: state.bind_error(),
+        config_error: state.config_error(),
         device_name: state.device_name.clone(),
         connected_device: connected,
+        queue_enabled: state.settings().queue.enabled,
     })
 }
 
@@ -99,17 +108,87 @@ pub async fn get_devices(state: State<'_, Arc<AppState>>) -> Result<Vec<DeviceIn
 
 #[tauri::command]
 pub async fn unpair_device(state: State<'_, Arc<AppState>>, name: String) -> Result<bool, String> {
-    state
+    let removed = state
         .store
         .remove_paired_device(&name)
+        .map_err(|e| e.to_string())?;
+    state.queue.clear(&name).map_err(|e| e.to_string())?;
+    Ok(removed)
+}
+
+#[tauri::command]
+pub async fn get_device_policy(
+    state: State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<DevicePolicy, String> {
+    state.store.device_policy(&name).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn set_device_policy(
+    state: State<'_, Arc<AppState>>,
+    name: String,
+    policy: DevicePolicy,
+) -> Result<(), String> {
+    state
+        .store
+        .set_device_policy(&name, &policy)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn resolve_confirmation(
+    state: State<'_, Arc<AppState>>,
+    id: u64,
+    accept: bool,
+) -> Result<bool, String> {
+    let decision = if accept {
+        Decision::Accept
+    } else {
+        Decision::Reject
+    };
+    Ok(state.confirmations.resolve(id, decision))
+}
+
+#[tauri::command]
+pub async fn get_selection_settings(
+    state: State<'_, Arc<AppState>>,
+) -> Result<SelectionSettings, String> {
+    Ok(state.clipboard.selection())
+}
+
+#[tauri::command]
+pub async fn set_selection_settings(
+    settings: SelectionSettings,
+    state: State<'_, Arc<AppState>>,
+) -> Result<(), String> {
+    state.clipboard.set_selection(settings);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn reload_config(state: State<'_, Arc<AppState>>) -> Result<ReloadReport, String> {
+    config::reload(&state).await.map_err(|e| e.to_string())
+}
+
+/// How long the receiver list browses before answering.
+const DISCOVER_WAIT: Duration = Duration::from_secs(2);
+
+/// Receivers on the local network, marking the ones already paired.
+#[tauri::command]
+pub async fn discover_receivers(state: State<'_, Arc<AppState>>) -> Result<Vec<Peer>, String> {
+    discovery::discover(&state.store, DISCOVER_WAIT)
+        .await
         .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
 pub async fn paste_clipboard(
     items: State<'_, ClipboardItems>,
+    state: State<'_, Arc<AppState>>,
 ) -> Result<Vec<ClipboardItem>, String> {
-    let text = clipboard::get_clipboard_text()
+    let text = clipboard::with_clipboard(&state.clipboard, |cb| cb.get_text())
+        .await
         .map_err(|e| e.to_string())?
         .unwrap_or_default();
 
@@ -136,6 +215,7 @@ pub async fn paste_clipboard(
         size_bytes: None,
         width: None,
         height: None,
+        mime_type: None,
     };
 
     let mut items = items.write().await;
@@ -154,39 +234,55 @@ pub async fn get_clipboard_items(
     Ok(items.clone())
 }
 
+/// Send an item to the connected device. Returns false if the device is not connected and
+/// the item was queued for it instead.
 #[tauri::command]
 pub async fn send_clipboard_item(
     id: u64,
     items: State<'_, ClipboardItems>,
     state: State<'_, Arc<AppState>>,
 ) -> Result<bool, String> {
-    let mut items = items.write().await;
-    let item = items.iter_mut().find(|i| i.id == id);
-    let item = match item {
-        Some(i) => i,
-        None => return Err("item not found".to_string()),
-    };
-
-    let session_tx = state.session_tx.read().await;
-    let tx = match session_tx.as_ref() {
-        Some(tx) => tx,
-        None => return Err("no active session".to_string()),
+    let text = {
+        let items = items.read().await;
+        match items.iter().find(|i| i.id == id) {
+            Some(i) => i.text.clone(),
+            None => return Err("item not found".to_string()),
+        }
     };
 
-    if item.text.len() > MAX_SEND_BYTES {
+    if text.len() > MAX_TEXT_SIZE {
         return Err(format!(
             "text too large to send ({} bytes, max {})",
-            item.text.len(),
-            MAX_SEND_BYTES
+            text.len(),
+            MAX_TEXT_SIZE
         ));
     }
 
-    let msg = Message::clipboard_send(&item.text);
-    tx.send(msg).map_err(|e| e.to_string())?;
+    let content = OutboundContent::Text {
+        text,
+        sensitive: false,
+    };
+    let outbound = queue::send_or_queue(&state, None, content, None)
+        .await
+        .map_err(|e| e.to_string())?;
+    match outbound {
+        Some(Outbound::Sent { pending, .. }) => match pending.outcome(DELIVERY_TIMEOUT).await {
+            Delivery::Delivered => {
+                mark_sent(items.inner(), id).await;
+                Ok(true)
+            }
+            outcome => Err(outcome.to_string()),
+        },
+        Some(Outbound::Queued(_)) => Ok(false),
+        None => Err("no active session".to_string()),
+    }
+}
 
-    // Option A: mark as sent optimistically
-    item.sent = true;
-    Ok(true)
+async fn mark_sent(items: &ClipboardItems, id: u64) {
+    let mut items = items.write().await;
+    if let Some(item) = items.iter_mut().find(|i| i.id == id) {
+        item.sent = true;
+    }
 }
 
 #[tauri::command]
@@ -207,34 +303,39 @@ pub async fn remove_clipboard_item(
 pub async fn paste_image_from_clipboard(
     items: State<'_, ClipboardItems>,
     image_store: State<'_, ImageStore>,
+    state: State<'_, Arc<AppState>>,
 ) -> Result<Vec<ClipboardItem>, String> {
-    let png_bytes = clipboard::get_clipboard_image()
+    let (rgba, width, height) = clipboard::with_clipboard(&state.clipboard, |cb| cb.get_image())
+        .await
         .map_err(|e| e.to_string())?
         .ok_or_else(|| "No image on clipboard".to_string())?;
 
-    let (width, height) = clipboard::png_dimensions(&png_bytes).map_err(|e| e.to_string())?;
+    // Downscale / re-encode so the image fits the send policy; this is CPU-bound
+    let policy = state.settings().image_send;
+    let prepared =
+        tokio::task::spawn_blocking(move || imaging::prepare_image(rgba, width, height, &policy))
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())?;
 
-    let size_bytes = png_bytes.len() as u64;
     let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-    let kb = size_bytes / 1024;
-    let preview = format!("Image ({}x{}, {} KB)", width, height, kb);
-
     let item = ClipboardItem {
         id,
         item_type: "image".to_string(),
         text: String::new(),
-        preview,
+        preview: prepared.preview(),
         timestamp: now_millis(),
         sent: false,
-        size_bytes: Some(size_bytes),
-        width: Some(width),
-        height: Some(height),
+        size_bytes: Some(prepared.bytes.len() as u64),
+        width: Some(prepared.width),
+        height: Some(prepared.height),
+        mime_type: Some(prepared.mime_type.clone()),
     };
 
-    // Store PNG bytes
+    // Store encoded bytes
     {
         let mut store = image_store.lock().await;
-        store.insert(id, png_bytes);
+        store.insert(id, prepared.bytes);
     }
 
     let mut items = items.write().await;
@@ -257,6 +358,7 @@ pub async fn paste_image_from_clipboard(
     Ok(items.clone())
 }
 
+/// Send an image item like `send_clipboard_item`, returning false if it was queued.
 #[tauri::command]
 pub async fn send_image_item(
     id: u64,
@@ -284,7 +386,7 @@ pub async fn send_image_item(
         return Err("item is not an image".to_string());
     }
 
-    let png_bytes = {
+    let image_bytes = {
         let store = image_store.lock().await;
         store
             .get(&id)
@@ -292,41 +394,64 @@ pub async fn send_image_item(
             .ok_or_else(|| "image data not found".to_string())?
     };
 
-    let width = item.width.unwrap_or(0);
-    let height = item.height.unwrap_or(0);
+    let content = OutboundContent::Image {
+        bytes: image_bytes,
+        mime_type: item
+            .mime_type
+            .unwrap_or_else(|| DEFAULT_IMAGE_TYPE.to_string()),
+        width: item.width.unwrap_or(0),
+        height: item.height.unwrap_or(0),
+    };
 
-    let session_tx = state.session_tx.read().await;
-    let tx = match session_tx.as_ref() {
-        Some(tx) => tx.clone(),
-        None => {
+    // Progress arrives as events while we wait for the device's answer
+    let outcome = match queue::send_or_queue(&state, None, content, None).await {
+        Ok(Some(Outbound::Sent { pending, .. })) => pending.outcome(DELIVERY_TIMEOUT).await,
+        Ok(Some(Outbound::Queued(_))) => {
+            transfer_lock.store(false, Ordering::SeqCst);
+            return Ok(false);
+        }
+        Ok(None) => {
             transfer_lock.store(false, Ordering::SeqCst);
             return Err("no active session".to_string());
         }
+        Err(e) => {
+            tracing::error!("image send failed: {}", e);
+            transfer_lock.store(false, Ordering::SeqCst);
+            return Err(e.to_string());
+        }
     };
-
-    let state_inner = state.inner().clone();
-    let transfer_lock_inner = transfer_lock.inner().clone();
-    let items_inner = items.inner().clone();
-
-    tauri::async_runtime::spawn(async move {
-        let result = server::send_image_chunks(&tx, &png_bytes, width, height, &state_inner).await;
-        transfer_lock_inner.store(false, Ordering::SeqCst);
-        match result {
-            Ok(()) => {
-                // Mark item as sent
-                let mut items = items_inner.write().await;
-                if let Some(item) = items.iter_mut().find(|i| i.id == id) {
-                    item.sent = true;
-                }
-            }
-            Err(e) => {
-                tracing::error!("image send failed: {}", e);
-                state_inner.emit(uclip_core::events::ServerEvent::ImageTransferFailed {
-                    reason: e.to_string(),
-                });
-            }
+    transfer_lock.store(false, Ordering::SeqCst);
+    match outcome {
+        Delivery::Delivered => {
+            mark_sent(items.inner(), id).await;
+            Ok(true)
         }
-    });
+        // The session already reported it as TransferRejected
+        Delivery::Rejected { reason, .. } => Err(reason),
+        outcome => {
+            state.emit(uclip_core::events::ServerEvent::ImageTransferFailed {
+                reason: outcome.to_string(),
+            });
+            Err(outcome.to_string())
+        }
+    }
+}
 
-    Ok(true)
+/// Items waiting for devices that are not connected, oldest first.
+#[tauri::command]
+pub async fn get_queue(state: State<'_, Arc<AppState>>) -> Result<Vec<QueuedItem>, String> {
+    state.queue.items(None).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn cancel_queued(state: State<'_, Arc<AppState>>, id: u64) -> Result<bool, String> {
+    queue::cancel(&state, id).map_err(|e| e.to_string())
+}
+
+/// Counters of the connected device's session; `None` while no device is connected.
+#[tauri::command]
+pub async fn get_session_stats(
+    state: State<'_, Arc<AppState>>,
+) -> Result<Option<SessionStats>, String> {
+    Ok(state.stats.snapshot())
 }
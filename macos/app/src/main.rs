@@ -4,22 +4,33 @@
 mod commands;
 
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use tauri::async_runtime::JoinHandle;
 use tauri::menu::{MenuBuilder, MenuItemBuilder};
 use tauri::tray::TrayIconBuilder;
-use tauri::{AppHandle, Emitter, Manager, WebviewUrl, WebviewWindowBuilder};
-use tokio::net::TcpListener;
+use tauri::{AppHandle, Emitter, Manager, RunEvent, WebviewUrl, WebviewWindowBuilder};
 use tokio_util::sync::CancellationToken;
 use tracing::info;
 
+use uclip_core::config::{self, Config, ConfigLoader, ConfigOverrides};
+use uclip_core::control;
 use uclip_core::crypto;
-use uclip_core::discovery::DiscoveryServer;
 use uclip_core::events::AppState;
 use uclip_core::server;
 use uclip_core::storage::DeviceStore;
 
+/// How long quitting waits for the session to say BYE; covers the server's
+/// grace period for finishing an image that is being sent.
+const SHUTDOWN_WAIT: Duration = Duration::from_secs(6);
+
+/// Stops the server and control socket when the app exits.
+struct Shutdown {
+    cancel: CancellationToken,
+    server: Mutex<Option<JoinHandle<()>>>,
+}
+
 /// Timestamp (millis) of the last tray icon click, used to suppress
 /// the blur-hide that races with the tray toggle.
 static LAST_TRAY_CLICK_MS: AtomicU64 = AtomicU64::new(0);
@@ -66,28 +77,35 @@ fn main() {
             }
 
             // Initialize core state
+            // An invalid config still starts the app, on defaults; the panel shows why
+            let loader = ConfigLoader::new(Config::default_path()?, ConfigOverrides::default());
+            let (config, config_error) = match loader.load() {
+                Ok(config) => (config, None),
+                Err(e) => {
+                    tracing::error!("{:#}; using default settings", e);
+                    (Config::default(), Some(format!("{:#}", e)))
+                }
+            };
             let store = DeviceStore::default_location()?;
             let identity = crypto::Identity::load_or_generate(&store)?;
             let pairing_code = crypto::generate_pairing_code();
-            let port = 9876u16;
-            let device_name = hostname();
+            let port = config.port;
+            let device_name = config.device_name.clone().unwrap_or_else(hostname);
 
             info!(
                 "starting with pairing code: {}, port: {}",
                 pairing_code, port
             );
 
-            let state = Arc::new(AppState::new(
-                identity,
-                pairing_code,
-                device_name.clone(),
-                store,
-                port,
-            ));
+            let mut state = AppState::new(identity, pairing_code, device_name, store, port);
+            state.bind = config.bind.clone();
+            state.config = Some(loader);
+            state.set_config_error(config_error);
+            let state = Arc::new(state);
+            tauri::async_runtime::block_on(config::apply(&state, &config));
 
             // Store state in Tauri's managed state
             app.manage(state.clone());
-            app.manage(CancellationToken::new());
             app.manage(commands::ClipboardItems::default());
             app.manage(commands::ImageStore::default());
             app.manage(commands::TransferLock::default());
@@ -101,22 +119,32 @@ fn main() {
                 }
             });
 
-            // Spawn server
+            // Spawn the control socket so `uclip status` etc. talk to the app
             let cancel = CancellationToken::new();
-            let server_state = state.clone();
+            let socket = control::socket_path(&state.store);
+            let control_state = state.clone();
+            let control_cancel = cancel.clone();
             tauri::async_runtime::spawn(async move {
-                // Start mDNS
-                let _discovery =
-                    DiscoveryServer::new(port, &device_name).expect("failed to start mDNS");
-
-                let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
-                    .await
-                    .expect("failed to bind TCP listener");
+                if let Err(e) =
+                    control::run_control_server(socket, control_state, control_cancel).await
+                {
+                    tracing::warn!("control socket unavailable: {}", e);
+                }
+            });
 
-                if let Err(e) = server::run_server(listener, server_state, cancel).await {
-                    tracing::error!("server error: {}", e);
+            // Spawn server; a port it cannot listen on reaches the UI as BindFailed, or
+            // through get_status if the window opens later
+            let server_state = state.clone();
+            let server_cancel = cancel.clone();
+            let server = tauri::async_runtime::spawn(async move {
+                if let Err(e) = server::start(server_state, server_cancel).await {
+                    tracing::error!("server error: {:#}", e);
                 }
             });
+            app.manage(Shutdown {
+                cancel,
+                server: Mutex::new(Some(server)),
+            });
 
             Ok(())
         })
@@ -124,15 +152,47 @@ fn main() {
             commands::get_status,
             commands::get_devices,
             commands::unpair_device,
+            commands::get_device_policy,
+            commands::set_device_policy,
+            commands::resolve_confirmation,
+            commands::get_selection_settings,
+            commands::set_selection_settings,
+            commands::reload_config,
+            commands::discover_receivers,
             commands::paste_clipboard,
             commands::get_clipboard_items,
             commands::send_clipboard_item,
             commands::remove_clipboard_item,
             commands::paste_image_from_clipboard,
             commands::send_image_item,
+            commands::get_queue,
+            commands::cancel_queued,
+            commands::get_session_stats,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let RunEvent::Exit = event {
+                shutdown(app);
+            }
+        });
+}
+
+/// Cancel the server so a connected device gets a BYE, and wait for it briefly.
+fn shutdown(app: &AppHandle) {
+    let Some(shutdown) = app.try_state::<Shutdown>() else {
+        return;
+    };
+    shutdown.cancel.cancel();
+    let server = shutdown.server.lock().unwrap().take();
+    if let Some(server) = server {
+        tauri::async_runtime::block_on(async {
+            if tokio::time::timeout(SHUTDOWN_WAIT, server).await.is_err() {
+                tracing::warn!("server did not stop within {:?}", SHUTDOWN_WAIT);
+            }
+        });
+    }
+    info!("shut down");
 }
 
 fn setup_tray(app: &AppHandle) -> Result<(), Box<dyn std::error::Error>> {
@@ -7,8 +7,10 @@ const deviceName = document.getElementById("deviceName");
 const pairingCode = document.getElementById("pairingCode");
 const connectionSection = document.getElementById("connectionSection");
 const connectedDevice = document.getElementById("connectedDevice");
+const sessionStats = document.getElementById("sessionStats");
 const devicesList = document.getElementById("devicesList");
 const portInfo = document.getElementById("portInfo");
+const configError = document.getElementById("configError");
 const pasteBtn = document.getElementById("pasteBtn");
 const pasteImageBtn = document.getElementById("pasteImageBtn");
 const clipboardList = document.getElementById("clipboardList");
@@ -17,15 +19,45 @@ const transferProgress = document.getElementById("transferProgress");
 const transferLabel = document.getElementById("transferLabel");
 const transferFill = document.getElementById("transferFill");
 
+const queueSection = document.getElementById("queueSection");
+const queueList = document.getElementById("queueList");
+
+const confirmSection = document.getElementById("confirmSection");
+const confirmDevice = document.getElementById("confirmDevice");
+const confirmPreview = document.getElementById("confirmPreview");
+const confirmAcceptBtn = document.getElementById("confirmAcceptBtn");
+const confirmRejectBtn = document.getElementById("confirmRejectBtn");
+
 let isConnected = false;
+let queueEnabled = false;
+let pendingConfirmId = null;
 let isTransferActive = false;
+// Set when the connected device stops answering PINGs, until its disconnect is shown
+let unresponsiveDevice = null;
 
 async function loadStatus() {
   try {
     const status = await invoke("get_status");
     pairingCode.textContent = status.pairing_code;
     deviceName.textContent = status.device_name;
-    portInfo.textContent = `Port ${status.port}`;
+    queueEnabled = status.queue_enabled;
+    if (status.config_error) {
+      configError.textContent = `Config file ignored: ${status.config_error}`;
+      configError.classList.remove("hidden");
+    } else {
+      configError.classList.add("hidden");
+    }
+
+    if (status.bind_error) {
+      // The server is not running; BindFailed was emitted before this window listened
+      statusDot.className = "status-dot error";
+      statusText.textContent = `Cannot listen on port ${status.port}`;
+      portInfo.textContent = status.bind_error;
+      connectionSection.style.display = "none";
+      isConnected = false;
+      return;
+    }
+    portInfo.textContent = status.listen_port ? `Port ${status.listen_port}` : "Starting…";
 
     if (status.connected_device) {
       statusDot.className = "status-dot connected";
@@ -33,6 +65,7 @@ async function loadStatus() {
       connectionSection.style.display = "";
       connectedDevice.textContent = status.connected_device;
       isConnected = true;
+      renderSessionStats(await invoke("get_session_stats"));
     } else {
       statusDot.className = "status-dot";
       statusText.textContent = "Waiting for connection";
@@ -85,6 +118,17 @@ function formatTime(epochMs) {
   return `${h}:${m}:${s}`;
 }
 
+function renderSessionStats(stats) {
+  if (!stats) {
+    sessionStats.textContent = "";
+    return;
+  }
+  const rtt = stats.rtt_ms === null ? "" : ` · ${stats.rtt_ms} ms round trip`;
+  sessionStats.textContent =
+    `${stats.transfers_completed} transferred, ${stats.transfers_failed} failed · ` +
+    `${formatBytes(stats.bytes_in)} in, ${formatBytes(stats.bytes_out)} out${rtt}`;
+}
+
 function formatBytes(bytes) {
   if (bytes >= 1024 * 1024) {
     return (bytes / (1024 * 1024)).toFixed(1) + " MB";
@@ -102,7 +146,7 @@ function renderClipboardItems(items) {
     .map((item) => {
       const isImage = item.item_type === "image";
       const icon = isImage ? "🖼" : "";
-      const sendDisabled = !isConnected || isTransferActive;
+      const sendDisabled = !canSend();
       return `
     <div class="clipboard-item ${isImage ? "clipboard-item-image" : ""}" data-id="${item.id}">
       <div class="clipboard-item-left">
@@ -132,6 +176,8 @@ function renderClipboardItems(items) {
           await invoke("send_clipboard_item", { id });
         }
         loadClipboardItems();
+loadQueue();
+        loadQueue();
       } catch (e) {
         console.error("Failed to send clipboard item:", e);
       }
@@ -178,9 +224,59 @@ pasteImageBtn.addEventListener("click", async () => {
   }
 });
 
+// Without a connection items can still be sent into the queue
+function canSend() {
+  return (isConnected || queueEnabled) && !isTransferActive;
+}
+
 function updateSendButtons() {
   clipboardList.querySelectorAll(".send-btn").forEach((btn) => {
-    btn.disabled = !isConnected || isTransferActive;
+    btn.disabled = !canSend();
+  });
+}
+
+async function loadQueue() {
+  try {
+    const items = await invoke("get_queue");
+    renderQueue(items);
+  } catch (e) {
+    console.error("Failed to load queue:", e);
+  }
+}
+
+function renderQueue(items) {
+  queueSection.classList.toggle("hidden", items.length === 0);
+  queueList.innerHTML = items
+    .map((item) => {
+      const preview =
+        item.kind === "image"
+          ? `🖼 ${item.width}x${item.height} ${formatBytes(item.bytes)}`
+          : item.text;
+      return `
+    <div class="clipboard-item" data-id="${item.id}">
+      <div class="clipboard-item-left">
+        <div class="clipboard-item-preview">${escapeHtml(preview)}</div>
+        <div class="clipboard-item-meta">
+          <span class="clipboard-item-time">${escapeHtml(item.device)} · expires ${formatTime(item.expires_at)}</span>
+        </div>
+      </div>
+      <div class="clipboard-item-actions">
+        <button class="delete-btn" data-id="${item.id}" title="Cancel">&times;</button>
+      </div>
+    </div>
+  `;
+    })
+    .join("");
+
+  queueList.querySelectorAll(".delete-btn").forEach((btn) => {
+    btn.addEventListener("click", async () => {
+      try {
+        await invoke("cancel_queued", { id: Number(btn.dataset.id) });
+        loadQueue();
+      } catch (e) {
+        console.error("Failed to cancel queued item:", e);
+      }
+    });
   });
 }
 
@@ -205,6 +301,32 @@ function hideTransferProgress() {
   updateSendButtons();
 }
 
+function showConfirmation(request) {
+  pendingConfirmId = request.id;
+  confirmDevice.textContent = request.device;
+  confirmPreview.textContent = request.preview;
+  confirmSection.classList.remove("hidden");
+}
+
+function hideConfirmation() {
+  pendingConfirmId = null;
+  confirmSection.classList.add("hidden");
+}
+
+async function resolveConfirmation(accept) {
+  if (pendingConfirmId === null) return;
+  const id = pendingConfirmId;
+  hideConfirmation();
+  try {
+    await invoke("resolve_confirmation", { id, accept });
+  } catch (e) {
+    console.error("Failed to resolve confirmation:", e);
+  }
+}
+
+confirmAcceptBtn.addEventListener("click", () => resolveConfirmation(true));
+confirmRejectBtn.addEventListener("click", () => resolveConfirmation(false));
+
 function escapeHtml(str) {
   const div = document.createElement("div");
   div.textContent = str;
@@ -224,6 +346,11 @@ listen("server-event", (event) => {
       statusDot.className = "status-dot";
       statusText.textContent = "Waiting for connection";
       break;
+    case "BindFailed":
+      statusDot.className = "status-dot error";
+      statusText.textContent = `Cannot listen on port ${data.data.port}`;
+      portInfo.textContent = data.data.reason;
+      break;
     case "DeviceConnected":
       statusDot.className = "status-dot connected";
       statusText.textContent = "Connected";
@@ -233,12 +360,29 @@ listen("server-event", (event) => {
       updateSendButtons();
       loadDevices();
       break;
+    case "PeerUnresponsive":
+      unresponsiveDevice = data.data.name;
+      break;
     case "DeviceDisconnected":
-      statusDot.className = "status-dot";
-      statusText.textContent = "Waiting for connection";
+      if (unresponsiveDevice) {
+        statusDot.className = "status-dot error";
+        statusText.textContent = `${unresponsiveDevice} stopped responding`;
+        unresponsiveDevice = null;
+        setTimeout(() => {
+          if (!isConnected) {
+            statusDot.className = "status-dot";
+            statusText.textContent = "Waiting for connection";
+          }
+        }, 3000);
+      } else {
+        statusDot.className = "status-dot";
+        statusText.textContent = "Waiting for connection";
+      }
       connectionSection.style.display = "none";
+      renderSessionStats(null);
       isConnected = false;
       updateSendButtons();
+      hideConfirmation();
       break;
     case "ClipboardReceived":
       break;
@@ -254,16 +398,42 @@ listen("server-event", (event) => {
     case "ImageReceived":
       hideTransferProgress();
       loadClipboardItems();
+      loadQueue();
       break;
     case "ImageSent":
       hideTransferProgress();
       loadClipboardItems();
+      loadQueue();
       break;
     case "ImageTransferFailed":
       transferLabel.textContent = "Transfer failed: " + (data.data.reason || "Unknown error");
       transferFill.style.width = "0%";
       setTimeout(hideTransferProgress, 3000);
       break;
+    case "ConfirmationRequested":
+      showConfirmation(data.data);
+      break;
+    case "ConfirmationResolved":
+      if (data.data.id === pendingConfirmId) {
+        hideConfirmation();
+      }
+      hideTransferProgress();
+      break;
+    case "TransferRejected":
+      if (!data.data.incoming) {
+        showTransferProgress("Not sent: " + data.data.reason, 0);
+        setTimeout(hideTransferProgress, 3000);
+      }
+      break;
+    case "SessionStats":
+      renderSessionStats(data.data);
+      break;
+    case "QueueChanged":
+      loadQueue();
+      break;
+    case "ConfigReloaded":
+      loadStatus().then(updateSendButtons);
+      break;
     case "HandshakeFailed":
       statusDot.className = "status-dot error";
       statusText.textContent = "Handshake failed";
@@ -279,3 +449,4 @@ listen("server-event", (event) => {
 loadStatus();
 loadDevices();
 loadClipboardItems();
+loadQueue();
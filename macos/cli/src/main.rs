@@ -1,12 +1,30 @@
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand};
-use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc};
 use tokio_util::sync::CancellationToken;
 
-use uclip_core::events::AppState;
-use uclip_core::{crypto, discovery, server, storage};
+use uclip_core::clipboard::sniff_image_type;
+use uclip_core::clipboard::ClipboardSelection;
+use uclip_core::config::{self, Config, ConfigLoader, ConfigOverrides, ReloadReport};
+use uclip_core::confirm::Decision;
+use uclip_core::control::{
+    self, CancelQueuedParams, ControlClient, DeviceEntry, OpenPairingParams, QueueParams, Queued,
+    RpcError, SendImageParams, SendTextParams, StatusInfo, UnpairParams,
+};
+use uclip_core::discovery::{self, DiscoveryClient};
+use uclip_core::events::{AppState, ServerEvent};
+use uclip_core::net::BindTarget;
+use uclip_core::pairing::PairingStatus;
+use uclip_core::policy::Direction;
+use uclip_core::queue::QueuedItem;
+use uclip_core::stats::SessionStats;
+use uclip_core::{crypto, history, server, storage};
 
 #[derive(Parser)]
 #[command(
@@ -14,6 +32,10 @@ use uclip_core::{crypto, discovery, server, storage};
     about = "Universal Clipboard - P2P encrypted clipboard receiver"
 )]
 struct Cli {
+    /// Config file to use instead of the default (also settable with UCLIP_CONFIG)
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -21,15 +43,57 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Start the clipboard receiver daemon
+    ///
+    /// Flags override UCLIP_* environment variables, which override the config file.
     Listen {
-        /// Port to listen on
-        #[arg(short, long, default_value_t = 9876)]
-        port: u16,
+        /// Port to listen on [default: 9876]
+        #[arg(short, long)]
+        port: Option<u16>,
 
-        /// Device name for mDNS advertisement
-        #[arg(short, long, default_value = "My Mac")]
-        name: String,
+        /// Address or interface name to listen on; repeat for several [default: 0.0.0.0 and ::]
+        #[arg(long, value_name = "ADDR|IFACE")]
+        bind: Vec<BindTarget>,
+
+        /// Device name for mDNS advertisement [default: My Mac]
+        #[arg(short, long)]
+        name: Option<String>,
+
+        /// Treat received text that looks like a secret (card number, private key) as sensitive
+        #[arg(long, overrides_with = "no_detect_sensitive")]
+        detect_sensitive: bool,
+
+        /// Do not look for secrets in received text, even if the config file enables it
+        #[arg(long, overrides_with = "detect_sensitive")]
+        no_detect_sensitive: bool,
+
+        /// Seconds before sensitive content is cleared from the clipboard (0 = never) [default: 60]
+        #[arg(long)]
+        clear_sensitive_after: Option<u64>,
+
+        /// Ask on the terminal before applying content from any device
+        #[arg(long, overrides_with = "no_confirm")]
+        confirm: bool,
+
+        /// Apply content without asking, even if the config file enables confirmation
+        #[arg(long, overrides_with = "confirm")]
+        no_confirm: bool,
+
+        /// Seconds to wait for a confirmation answer before rejecting [default: 30]
+        #[arg(long)]
+        confirm_timeout: Option<u64>,
+
+        /// Linux selection that received text is written to: clipboard, primary or both
+        #[arg(long)]
+        selection: Option<ClipboardSelection>,
+
+        /// Linux selection that text to send is read from: clipboard, primary or both
+        #[arg(long)]
+        selection_source: Option<ClipboardSelection>,
     },
+    /// Show the config file location and the effective settings
+    Config,
+    /// Make the running daemon re-read its config file
+    Reload,
     /// Show current pairing info
     Status,
     /// List paired devices
@@ -39,79 +103,546 @@ enum Commands {
         /// Name of the device to unpair
         name: String,
     },
+    /// Send text, stdin, an image or a file to the connected device through the daemon
+    #[command(
+        after_help = "Exit status: 0 delivered or queued, 1 other error, 2 no daemon running, \
+                            3 no device connected, 4 rejected, 5 timed out"
+    )]
+    Send {
+        /// Text to send; read from stdin when neither text, --image nor --file is given
+        text: Option<String>,
+
+        /// Image file to send (resized to fit the daemon's image settings)
+        #[arg(long, conflicts_with_all = ["text", "file"])]
+        image: Option<PathBuf>,
+
+        /// File to send: images are sent as images, UTF-8 files as text
+        #[arg(long, conflicts_with = "text")]
+        file: Option<PathBuf>,
+
+        /// Mark the text as sensitive so the device keeps it out of its history
+        #[arg(long)]
+        sensitive: bool,
+
+        /// Seconds to wait for the device to acknowledge (0 = return once queued)
+        #[arg(long, default_value_t = 60)]
+        timeout: u64,
+
+        /// Paired device to queue for if it is not the connected one (needs [queue] enabled)
+        #[arg(long, value_name = "DEVICE")]
+        to: Option<String>,
+
+        /// Drop the item if it is still queued after this many seconds [default: queue.expiry_secs]
+        #[arg(long, value_name = "SECS")]
+        expire: Option<u64>,
+    },
+    /// Show or cancel content queued for devices that are not connected
+    Queue {
+        #[command(subcommand)]
+        action: Option<QueueAction>,
+
+        /// Only list items for this device
+        #[arg(long, value_name = "DEVICE")]
+        device: Option<String>,
+    },
+    /// Print the running daemon's events as JSON lines
+    Events {
+        /// Only print events of this type, e.g. ClipboardReceived (repeatable or comma-separated)
+        #[arg(
+            long = "type",
+            value_name = "TYPE",
+            value_delimiter = ',',
+            value_parser = clap::builder::PossibleValuesParser::new(ServerEvent::TYPES)
+        )]
+        types: Vec<String>,
+    },
+    /// List Universal Clipboard receivers on the local network
+    Discover {
+        /// Seconds to browse before printing the list
+        #[arg(long, default_value_t = 3)]
+        wait: u64,
+
+        /// Keep browsing and print receivers appearing and disappearing as JSON lines
+        #[arg(long)]
+        watch: bool,
+    },
+    /// Show, open or close the running daemon's pairing window
+    Pairing {
+        #[command(subcommand)]
+        action: Option<PairingAction>,
+    },
+    /// Show or change the transfer policy of a paired device
+    Policy {
+        /// Name of the paired device
+        name: String,
+
+        /// Accept text from the device
+        #[arg(long)]
+        allow_text: Option<bool>,
+
+        /// Accept images from the device
+        #[arg(long)]
+        allow_images: Option<bool>,
+
+        /// Largest accepted item in bytes (0 = no limit)
+        #[arg(long)]
+        max_bytes: Option<usize>,
+
+        /// Require confirmation before incoming content is applied
+        #[arg(long)]
+        confirm: Option<bool>,
+
+        /// Allowed direction: both, receive-only or send-only
+        #[arg(long)]
+        direction: Option<Direction>,
+    },
     /// Reset identity (generates new keypair, removes all pairings)
     Reset,
 }
 
+#[derive(Subcommand)]
+enum PairingAction {
+    /// Accept new pairings
+    Open {
+        /// Close again after this many seconds (default: stay open)
+        #[arg(long = "for", value_name = "SECS")]
+        seconds: Option<u64>,
+    },
+    /// Refuse new pairings; paired devices can still connect
+    Close,
+}
+
+#[derive(Subcommand)]
+enum QueueAction {
+    /// Remove a queued item before it is delivered
+    Cancel {
+        /// ID shown by `uclip queue`
+        id: u64,
+    },
+}
+
+/// Exit codes besides 0 (success) and 1 (any other error); all but the first are `uclip send`'s.
+const EXIT_NO_DAEMON: u8 = 2;
+const EXIT_NO_SESSION: u8 = 3;
+const EXIT_REJECTED: u8 = 4;
+const EXIT_TIMEOUT: u8 = 5;
+
+/// What `uclip send` delivers.
+enum Payload {
+    Text(String),
+    Image(Vec<u8>, &'static str),
+}
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     tracing_subscriber::fmt::init();
 
     let cli = Cli::parse();
     let store = storage::DeviceStore::default_location()?;
+    let socket = control::socket_path(&store);
+    let config_path = match cli.config {
+        Some(path) => path,
+        None => Config::default_path()?,
+    };
 
     match cli.command {
-        Commands::Listen { port, name } => {
+        Commands::Listen {
+            port,
+            bind,
+            name,
+            detect_sensitive,
+            no_detect_sensitive,
+            clear_sensitive_after,
+            confirm,
+            no_confirm,
+            confirm_timeout,
+            selection,
+            selection_source,
+        } => {
+            let flags = ConfigOverrides {
+                port,
+                bind: (!bind.is_empty()).then_some(bind),
+                device_name: name,
+                selection,
+                selection_source,
+                detect_sensitive: switch(detect_sensitive, no_detect_sensitive),
+                clear_sensitive_after,
+                confirm: switch(confirm, no_confirm),
+                confirm_timeout,
+            };
+            let loader = ConfigLoader::new(config_path, flags);
+            let config = loader.load()?;
+            let port = config.port;
+            let name = config
+                .device_name
+                .clone()
+                .unwrap_or_else(|| "My Mac".to_string());
             let identity = crypto::Identity::load_or_generate(&store)?;
             let pairing_code = crypto::generate_pairing_code();
 
+            let mut state = AppState::new(identity, pairing_code, name, store, port);
+            state.bind = config.bind.clone();
+            state.config = Some(loader);
+            let state = Arc::new(state);
+            config::apply(&state, &config).await;
+            let cancel = CancellationToken::new();
+            let listeners = server::bind_listeners(&state)?;
+
             println!("========================================");
             println!("  Universal Clipboard Receiver");
             println!("========================================");
-            println!("  Device:  {}", name);
-            println!("  Key:     {}...", &identity.public_key_hex()[..16]);
-            println!("  Port:    {}", port);
+            println!("  Device:  {}", state.device_name);
+            println!("  Key:     {}...", &state.identity.public_key_hex()[..16]);
+            for listener in &listeners {
+                println!("  Listen:  {}", listener.local_addr()?);
+            }
             println!("----------------------------------------");
-            println!("  PAIRING CODE:  {}", pairing_code);
+            println!("  PAIRING CODE:  {}", state.pairing_code);
             println!("----------------------------------------");
             println!("  Enter this code on your Android device");
             println!("  to pair. Code changes on each restart.");
             println!("========================================");
 
-            let state = Arc::new(AppState::new(
-                identity,
-                pairing_code,
-                name.clone(),
-                store,
-                port,
-            ));
-            let cancel = CancellationToken::new();
+            // Re-read the config on SIGHUP
+            spawn_reload_on_hangup(state.clone())?;
+
+            // Say goodbye to the connected device on Ctrl-C / SIGTERM
+            spawn_shutdown_on_signal(cancel.clone())?;
+
+            // Device policies may require confirmation even without --confirm
+            spawn_confirmation_prompt(state.clone());
+
+            // Local control socket for status, devices, pairing, ...
+            let control_state = state.clone();
+            let control_cancel = cancel.clone();
+            let control = tokio::spawn(async move {
+                if let Err(e) =
+                    control::run_control_server(socket, control_state, control_cancel).await
+                {
+                    eprintln!("Control socket unavailable: {:#}", e);
+                }
+            });
 
-            // Start mDNS advertisement
-            let _discovery = discovery::DiscoveryServer::new(port, &name)?;
+            server::serve(listeners, state, cancel).await?;
+            // Let the control server remove its socket
+            let _ = control.await;
+            println!("Stopped.");
+        }
 
-            // Start TCP listener
-            let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
-            server::run_server(listener, state, cancel).await?;
+        Commands::Config => {
+            let loader = ConfigLoader::new(config_path, ConfigOverrides::default());
+            let exists = loader.path().exists();
+            println!(
+                "# {}{}",
+                loader.path().display(),
+                if exists { "" } else { " (not found, defaults)" }
+            );
+            print!("{}", loader.load()?.to_toml()?);
+        }
+
+        Commands::Reload => {
+            let Some(mut daemon) = ControlClient::connect_if_running(&socket).await? else {
+                bail!("no uclip daemon is running (start one with `uclip listen`)");
+            };
+            let report: ReloadReport = daemon.call("reload_config", ()).await?;
+            println!("Config reloaded.");
+            if !report.restart_required.is_empty() {
+                println!(
+                    "Restart the daemon to apply: {}",
+                    report.restart_required.join(", ")
+                );
+            }
         }
 
         Commands::Status => {
+            if let Some(mut daemon) = ControlClient::connect_if_running(&socket).await? {
+                let status: StatusInfo = daemon.call("status", ()).await?;
+                println!(
+                    "Daemon:         running ({}, port {})",
+                    status.device_name, status.port
+                );
+                println!("Pairing code:   {}", status.pairing_code);
+                println!("Pairing:        {}", describe_pairing(&status.pairing));
+                match status.connected_device {
+                    Some(device) => println!("Connected:      {}", device),
+                    None => println!("Connected:      no device"),
+                }
+                if let Some(session) = &status.session {
+                    print_session_stats(session);
+                }
+                println!("Public key:     {}", status.public_key);
+                println!("Paired devices: {}", status.paired_devices);
+                return Ok(ExitCode::SUCCESS);
+            }
             let identity = crypto::Identity::load_or_generate(&store)?;
-            println!("Public key: {}", identity.public_key_hex());
+            println!("Daemon:         not running");
+            println!("Public key:     {}", identity.public_key_hex());
             let devices = store.list_paired_devices()?;
             println!("Paired devices: {}", devices.len());
         }
 
         Commands::Devices => {
-            let devices = store.list_paired_devices()?;
+            let devices: Vec<DeviceEntry> = match ControlClient::connect_if_running(&socket).await?
+            {
+                Some(mut daemon) => daemon.call("devices", ()).await?,
+                None => store
+                    .list_paired_devices()?
+                    .into_iter()
+                    .map(|(name, public_key)| DeviceEntry {
+                        name,
+                        public_key,
+                        connected: false,
+                    })
+                    .collect(),
+            };
             if devices.is_empty() {
                 println!("No paired devices.");
             } else {
                 println!("Paired devices:");
-                for (name, key) in &devices {
-                    println!("  {} (key: {}...)", name, &key[..16]);
+                for device in &devices {
+                    let connected = if device.connected { ", connected" } else { "" };
+                    println!(
+                        "  {} (key: {}...{})",
+                        device.name,
+                        &device.public_key[..16],
+                        connected
+                    );
                 }
             }
         }
 
         Commands::Unpair { name } => {
-            if store.remove_paired_device(&name)? {
+            let removed = match ControlClient::connect_if_running(&socket).await? {
+                Some(mut daemon) => {
+                    daemon
+                        .call("unpair", UnpairParams { name: name.clone() })
+                        .await?
+                }
+                None => store.remove_paired_device(&name)?,
+            };
+            if removed {
                 println!("Unpaired device: {}", name);
             } else {
                 println!("Device not found: {}", name);
             }
         }
 
+        Commands::Send {
+            text,
+            image,
+            file,
+            sensitive,
+            timeout,
+            to,
+            expire,
+        } => {
+            let payload = read_payload(text, image, file)?;
+            let Some(mut daemon) = ControlClient::connect_if_running(&socket).await? else {
+                eprintln!("No uclip daemon is running (start one with `uclip listen`).");
+                return Ok(ExitCode::from(EXIT_NO_DAEMON));
+            };
+            let wait_secs = (timeout > 0).then_some(timeout);
+            let result: Result<Queued> = match payload {
+                Payload::Text(text) => {
+                    let params = SendTextParams {
+                        text,
+                        sensitive,
+                        wait_secs,
+                        device: to,
+                        expire_secs: expire,
+                    };
+                    daemon.call("send_text", params).await
+                }
+                Payload::Image(bytes, mime_type) => {
+                    let params = SendImageParams {
+                        device: to,
+                        expire_secs: expire,
+                        ..SendImageParams::new(&bytes, Some(mime_type), wait_secs)
+                    };
+                    daemon.call("send_image", params).await
+                }
+            };
+            match result {
+                Ok(queued) if queued.delivered => {
+                    println!("Delivered to {} ({} bytes)", queued.device, queued.bytes)
+                }
+                Ok(Queued {
+                    device,
+                    bytes,
+                    queue_id: Some(id),
+                    ..
+                }) => println!(
+                    "{} is not connected; queued as item {} ({} bytes)",
+                    device, id, bytes
+                ),
+                Ok(queued) => println!("Queued for {} ({} bytes)", queued.device, queued.bytes),
+                Err(e) => {
+                    eprintln!("Send failed: {}", e);
+                    let code = match e.downcast_ref::<RpcError>().map(|e| e.code) {
+                        Some(control::NO_SESSION) => EXIT_NO_SESSION,
+                        Some(control::REJECTED) => EXIT_REJECTED,
+                        Some(control::TIMEOUT) => EXIT_TIMEOUT,
+                        _ => 1,
+                    };
+                    return Ok(ExitCode::from(code));
+                }
+            }
+        }
+
+        Commands::Queue { action, device } => {
+            let Some(mut daemon) = ControlClient::connect_if_running(&socket).await? else {
+                bail!("no uclip daemon is running (start one with `uclip listen`)");
+            };
+            if let Some(QueueAction::Cancel { id }) = action {
+                if daemon
+                    .call("cancel_queued", CancelQueuedParams { id })
+                    .await?
+                {
+                    println!("Cancelled item {}", id);
+                } else {
+                    println!("Item {} is not queued", id);
+                }
+                return Ok(ExitCode::SUCCESS);
+            }
+            let items: Vec<QueuedItem> = daemon.call("queue", QueueParams { device }).await?;
+            if items.is_empty() {
+                println!("Nothing queued.");
+            } else {
+                println!("Queued:");
+            }
+            let now = uclip_core::history::now_millis();
+            for item in &items {
+                let expires = item.expires_at.saturating_sub(now) / 1000;
+                println!(
+                    "  {:>4}  {} - {} (expires in {}s)",
+                    item.id,
+                    item.device,
+                    item.preview(),
+                    expires
+                );
+            }
+        }
+
+        Commands::Events { types } => {
+            let Some(mut daemon) = ControlClient::connect_if_running(&socket).await? else {
+                eprintln!("No uclip daemon is running (start one with `uclip listen`).");
+                return Ok(ExitCode::from(EXIT_NO_DAEMON));
+            };
+            daemon.subscribe(&types).await?;
+            let mut stdout = std::io::stdout();
+            while let Some(event) = daemon.next_event().await? {
+                // Stop quietly when the reader goes away (`uclip events | head`)
+                if writeln!(stdout, "{}", event).is_err() {
+                    break;
+                }
+            }
+        }
+
+        Commands::Discover { wait, watch } => {
+            if watch {
+                let mut client = DiscoveryClient::browse(&store)?;
+                let mut stdout = std::io::stdout();
+                while let Some(event) = client.next_event().await {
+                    if writeln!(stdout, "{}", serde_json::to_string(&event)?).is_err() {
+                        break;
+                    }
+                }
+                return Ok(ExitCode::SUCCESS);
+            }
+            let peers = discovery::discover(&store, Duration::from_secs(wait)).await?;
+            if peers.is_empty() {
+                println!("No receivers found.");
+            } else {
+                println!("Receivers:");
+            }
+            for peer in &peers {
+                let addrs: Vec<String> = peer.addrs.iter().map(|a| a.to_string()).collect();
+                let mut notes = Vec::new();
+                match &peer.txt {
+                    Some(txt) => {
+                        notes.push(format!("v{}", txt.version));
+                        if txt.pairing_open {
+                            notes.push("pairing open".to_string());
+                        }
+                    }
+                    None => notes.push("no TXT record".to_string()),
+                }
+                if let Some(name) = &peer.paired {
+                    notes.push(format!("paired as {}", name));
+                }
+                if peer.local {
+                    notes.push("this machine".to_string());
+                }
+                println!(
+                    "  {} - {} port {} ({})",
+                    peer.name,
+                    addrs.join(", "),
+                    peer.port,
+                    notes.join(", ")
+                );
+            }
+        }
+
+        Commands::Pairing { action } => {
+            let Some(mut daemon) = ControlClient::connect_if_running(&socket).await? else {
+                bail!("no uclip daemon is running (start one with `uclip listen`)");
+            };
+            let status: PairingStatus = match action {
+                Some(PairingAction::Open { seconds }) => {
+                    daemon
+                        .call("open_pairing", OpenPairingParams { seconds })
+                        .await?
+                }
+                Some(PairingAction::Close) => daemon.call("close_pairing", ()).await?,
+                None => daemon.call::<StatusInfo>("status", ()).await?.pairing,
+            };
+            println!("Pairing: {}", describe_pairing(&status));
+        }
+
+        Commands::Policy {
+            name,
+            allow_text,
+            allow_images,
+            max_bytes,
+            confirm,
+            direction,
+        } => {
+            let mut policy = store.device_policy(&name)?;
+            let changed = allow_text.is_some()
+                || allow_images.is_some()
+                || max_bytes.is_some()
+                || confirm.is_some()
+                || direction.is_some();
+            if let Some(v) = allow_text {
+                policy.allow_text = v;
+            }
+            if let Some(v) = allow_images {
+                policy.allow_images = v;
+            }
+            if let Some(v) = max_bytes {
+                policy.max_bytes = (v > 0).then_some(v);
+            }
+            if let Some(v) = confirm {
+                policy.require_confirmation = v;
+            }
+            if let Some(v) = direction {
+                policy.direction = v;
+            }
+            if changed {
+                store.set_device_policy(&name, &policy)?;
+                println!("Updated policy for {}", name);
+            }
+            println!("Policy for {}:", name);
+            println!("  Allow text:    {}", policy.allow_text);
+            println!("  Allow images:  {}", policy.allow_images);
+            match policy.max_bytes {
+                Some(max) => println!("  Max bytes:     {}", max),
+                None => println!("  Max bytes:     no limit"),
+            }
+            println!("  Confirm:       {}", policy.require_confirmation);
+            println!("  Direction:     {}", policy.direction);
+        }
+
         Commands::Reset => {
             println!("This will delete your identity and all pairings.");
             println!("Are you sure? Type 'yes' to confirm:");
@@ -128,5 +659,167 @@ async fn main() -> Result<()> {
         }
     }
 
+    Ok(ExitCode::SUCCESS)
+}
+
+/// A `--flag` / `--no-flag` pair as an override: `None` when neither was given.
+fn switch(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// Work out what `uclip send` was asked to send.
+fn read_payload(
+    text: Option<String>,
+    image: Option<PathBuf>,
+    file: Option<PathBuf>,
+) -> Result<Payload> {
+    if let Some(path) = image {
+        let bytes =
+            std::fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+        let Some(mime_type) = sniff_image_type(&bytes) else {
+            bail!("{} is not a PNG, JPEG, GIF or WebP image", path.display());
+        };
+        return Ok(Payload::Image(bytes, mime_type));
+    }
+    if let Some(path) = file {
+        let bytes =
+            std::fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+        if let Some(mime_type) = sniff_image_type(&bytes) {
+            return Ok(Payload::Image(bytes, mime_type));
+        }
+        let text = String::from_utf8(bytes).map_err(|_| {
+            anyhow::anyhow!("{} is neither an image nor UTF-8 text", path.display())
+        })?;
+        return Ok(Payload::Text(text));
+    }
+    let text = match text {
+        Some(text) => text,
+        None => {
+            let mut input = String::new();
+            std::io::stdin()
+                .read_to_string(&mut input)
+                .context("stdin is not UTF-8 text")?;
+            input
+        }
+    };
+    if text.is_empty() {
+        bail!("nothing to send");
+    }
+    Ok(Payload::Text(text))
+}
+
+fn print_session_stats(stats: &SessionStats) {
+    let secs = history::now_millis().saturating_sub(stats.connected_at) / 1000;
+    println!("Session:        {}m {}s", secs / 60, secs % 60);
+    println!(
+        "Traffic:        {} messages / {} bytes in, {} messages / {} bytes out",
+        stats.messages_in, stats.bytes_in, stats.messages_out, stats.bytes_out
+    );
+    println!(
+        "Transfers:      {} completed, {} failed",
+        stats.transfers_completed, stats.transfers_failed
+    );
+    match (stats.rtt_ms, stats.avg_rtt_ms) {
+        (Some(rtt), Some(avg)) => println!("Round trip:     {} ms (average {} ms)", rtt, avg),
+        _ => println!("Round trip:     not measured yet"),
+    }
+}
+
+fn describe_pairing(status: &PairingStatus) -> String {
+    match (status.open, status.remaining_secs) {
+        (true, Some(secs)) => format!("open ({}s left)", secs),
+        (true, None) => "open".to_string(),
+        (false, _) => "closed".to_string(),
+    }
+}
+
+fn spawn_reload_on_hangup(state: Arc<AppState>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            if let Err(e) = config::reload(&state).await {
+                eprintln!("Config not reloaded: {:#}", e);
+            }
+        }
+    });
     Ok(())
 }
+
+/// Cancel on the first SIGINT/SIGTERM so the session can end with a BYE; exit on the second.
+fn spawn_shutdown_on_signal(cancel: CancellationToken) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+        eprintln!("Shutting down...");
+        cancel.cancel();
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+        std::process::exit(130);
+    });
+    Ok(())
+}
+
+/// Answer confirmation requests from the terminal.
+fn spawn_confirmation_prompt(state: Arc<AppState>) {
+    let (line_tx, mut line_rx) = mpsc::unbounded_channel::<String>();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else { break };
+            if line_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut events = state.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            let ServerEvent::ConfirmationRequested {
+                id,
+                device,
+                kind,
+                preview,
+                timeout_secs,
+                ..
+            } = event
+            else {
+                continue;
+            };
+
+            // Ignore anything typed before the prompt appeared
+            while line_rx.try_recv().is_ok() {}
+            println!("Incoming {} from {}: {}", kind, device, preview);
+            println!("Accept? [y/N] (rejects automatically in {}s)", timeout_secs);
+            let answer =
+                tokio::time::timeout(Duration::from_secs(timeout_secs), line_rx.recv()).await;
+            let decision = match answer {
+                Ok(Some(line)) if matches!(line.trim(), "y" | "Y" | "yes") => Decision::Accept,
+                _ => Decision::Reject,
+            };
+            if !state.confirmations.resolve(id, decision) {
+                println!("Request expired.");
+            } else if decision == Decision::Accept {
+                println!("Accepted.");
+            } else {
+                println!("Rejected.");
+            }
+        }
+    });
+}
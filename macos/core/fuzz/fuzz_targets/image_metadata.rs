@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use uclip_core::protocol::ImageMetadata;
+
+// IMAGE_SEND_START payloads are JSON chosen by the peer.
+fuzz_target!(|data: &[u8]| {
+    let Ok(json) = std::str::from_utf8(data) else {
+        return;
+    };
+    if let Ok(meta) = ImageMetadata::from_json(json) {
+        let reparsed = ImageMetadata::from_json(&meta.to_json()).expect("own output must parse");
+        assert_eq!(reparsed, meta);
+    }
+});
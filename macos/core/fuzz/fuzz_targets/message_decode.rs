@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use uclip_core::protocol::Message;
+
+// Decrypted Noise payloads go straight into Message::decode.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = Message::decode(data) {
+        // Trailing bytes are ignored, everything before them must round-trip
+        let encoded = msg.encode();
+        assert_eq!(encoded, &data[..encoded.len()]);
+        let _ = msg.payload_text();
+    }
+});
@@ -0,0 +1,54 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tokio::io::AsyncWriteExt;
+use uclip_core::crypto;
+use uclip_core_fuzz::{fixture, runtime};
+
+/// Input records: `[kind 1B][len 2B BE][bytes]`. Even kinds are encrypted and framed by a
+/// well-behaved peer (so valid messages reach `Message::decode`); odd kinds are written to
+/// the socket as-is, corrupting the length-prefixed Noise framing.
+fn records(mut data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut records = Vec::new();
+    while data.len() >= 3 {
+        let len = u16::from_be_bytes([data[1], data[2]]) as usize;
+        let end = (3 + len).min(data.len());
+        records.push((data[0], &data[3..end]));
+        data = &data[end..];
+    }
+    records
+}
+
+fuzz_target!(|data: &[u8]| {
+    let fixture = fixture();
+    let store = fixture.store();
+    runtime().block_on(async {
+        let (peer_side, receiver_side) = tokio::io::duplex(1 << 20);
+        let (initiator, responder) = tokio::join!(
+            crypto::handshake_paired_initiator(
+                peer_side,
+                &fixture.peer,
+                &fixture.receiver.public_key
+            ),
+            crypto::accept_connection(receiver_side, &fixture.receiver, Some("000000"), &store),
+        );
+        let mut initiator = initiator.expect("paired handshake failed");
+        let (mut responder, _) = responder.expect("paired handshake failed");
+
+        let writer = async move {
+            for (kind, bytes) in records(data) {
+                let sent = if kind % 2 == 0 {
+                    initiator.send(bytes).await.is_ok()
+                } else {
+                    initiator.stream_mut().write_all(bytes).await.is_ok()
+                };
+                if !sent {
+                    break;
+                }
+            }
+            // Dropping the transport closes the stream, ending the reader
+        };
+        let reader = async move { while responder.recv_message().await.is_ok() {} };
+        tokio::join!(writer, reader);
+    });
+});
@@ -0,0 +1,75 @@
+#![no_main]
+
+use std::collections::VecDeque;
+use std::sync::{Arc, OnceLock};
+
+use anyhow::{bail, Result};
+use libfuzzer_sys::fuzz_target;
+use tokio_util::sync::CancellationToken;
+use uclip_core::clipboard::MemoryClipboard;
+use uclip_core::config::Settings;
+use uclip_core::crypto::{Identity, MessageTransport};
+use uclip_core::events::AppState;
+use uclip_core::protocol::Message;
+use uclip_core::sensitive::SensitiveSettings;
+use uclip_core::server;
+use uclip_core_fuzz::{fixture, runtime, split_frames, PEER_NAME};
+
+/// Feeds decrypted frames to the session as if they came off the wire; replies are dropped.
+struct ScriptedTransport {
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl MessageTransport for ScriptedTransport {
+    async fn send_message(&mut self, _msg: &Message) -> Result<()> {
+        Ok(())
+    }
+
+    async fn recv_message(&mut self) -> Result<Message> {
+        match self.frames.pop_front() {
+            Some(frame) => Message::decode(&frame),
+            None => bail!("script finished"),
+        }
+    }
+}
+
+fn state() -> &'static Arc<AppState> {
+    static STATE: OnceLock<Arc<AppState>> = OnceLock::new();
+    STATE.get_or_init(|| {
+        let fixture = fixture();
+        let identity = Identity {
+            private_key: fixture.receiver.private_key.clone(),
+            public_key: fixture.receiver.public_key.clone(),
+        };
+        let mut state = AppState::new(
+            identity,
+            "000000".to_string(),
+            "fuzz-receiver".to_string(),
+            fixture.store(),
+            0,
+        );
+        state.clipboard = Arc::new(MemoryClipboard::default());
+        // Don't leave auto-clear timers behind between iterations
+        state.set_settings(Settings {
+            sensitive: SensitiveSettings {
+                clear_after: None,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        Arc::new(state)
+    })
+}
+
+// Sequences of post-handshake messages, driving the receive state machine
+// (metadata, image reassembly, policy checks) in `handle_session`.
+fuzz_target!(|data: &[u8]| {
+    let transport = ScriptedTransport {
+        frames: split_frames(data).into_iter().map(<[u8]>::to_vec).collect(),
+    };
+    let state = state();
+    let cancel = CancellationToken::new();
+    runtime().block_on(async {
+        let _ = server::handle_session(transport, PEER_NAME, state, &cancel).await;
+    });
+});
@@ -0,0 +1,159 @@
+#!/usr/bin/env python3
+"""Regenerate fuzz/seeds/ from the message table and flows in protocol/PROTOCOL.md.
+
+Run from anywhere: python3 macos/core/fuzz/gen_seeds.py
+"""
+
+import re
+import shutil
+import struct
+import zlib
+from pathlib import Path
+
+FUZZ_DIR = Path(__file__).resolve().parent
+PROTOCOL_MD = FUZZ_DIR.parents[2] / "protocol" / "PROTOCOL.md"
+SEEDS_DIR = FUZZ_DIR / "seeds"
+
+
+def png_chunk(kind, data):
+    return (
+        struct.pack(">I", len(data))
+        + kind
+        + data
+        + struct.pack(">I", zlib.crc32(kind + data) & 0xFFFFFFFF)
+    )
+
+
+# Smallest valid PNG (1x1 RGBA), standing in for "raw encoded image bytes"
+TINY_PNG = (
+    b"\x89PNG\r\n\x1a\n"
+    + png_chunk(b"IHDR", struct.pack(">IIBBBBB", 1, 1, 8, 6, 0, 0, 0))
+    + png_chunk(b"IDAT", zlib.compress(b"\x00\xff\x00\x00\xff"))
+    + png_chunk(b"IEND", b"")
+)
+
+
+def parse_message_table(text):
+    """Return {NAME: (type_byte, example_payload)} from the Message Types table."""
+    messages = {}
+    row = re.compile(r"^\|\s*0x([0-9A-Fa-f]{2})\s*\|\s*([A-Z_]+)\s*\|(.*)\|\s*$")
+    for line in text.splitlines():
+        m = row.match(line)
+        if not m:
+            continue
+        type_byte = int(m.group(1), 16)
+        name = m.group(2)
+        description = m.group(3)
+        examples = re.findall(r"`([^`]*)`", description)
+        json_examples = [e for e in examples if e.startswith("{")]
+        if json_examples:
+            # Turn the table's placeholders into valid JSON
+            example = json_examples[0].replace(", ...]", "]").replace('"..."', '"example"')
+            example = re.sub(r":([A-Z])([,}])", r":1\2", example)
+            payload = example.encode()
+        elif "Empty" in description:
+            payload = b""
+        elif "image bytes" in description:
+            payload = TINY_PNG
+        else:
+            payload = b"hello from android"
+        messages[name] = (type_byte, payload)
+    return messages
+
+
+def frame(messages, name, payload=None):
+    type_byte, example = messages[name]
+    body = example if payload is None else payload
+    return struct.pack(">BI", type_byte, len(body)) + body
+
+
+def flows(messages):
+    """Message sequences described in the Flow / Image Transfer Flow sections."""
+    png_meta = (
+        '{"width":1,"height":1,"totalBytes":%d,"mimeType":"image/png"}' % len(TINY_PNG)
+    ).encode()
+    device_info = frame(messages, "DEVICE_INFO", b'{"name":"Pixel","imageTypes":["image/png"]}')
+    return {
+        "text": [device_info, frame(messages, "CLIPBOARD_SEND")],
+        "sensitive_text": [
+            device_info,
+            frame(messages, "CLIPBOARD_META"),
+            frame(messages, "CLIPBOARD_SEND", b"hunter2"),
+        ],
+        "keepalive": [device_info, frame(messages, "PING"), frame(messages, "PONG")],
+        "image": [
+            device_info,
+            frame(messages, "IMAGE_SEND_START", png_meta),
+            frame(messages, "IMAGE_CHUNK", TINY_PNG[:40]),
+            frame(messages, "IMAGE_CHUNK", TINY_PNG[40:]),
+            frame(messages, "IMAGE_SEND_END"),
+        ],
+        "image_aborted": [
+            device_info,
+            frame(messages, "IMAGE_SEND_START", png_meta),
+            frame(messages, "IMAGE_CHUNK", TINY_PNG[:40]),
+            frame(messages, "ERROR"),
+        ],
+        "goodbye_mid_image": [
+            device_info,
+            frame(messages, "IMAGE_SEND_START", png_meta),
+            frame(messages, "IMAGE_CHUNK", TINY_PNG[:40]),
+            frame(messages, "BYE"),
+        ],
+        "image_windowed": [
+            frame(
+                messages,
+                "DEVICE_INFO",
+                b'{"name":"Pixel","imageTypes":["image/png"],"window":40}',
+            ),
+            frame(messages, "IMAGE_SEND_START", png_meta),
+            frame(messages, "IMAGE_CHUNK", TINY_PNG[:40]),
+            frame(messages, "WINDOW_UPDATE", b'{"bytes":40}'),
+            frame(messages, "IMAGE_CHUNK", TINY_PNG[40:]),
+            frame(messages, "IMAGE_SEND_END"),
+        ],
+        "image_too_large": [
+            device_info,
+            frame(messages, "IMAGE_SEND_START", b'{"totalBytes":26214401}'),
+            frame(messages, "IMAGE_CHUNK", TINY_PNG),
+            frame(messages, "IMAGE_SEND_END"),
+        ],
+    }
+
+
+def write(target, name, data):
+    path = SEEDS_DIR / target / name
+    path.parent.mkdir(parents=True, exist_ok=True)
+    path.write_bytes(data)
+
+
+def main():
+    messages = parse_message_table(PROTOCOL_MD.read_text())
+    if not messages:
+        raise SystemExit(f"no message types found in {PROTOCOL_MD}")
+    shutil.rmtree(SEEDS_DIR, ignore_errors=True)
+
+    for name in messages:
+        write("message_decode", name.lower(), frame(messages, name))
+
+    for name, payload in [
+        ("table", messages["IMAGE_SEND_START"][1]),
+        ("jpeg", b'{"width":640,"height":480,"totalBytes":48213,"mimeType":"image/jpeg"}'),
+        ("legacy", b'{"width":1,"height":1,"totalBytes":%d}' % len(TINY_PNG)),
+    ]:
+        write("image_metadata", name, payload)
+
+    for name, frames in flows(messages).items():
+        write("session", name, b"".join(frames))
+        # noise_frame records: [kind=0 (encrypt)][len u16][frame]
+        write(
+            "noise_frame",
+            name,
+            b"".join(struct.pack(">BH", 0, len(f)) + f for f in frames),
+        )
+
+    print(f"wrote seeds for {len(messages)} message types to {SEEDS_DIR}")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,74 @@
+//! Helpers shared by the fuzz targets.
+
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use tokio::runtime::Runtime;
+use uclip_core::crypto::Identity;
+use uclip_core::storage::DeviceStore;
+
+/// One runtime for all iterations; building it per input would dominate the run time.
+pub fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to build tokio runtime")
+    })
+}
+
+/// Split input into protocol frames (`[type 1B][len 4B BE][payload]`), the same layout as
+/// the seeds. A length running past the end of the input is cut short, so the last frame
+/// may be truncated; decoding it is part of what is being fuzzed.
+pub fn split_frames(mut data: &[u8]) -> Vec<&[u8]> {
+    let mut frames = Vec::new();
+    while !data.is_empty() {
+        let len = if data.len() >= 5 {
+            u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize
+        } else {
+            0
+        };
+        let end = (5 + len).min(data.len());
+        frames.push(&data[..end]);
+        data = &data[end..];
+    }
+    frames
+}
+
+/// Name the fuzzing peer is paired under in the fixture store.
+pub const PEER_NAME: &str = "fuzz-peer";
+
+/// Receiver and peer identities, with the peer already paired in the receiver's store.
+pub struct Fixture {
+    pub receiver: Identity,
+    pub peer: Identity,
+    pub store_dir: PathBuf,
+}
+
+impl Fixture {
+    /// A fresh handle on the receiver's store (`DeviceStore` is not `Clone`).
+    pub fn store(&self) -> DeviceStore {
+        DeviceStore::new(self.store_dir.clone()).expect("failed to open fuzz store")
+    }
+}
+
+pub fn fixture() -> &'static Fixture {
+    static FIXTURE: OnceLock<Fixture> = OnceLock::new();
+    FIXTURE.get_or_init(|| {
+        let base = std::env::temp_dir().join(format!("uclip-fuzz-{}", std::process::id()));
+        let store_dir = base.join("receiver");
+        let store = DeviceStore::new(store_dir.clone()).expect("failed to open fuzz store");
+        let receiver = Identity::load_or_generate(&store).expect("receiver identity");
+        let peer_store = DeviceStore::new(base.join("peer")).expect("failed to open fuzz store");
+        let peer = Identity::load_or_generate(&peer_store).expect("peer identity");
+        store
+            .save_paired_device(PEER_NAME, &peer.public_key)
+            .expect("failed to pair fuzz peer");
+        Fixture {
+            receiver,
+            peer,
+            store_dir,
+        }
+    })
+}
@@ -1,20 +1,265 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use arboard::Clipboard;
-use tracing::info;
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use tracing::{debug, info};
 
-/// Write text content to the system clipboard.
-pub fn set_clipboard_text(text: &str) -> Result<()> {
-    let mut clipboard = Clipboard::new().context("failed to access clipboard")?;
-    clipboard
-        .set_text(text)
-        .context("failed to write to clipboard")?;
-    info!("clipboard updated ({} chars)", text.len());
+use crate::imaging::{self, ImageSendPolicy, PreparedImage};
+use crate::protocol::{DeviceInfo, DEFAULT_IMAGE_TYPE};
+
+/// Access to a clipboard. The server and sensitive-content handling go through
+/// `AppState::clipboard`, so tests can swap the system clipboard for `MemoryClipboard`.
+pub trait ClipboardBackend: Send + Sync {
+    /// Read the current text, or `None` if the clipboard holds no text.
+    fn get_text(&self) -> Result<Option<String>>;
+
+    fn set_text(&self, text: &str) -> Result<()>;
+
+    /// Read text back from where `set_text` writes it, which can differ from where
+    /// `get_text` reads when selection settings are in use.
+    fn get_received_text(&self) -> Result<Option<String>> {
+        self.get_text()
+    }
+
+    /// Read the current image as (rgba_bytes, width, height), or `None` if there is none.
+    fn get_image(&self) -> Result<Option<(Vec<u8>, u32, u32)>>;
+
+    fn set_image(&self, rgba: Vec<u8>, width: u32, height: u32) -> Result<()>;
+
+    fn clear(&self) -> Result<()>;
+
+    /// Clear where `set_text` writes if the text there satisfies `matches`, checking and
+    /// clearing in one step. With several selections, each is checked and cleared on its
+    /// own. Returns whether anything was cleared.
+    fn clear_received_if(&self, matches: TextMatcher) -> Result<bool> {
+        match self.get_received_text()? {
+            Some(text) if matches(&text) => self.clear().map(|_| true),
+            _ => Ok(false),
+        }
+    }
+
+    /// Current selection settings. Backends with a single clipboard always report the default.
+    fn selection(&self) -> SelectionSettings {
+        SelectionSettings::default()
+    }
+
+    /// Change which selections text is written to and read from.
+    /// Backends with a single clipboard ignore this.
+    fn set_selection(&self, _selection: SelectionSettings) {}
+
+    /// Decode encoded image bytes (PNG, JPEG, GIF or WebP) and write them as an image.
+    fn set_encoded_image(&self, bytes: &[u8], mime_type: Option<&str>) -> Result<()> {
+        let (rgba, width, height) = decode_image_to_rgba(bytes, mime_type)?;
+        self.set_image(rgba, width, height)?;
+        info!(
+            "clipboard image set ({}x{}, {} bytes {})",
+            width,
+            height,
+            bytes.len(),
+            mime_type.unwrap_or("unknown type")
+        );
+        Ok(())
+    }
+}
+
+/// Predicate for `ClipboardBackend::clear_received_if`.
+pub type TextMatcher = Box<dyn Fn(&str) -> bool + Send>;
+
+/// Linux selection(s) used for text. Other platforms only have one clipboard and always
+/// use it, whatever this says.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClipboardSelection {
+    /// The regular clipboard (Ctrl+C / Ctrl+V).
+    #[default]
+    Clipboard,
+    /// The PRIMARY selection (select text, middle-click to paste).
+    Primary,
+    /// Write to both; read the clipboard first and fall back to PRIMARY.
+    Both,
+}
+
+impl FromStr for ClipboardSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clipboard" => Ok(Self::Clipboard),
+            "primary" => Ok(Self::Primary),
+            "both" => Ok(Self::Both),
+            _ => Err(format!(
+                "invalid selection '{}' (expected clipboard, primary or both)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ClipboardSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Clipboard => "clipboard",
+            Self::Primary => "primary",
+            Self::Both => "both",
+        })
+    }
+}
+
+impl ClipboardSelection {
+    /// Selections to use in order, as "is PRIMARY" flags. Without PRIMARY support
+    /// everything maps to the regular clipboard.
+    fn targets(self) -> &'static [bool] {
+        if !PRIMARY_SUPPORTED {
+            return &[false];
+        }
+        match self {
+            Self::Clipboard => &[false],
+            Self::Primary => &[true],
+            Self::Both => &[false, true],
+        }
+    }
+}
+
+/// Where received text goes and where local text is read from. Images always use the
+/// regular clipboard.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SelectionSettings {
+    /// Selection(s) that received text is written to.
+    pub target: ClipboardSelection,
+    /// Selection(s) that text to send is read from.
+    pub source: ClipboardSelection,
+}
+
+/// Per-selection access where arboard exposes PRIMARY (Linux and the BSDs).
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+mod selection {
+    use anyhow::{Context, Result};
+    use arboard::{ClearExtLinux, Clipboard, GetExtLinux, LinuxClipboardKind, SetExtLinux};
+
+    pub const PRIMARY_SUPPORTED: bool = true;
+
+    fn kind(primary: bool) -> LinuxClipboardKind {
+        if primary {
+            LinuxClipboardKind::Primary
+        } else {
+            LinuxClipboardKind::Clipboard
+        }
+    }
+
+    pub fn read_text_from(clipboard: &mut Clipboard, primary: bool) -> Result<Option<String>> {
+        match clipboard.get().clipboard(kind(primary)).text() {
+            Ok(text) => Ok(Some(text)),
+            Err(arboard::Error::ContentNotAvailable) => Ok(None),
+            Err(e) => Err(e).context("failed to read clipboard"),
+        }
+    }
+
+    pub fn write_text_to(clipboard: &mut Clipboard, text: &str, primary: bool) -> Result<()> {
+        clipboard
+            .set()
+            .clipboard(kind(primary))
+            .text(text)
+            .context("failed to write to clipboard")
+    }
+
+    pub fn clear_selection(clipboard: &mut Clipboard, primary: bool) -> Result<()> {
+        clipboard
+            .clear_with()
+            .clipboard(kind(primary))
+            .context("failed to clear clipboard")
+    }
+}
+
+/// Everywhere else there is only the regular clipboard.
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+)))]
+mod selection {
+    use anyhow::{Context, Result};
+    use arboard::Clipboard;
+
+    pub const PRIMARY_SUPPORTED: bool = false;
+
+    pub fn read_text_from(clipboard: &mut Clipboard, _primary: bool) -> Result<Option<String>> {
+        super::read_text(clipboard)
+    }
+
+    pub fn write_text_to(clipboard: &mut Clipboard, text: &str, _primary: bool) -> Result<()> {
+        clipboard
+            .set_text(text)
+            .context("failed to write to clipboard")
+    }
+
+    pub fn clear_selection(clipboard: &mut Clipboard, _primary: bool) -> Result<()> {
+        super::clear_contents(clipboard)
+    }
+}
+
+use selection::{clear_selection, read_text_from, write_text_to, PRIMARY_SUPPORTED};
+
+/// Read text from the first of `source`'s selections that has any.
+fn read_selection(clipboard: &mut Clipboard, source: ClipboardSelection) -> Result<Option<String>> {
+    for &primary in source.targets() {
+        if let Some(text) = read_text_from(clipboard, primary)? {
+            return Ok(Some(text));
+        }
+    }
+    Ok(None)
+}
+
+fn write_selection(
+    clipboard: &mut Clipboard,
+    text: &str,
+    target: ClipboardSelection,
+) -> Result<()> {
+    for &primary in target.targets() {
+        write_text_to(clipboard, text, primary)?;
+    }
+    info!("clipboard updated ({} chars, {})", text.len(), target);
+    Ok(())
+}
+
+fn clear_target(clipboard: &mut Clipboard, target: ClipboardSelection) -> Result<()> {
+    for &primary in target.targets() {
+        clear_selection(clipboard, primary)?;
+    }
     Ok(())
 }
 
-/// Read the current text content from the system clipboard.
-pub fn get_clipboard_text() -> Result<Option<String>> {
-    let mut clipboard = Clipboard::new().context("failed to access clipboard")?;
+/// Clear each of `target`'s selections that holds text satisfying `matches`, checking
+/// each one on its own so a selection that changed since is left alone.
+fn clear_target_if(
+    clipboard: &mut Clipboard,
+    target: ClipboardSelection,
+    matches: &TextMatcher,
+) -> Result<bool> {
+    let mut cleared = false;
+    for &primary in target.targets() {
+        match read_text_from(clipboard, primary)? {
+            Some(text) if matches(&text) => {
+                clear_selection(clipboard, primary)?;
+                cleared = true;
+            }
+            _ => {}
+        }
+    }
+    Ok(cleared)
+}
+
+fn open_clipboard() -> Result<Clipboard> {
+    Clipboard::new().context("failed to access clipboard")
+}
+
+fn read_text(clipboard: &mut Clipboard) -> Result<Option<String>> {
     match clipboard.get_text() {
         Ok(text) => Ok(Some(text)),
         Err(arboard::Error::ContentNotAvailable) => Ok(None),
@@ -22,53 +267,352 @@ pub fn get_clipboard_text() -> Result<Option<String>> {
     }
 }
 
-/// Read image from system clipboard and return as PNG bytes.
-/// Returns `Ok(None)` if no image is on the clipboard.
-pub fn get_clipboard_image() -> Result<Option<Vec<u8>>> {
-    let mut clipboard = Clipboard::new().context("failed to access clipboard")?;
+fn write_text(clipboard: &mut Clipboard, text: &str) -> Result<()> {
+    clipboard
+        .set_text(text)
+        .context("failed to write to clipboard")?;
+    info!("clipboard updated ({} chars)", text.len());
+    Ok(())
+}
+
+fn read_image(clipboard: &mut Clipboard) -> Result<Option<(Vec<u8>, u32, u32)>> {
     let image_data = match clipboard.get_image() {
         Ok(img) => img,
         Err(arboard::Error::ContentNotAvailable) => return Ok(None),
         Err(e) => return Err(e).context("failed to read image from clipboard"),
     };
-
-    let png_bytes = encode_rgba_to_png(
-        &image_data.bytes,
+    Ok(Some((
+        image_data.bytes.into_owned(),
         image_data.width as u32,
         image_data.height as u32,
-    )?;
-
-    info!(
-        "clipboard image read ({}x{}, {} bytes PNG)",
-        image_data.width,
-        image_data.height,
-        png_bytes.len()
-    );
-    Ok(Some(png_bytes))
+    )))
 }
 
-/// Write PNG bytes to the system clipboard as an image.
-pub fn set_clipboard_image(png_bytes: &[u8]) -> Result<()> {
-    let (rgba, width, height) = decode_png_to_rgba(png_bytes)?;
-
+fn write_image(clipboard: &mut Clipboard, rgba: Vec<u8>, width: u32, height: u32) -> Result<()> {
     let image_data = arboard::ImageData {
         width: width as usize,
         height: height as usize,
         bytes: rgba.into(),
     };
-
-    let mut clipboard = Clipboard::new().context("failed to access clipboard")?;
     clipboard
         .set_image(image_data)
-        .context("failed to write image to clipboard")?;
-
-    info!(
-        "clipboard image set ({}x{}, {} bytes PNG)",
-        width,
-        height,
-        png_bytes.len()
-    );
-    Ok(())
+        .context("failed to write image to clipboard")
+}
+
+fn clear_contents(clipboard: &mut Clipboard) -> Result<()> {
+    clipboard.clear().context("failed to clear clipboard")
+}
+
+/// The system clipboard, via a fresh arboard handle per call.
+///
+/// On X11 and Wayland the handle must outlive the content it sets, so anything that
+/// writes received content should use `ClipboardWorker` instead.
+#[derive(Debug, Default)]
+pub struct ArboardClipboard;
+
+impl ClipboardBackend for ArboardClipboard {
+    fn get_text(&self) -> Result<Option<String>> {
+        read_text(&mut open_clipboard()?)
+    }
+
+    fn set_text(&self, text: &str) -> Result<()> {
+        write_text(&mut open_clipboard()?, text)
+    }
+
+    fn get_image(&self) -> Result<Option<(Vec<u8>, u32, u32)>> {
+        read_image(&mut open_clipboard()?)
+    }
+
+    fn set_image(&self, rgba: Vec<u8>, width: u32, height: u32) -> Result<()> {
+        write_image(&mut open_clipboard()?, rgba, width, height)
+    }
+
+    fn clear(&self) -> Result<()> {
+        clear_contents(&mut open_clipboard()?)
+    }
+}
+
+type Reply<T> = mpsc::Sender<Result<T>>;
+
+enum Request {
+    GetText(ClipboardSelection, Reply<Option<String>>),
+    SetText(String, ClipboardSelection, Reply<()>),
+    GetImage(Reply<Option<(Vec<u8>, u32, u32)>>),
+    SetImage {
+        rgba: Vec<u8>,
+        width: u32,
+        height: u32,
+        reply: Reply<()>,
+    },
+    Clear(ClipboardSelection, Reply<()>),
+    ClearIf(ClipboardSelection, TextMatcher, Reply<bool>),
+}
+
+/// The system clipboard, owned by a dedicated thread that keeps one arboard handle alive.
+///
+/// X11 and Wayland serve the selection from the process that set it, so content written
+/// through a short-lived handle can disappear as soon as the handle is dropped. The worker
+/// opens the handle on first use and reopens it after a failure. Calls block until the
+/// worker answers; from async code, go through `with_clipboard`.
+pub struct ClipboardWorker {
+    tx: mpsc::Sender<Request>,
+    selection: Mutex<SelectionSettings>,
+}
+
+impl ClipboardWorker {
+    /// Start the worker thread. It exits once the `ClipboardWorker` is dropped.
+    pub fn spawn() -> Self {
+        Self::with_selection(SelectionSettings::default())
+    }
+
+    /// Start the worker thread with the given text selection settings.
+    pub fn with_selection(selection: SelectionSettings) -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("uclip-clipboard".to_string())
+            .spawn(move || worker_loop(rx))
+            .expect("failed to spawn clipboard thread");
+        Self {
+            tx,
+            selection: Mutex::new(selection),
+        }
+    }
+
+    fn call<T>(&self, request: impl FnOnce(Reply<T>) -> Request) -> Result<T> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.tx
+            .send(request(reply_tx))
+            .map_err(|_| anyhow!("clipboard worker stopped"))?;
+        reply_rx
+            .recv()
+            .map_err(|_| anyhow!("clipboard worker stopped"))?
+    }
+}
+
+impl ClipboardBackend for ClipboardWorker {
+    fn get_text(&self) -> Result<Option<String>> {
+        let source = self.selection().source;
+        self.call(|reply| Request::GetText(source, reply))
+    }
+
+    fn get_received_text(&self) -> Result<Option<String>> {
+        let target = self.selection().target;
+        self.call(|reply| Request::GetText(target, reply))
+    }
+
+    fn set_text(&self, text: &str) -> Result<()> {
+        let target = self.selection().target;
+        self.call(|reply| Request::SetText(text.to_string(), target, reply))
+    }
+
+    fn get_image(&self) -> Result<Option<(Vec<u8>, u32, u32)>> {
+        self.call(Request::GetImage)
+    }
+
+    fn set_image(&self, rgba: Vec<u8>, width: u32, height: u32) -> Result<()> {
+        self.call(|reply| Request::SetImage {
+            rgba,
+            width,
+            height,
+            reply,
+        })
+    }
+
+    fn clear(&self) -> Result<()> {
+        let target = self.selection().target;
+        self.call(|reply| Request::Clear(target, reply))
+    }
+
+    fn clear_received_if(&self, matches: TextMatcher) -> Result<bool> {
+        let target = self.selection().target;
+        self.call(|reply| Request::ClearIf(target, matches, reply))
+    }
+
+    fn selection(&self) -> SelectionSettings {
+        *self.selection.lock().unwrap()
+    }
+
+    fn set_selection(&self, selection: SelectionSettings) {
+        *self.selection.lock().unwrap() = selection;
+    }
+}
+
+fn worker_loop(rx: mpsc::Receiver<Request>) {
+    let mut handle: Option<Clipboard> = None;
+    for request in rx {
+        match request {
+            Request::GetText(source, reply) => {
+                let _ = reply.send(with_handle(&mut handle, |cb| read_selection(cb, source)));
+            }
+            Request::SetText(text, target, reply) => {
+                let _ = reply.send(with_handle(&mut handle, |cb| {
+                    write_selection(cb, &text, target)
+                }));
+            }
+            Request::GetImage(reply) => {
+                let _ = reply.send(with_handle(&mut handle, read_image));
+            }
+            Request::SetImage {
+                rgba,
+                width,
+                height,
+                reply,
+            } => {
+                let _ = reply.send(with_handle(&mut handle, |cb| {
+                    write_image(cb, rgba, width, height)
+                }));
+            }
+            Request::Clear(target, reply) => {
+                let _ = reply.send(with_handle(&mut handle, |cb| clear_target(cb, target)));
+            }
+            Request::ClearIf(target, matches, reply) => {
+                let _ = reply.send(with_handle(&mut handle, |cb| {
+                    clear_target_if(cb, target, &matches)
+                }));
+            }
+        }
+    }
+    debug!("clipboard worker stopped");
+}
+
+/// Run `op` on the long-lived handle, opening it if needed. An operation that fails because
+/// the connection is gone drops the handle so the next request reconnects (e.g. after the
+/// display server restarted). Failures to read or convert content keep it, since dropping
+/// it would also drop whatever this process put on the clipboard.
+fn with_handle<T>(
+    handle: &mut Option<Clipboard>,
+    op: impl FnOnce(&mut Clipboard) -> Result<T>,
+) -> Result<T> {
+    let clipboard = match handle {
+        Some(clipboard) => clipboard,
+        None => handle.insert(open_clipboard()?),
+    };
+    let result = op(clipboard);
+    if let Err(e) = &result {
+        if is_connection_error(e) {
+            *handle = None;
+        }
+    }
+    result
+}
+
+fn is_connection_error(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<arboard::Error>(),
+        Some(arboard::Error::ClipboardNotSupported | arboard::Error::Unknown { .. })
+    )
+}
+
+/// Run a blocking clipboard operation off the async executor.
+pub async fn with_clipboard<T, F>(clipboard: &Arc<dyn ClipboardBackend>, op: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn ClipboardBackend) -> Result<T> + Send + 'static,
+{
+    let clipboard = clipboard.clone();
+    tokio::task::spawn_blocking(move || op(clipboard.as_ref()))
+        .await
+        .context("clipboard task panicked")?
+}
+
+/// Content held by a `MemoryClipboard`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClipboardContent {
+    Text(String),
+    Image {
+        rgba: Vec<u8>,
+        width: u32,
+        height: u32,
+    },
+}
+
+/// An in-process clipboard for tests and headless use.
+#[derive(Debug, Default)]
+pub struct MemoryClipboard {
+    content: Mutex<Option<ClipboardContent>>,
+}
+
+impl MemoryClipboard {
+    /// Snapshot of the current content.
+    pub fn content(&self) -> Option<ClipboardContent> {
+        self.content.lock().unwrap().clone()
+    }
+}
+
+impl ClipboardBackend for MemoryClipboard {
+    fn get_text(&self) -> Result<Option<String>> {
+        match &*self.content.lock().unwrap() {
+            Some(ClipboardContent::Text(text)) => Ok(Some(text.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    fn set_text(&self, text: &str) -> Result<()> {
+        *self.content.lock().unwrap() = Some(ClipboardContent::Text(text.to_string()));
+        Ok(())
+    }
+
+    fn get_image(&self) -> Result<Option<(Vec<u8>, u32, u32)>> {
+        match &*self.content.lock().unwrap() {
+            Some(ClipboardContent::Image {
+                rgba,
+                width,
+                height,
+            }) => Ok(Some((rgba.clone(), *width, *height))),
+            _ => Ok(None),
+        }
+    }
+
+    fn set_image(&self, rgba: Vec<u8>, width: u32, height: u32) -> Result<()> {
+        *self.content.lock().unwrap() = Some(ClipboardContent::Image {
+            rgba,
+            width,
+            height,
+        });
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        *self.content.lock().unwrap() = None;
+        Ok(())
+    }
+
+    fn clear_received_if(&self, matches: TextMatcher) -> Result<bool> {
+        let mut content = self.content.lock().unwrap();
+        match &*content {
+            Some(ClipboardContent::Text(text)) if matches(text) => {
+                *content = None;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// A clipboard that is always empty and discards writes.
+#[derive(Debug, Default)]
+pub struct NoopClipboard;
+
+impl ClipboardBackend for NoopClipboard {
+    fn get_text(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn set_text(&self, _text: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_image(&self) -> Result<Option<(Vec<u8>, u32, u32)>> {
+        Ok(None)
+    }
+
+    fn set_image(&self, _rgba: Vec<u8>, _width: u32, _height: u32) -> Result<()> {
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 /// Encode RGBA pixel data to PNG bytes.
@@ -94,6 +638,96 @@ pub fn decode_png_to_rgba(png_bytes: &[u8]) -> Result<(Vec<u8>, u32, u32)> {
     Ok((rgba.into_raw(), width, height))
 }
 
+/// Map an image MIME type to a format we can decode.
+pub fn image_format_for_mime(mime_type: &str) -> Option<ImageFormat> {
+    match mime_type {
+        "image/png" => Some(ImageFormat::Png),
+        "image/jpeg" | "image/jpg" => Some(ImageFormat::Jpeg),
+        "image/gif" => Some(ImageFormat::Gif),
+        "image/webp" => Some(ImageFormat::WebP),
+        _ => None,
+    }
+}
+
+/// Detect the MIME type of encoded image bytes from their header.
+pub fn sniff_image_type(bytes: &[u8]) -> Option<&'static str> {
+    match image::guess_format(bytes).ok()? {
+        ImageFormat::Png => Some("image/png"),
+        ImageFormat::Jpeg => Some("image/jpeg"),
+        ImageFormat::Gif => Some("image/gif"),
+        ImageFormat::WebP => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Resolve the format of `bytes`: the declared MIME type unless the header contradicts it,
+/// otherwise whatever the header says.
+fn resolve_format(bytes: &[u8], mime_type: Option<&str>) -> Result<ImageFormat> {
+    if let Some(format) = mime_type.and_then(image_format_for_mime) {
+        if image::guess_format(bytes).map_or(true, |sniffed| sniffed == format) {
+            return Ok(format);
+        }
+        debug!(
+            "declared image type {:?} does not match content, sniffing",
+            mime_type
+        );
+    }
+    sniff_image_type(bytes)
+        .and_then(image_format_for_mime)
+        .context("unsupported or unrecognized image format")
+}
+
+/// Decode PNG, JPEG, GIF (first frame) or WebP bytes to RGBA pixel data,
+/// returning (rgba_bytes, width, height).
+pub fn decode_image_to_rgba(bytes: &[u8], mime_type: Option<&str>) -> Result<(Vec<u8>, u32, u32)> {
+    let format = resolve_format(bytes, mime_type)?;
+    let img = image::load_from_memory_with_format(bytes, format)
+        .with_context(|| format!("failed to decode {:?} image", format))?;
+    let rgba = img.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    Ok((rgba.into_raw(), width, height))
+}
+
+/// Get dimensions of encoded image bytes without fully decoding.
+pub fn image_dimensions(bytes: &[u8], mime_type: Option<&str>) -> Result<(u32, u32)> {
+    use std::io::Cursor;
+    let format = resolve_format(bytes, mime_type)?;
+    image::ImageReader::with_format(Cursor::new(bytes), format)
+        .into_dimensions()
+        .context("failed to read image dimensions")
+}
+
+/// Keep a `width`x`height` encoded image as-is if the peer accepts its type, otherwise
+/// re-encode it as PNG within `policy`'s byte budget, shrinking it if PNG does not fit.
+pub fn encode_for_peer(
+    bytes: Vec<u8>,
+    mime_type: &str,
+    width: u32,
+    height: u32,
+    peer: &DeviceInfo,
+    policy: &ImageSendPolicy,
+) -> Result<PreparedImage> {
+    if peer.accepts_image_type(mime_type) {
+        return Ok(PreparedImage {
+            bytes,
+            mime_type: mime_type.to_string(),
+            width,
+            height,
+            original_width: width,
+            original_height: height,
+        });
+    }
+    debug!("peer does not accept {}, re-encoding as PNG", mime_type);
+    let (rgba, width, height) = decode_image_to_rgba(&bytes, Some(mime_type))?;
+    let png_only = ImageSendPolicy {
+        allow_lossy: false,
+        ..policy.clone()
+    };
+    let prepared = imaging::prepare_image(rgba, width, height, &png_only)?;
+    debug_assert_eq!(prepared.mime_type, DEFAULT_IMAGE_TYPE);
+    Ok(prepared)
+}
+
 /// Get dimensions from PNG bytes without fully decoding.
 pub fn png_dimensions(png_bytes: &[u8]) -> Result<(u32, u32)> {
     use std::io::Cursor;
@@ -138,6 +772,169 @@ mod tests {
         assert!(result.is_err());
     }
 
+    fn encode_test_image(format: ImageFormat, width: u32, height: u32) -> Vec<u8> {
+        use std::io::Cursor;
+        let img = if format == ImageFormat::Jpeg {
+            image::DynamicImage::ImageRgb8(image::RgbImage::new(width, height))
+        } else {
+            image::DynamicImage::ImageRgba8(image::RgbaImage::new(width, height))
+        };
+        let mut buf = Cursor::new(Vec::new());
+        img.write_to(&mut buf, format).unwrap();
+        buf.into_inner()
+    }
+
+    #[test]
+    fn test_decode_jpeg_gif_webp() {
+        for (format, mime) in [
+            (ImageFormat::Jpeg, "image/jpeg"),
+            (ImageFormat::Gif, "image/gif"),
+            (ImageFormat::WebP, "image/webp"),
+        ] {
+            let bytes = encode_test_image(format, 4, 3);
+            assert_eq!(sniff_image_type(&bytes), Some(mime));
+
+            let (rgba, w, h) = decode_image_to_rgba(&bytes, Some(mime)).unwrap();
+            assert_eq!((w, h), (4, 3));
+            assert_eq!(rgba.len(), 4 * 3 * 4);
+            assert_eq!(image_dimensions(&bytes, Some(mime)).unwrap(), (4, 3));
+        }
+    }
+
+    #[test]
+    fn test_decode_sniffs_missing_or_wrong_mime() {
+        let jpeg = encode_test_image(ImageFormat::Jpeg, 5, 2);
+        assert_eq!(decode_image_to_rgba(&jpeg, None).unwrap().1, 5);
+        // Declared as PNG but actually JPEG
+        assert_eq!(decode_image_to_rgba(&jpeg, Some("image/png")).unwrap().1, 5);
+        assert!(decode_image_to_rgba(&[0, 1, 2, 3], Some("image/png")).is_err());
+    }
+
+    #[test]
+    fn test_encode_for_peer() {
+        let jpeg = encode_test_image(ImageFormat::Jpeg, 2, 2);
+        let modern = DeviceInfo {
+            name: "mac".to_string(),
+            image_types: vec!["image/png".to_string(), "image/jpeg".to_string()],
+            window: None,
+        };
+        let policy = ImageSendPolicy::default();
+        let prepared = encode_for_peer(jpeg.clone(), "image/jpeg", 2, 2, &modern, &policy).unwrap();
+        assert_eq!(prepared.mime_type, "image/jpeg");
+        assert_eq!(prepared.bytes, jpeg);
+
+        let legacy = DeviceInfo {
+            name: "phone".to_string(),
+            image_types: vec![],
+            window: None,
+        };
+        let prepared = encode_for_peer(jpeg, "image/jpeg", 2, 2, &legacy, &policy).unwrap();
+        assert_eq!(prepared.mime_type, "image/png");
+        assert_eq!(&prepared.bytes[..4], &[0x89, 0x50, 0x4E, 0x47]);
+    }
+
+    #[test]
+    fn test_png_fallback_stays_within_budget() {
+        // Noise compresses badly, so the PNG is far larger than the JPEG
+        let rgba: Vec<u8> = (0..256u32 * 256 * 4)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let jpeg = {
+            let img = image::RgbaImage::from_raw(256, 256, rgba).unwrap();
+            let rgb = image::DynamicImage::ImageRgba8(img).to_rgb8();
+            let mut buf = std::io::Cursor::new(Vec::new());
+            rgb.write_to(&mut buf, ImageFormat::Jpeg).unwrap();
+            buf.into_inner()
+        };
+        let legacy = DeviceInfo::default();
+        let policy = ImageSendPolicy {
+            max_bytes: 60_000,
+            allow_lossy: true,
+            ..Default::default()
+        };
+        let prepared = encode_for_peer(jpeg, "image/jpeg", 256, 256, &legacy, &policy).unwrap();
+        assert_eq!(prepared.mime_type, "image/png");
+        assert!(prepared.bytes.len() <= 60_000);
+        assert!(prepared.width < 256);
+    }
+
+    #[test]
+    fn test_memory_clipboard() {
+        let clipboard = MemoryClipboard::default();
+        assert_eq!(clipboard.get_text().unwrap(), None);
+
+        clipboard.set_text("hello").unwrap();
+        assert_eq!(clipboard.get_text().unwrap().as_deref(), Some("hello"));
+        assert_eq!(clipboard.get_image().unwrap(), None);
+
+        let png = encode_rgba_to_png(&[1, 2, 3, 255], 1, 1).unwrap();
+        clipboard
+            .set_encoded_image(&png, Some("image/png"))
+            .unwrap();
+        assert_eq!(clipboard.get_text().unwrap(), None);
+        assert_eq!(
+            clipboard.content(),
+            Some(ClipboardContent::Image {
+                rgba: vec![1, 2, 3, 255],
+                width: 1,
+                height: 1,
+            })
+        );
+
+        clipboard.clear().unwrap();
+        assert_eq!(clipboard.content(), None);
+    }
+
+    #[test]
+    fn test_noop_clipboard_discards_writes() {
+        let clipboard = NoopClipboard;
+        clipboard.set_text("hello").unwrap();
+        assert_eq!(clipboard.get_text().unwrap(), None);
+        assert!(clipboard
+            .set_encoded_image(&[0, 1, 2], Some("image/png"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_clipboard_selection_parse_and_targets() {
+        assert_eq!("primary".parse(), Ok(ClipboardSelection::Primary));
+        assert!("secondary".parse::<ClipboardSelection>().is_err());
+        assert_eq!(ClipboardSelection::Both.to_string(), "both");
+        if PRIMARY_SUPPORTED {
+            assert_eq!(ClipboardSelection::Both.targets(), &[false, true]);
+            assert_eq!(ClipboardSelection::Primary.targets(), &[true]);
+        } else {
+            assert_eq!(ClipboardSelection::Primary.targets(), &[false]);
+        }
+        let settings: SelectionSettings = serde_json::from_str(r#"{"target":"both"}"#).unwrap();
+        assert_eq!(settings.target, ClipboardSelection::Both);
+        assert_eq!(settings.source, ClipboardSelection::Clipboard);
+    }
+
+    #[test]
+    fn test_worker_keeps_selection_settings() {
+        let worker = ClipboardWorker::spawn();
+        assert_eq!(worker.selection(), SelectionSettings::default());
+        let selection = SelectionSettings {
+            target: ClipboardSelection::Both,
+            source: ClipboardSelection::Primary,
+        };
+        worker.set_selection(selection);
+        assert_eq!(worker.selection(), selection);
+    }
+
+    #[tokio::test]
+    async fn test_with_clipboard_runs_off_executor() {
+        let clipboard: Arc<dyn ClipboardBackend> = Arc::new(MemoryClipboard::default());
+        with_clipboard(&clipboard, |cb| cb.set_text("hello"))
+            .await
+            .unwrap();
+        let text = with_clipboard(&clipboard, |cb| cb.get_text())
+            .await
+            .unwrap();
+        assert_eq!(text.as_deref(), Some("hello"));
+    }
+
     #[test]
     fn test_png_dimensions() {
         let rgba = vec![0u8; 4 * 3 * 5]; // 3x5 image
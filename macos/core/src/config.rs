@@ -0,0 +1,783 @@
+//! Daemon and app settings from `config.toml`.
+//!
+//! Values are resolved in this order, later sources winning: built-in defaults, the config
+//! file, `UCLIP_*` environment variables, command-line flags. Everything except the port,
+//! listen addresses and device name can be reloaded while the daemon runs; the OS-level TCP
+//! keepalive only changes for connections accepted after a reload.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::clipboard::{ClipboardSelection, SelectionSettings};
+use crate::confirm::{ConfirmSettings, DEFAULT_CONFIRM_TIMEOUT};
+use crate::events::{AppState, ServerEvent};
+use crate::history::HISTORY_SIZE;
+use crate::imaging::{ImageSendPolicy, DEFAULT_JPEG_QUALITY, DEFAULT_MAX_DIMENSION};
+use crate::net::BindTarget;
+use crate::protocol::{IMAGE_CHUNK_SIZE, MAX_IMAGE_SIZE, MAX_TEXT_SIZE};
+use crate::queue::{QueueSettings, DEFAULT_QUEUE_EXPIRY, DEFAULT_QUEUE_MAX_ITEMS};
+use crate::sensitive::{SensitiveSettings, DEFAULT_CLEAR_AFTER};
+
+/// Port the daemon listens on unless configured otherwise.
+pub const DEFAULT_PORT: u16 = 9876;
+
+/// Default interval between keepalive PINGs.
+pub const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// Default time a device has to answer a PING before the session is considered dead.
+pub const DEFAULT_PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default time an outgoing image waits for the device to grant window before it fails.
+pub const DEFAULT_WINDOW_STALL: Duration = Duration::from_secs(30);
+
+/// File name of the config file inside the config directory.
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Environment variable pointing at a config file to use instead of the default one.
+pub const CONFIG_PATH_ENV: &str = "UCLIP_CONFIG";
+
+/// mDNS labels are limited to 63 bytes.
+const MAX_DEVICE_NAME_LEN: usize = 63;
+
+/// Upper bound for `limits.max_image_bytes`, to keep a peer from making us buffer gigabytes.
+const MAX_IMAGE_BYTES_LIMIT: usize = 256 * 1024 * 1024;
+
+/// Contents of `config.toml`. Every field is optional in the file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub port: u16,
+    /// Addresses or interface names to listen on; `0.0.0.0` and `::` mean every interface.
+    pub bind: Vec<BindTarget>,
+    /// Name advertised over mDNS (the CLI defaults to "My Mac", the app to the computer name).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
+    /// Linux selection that received text is written to.
+    pub selection: ClipboardSelection,
+    /// Linux selection that text to send is read from.
+    pub selection_source: ClipboardSelection,
+    pub limits: LimitsConfig,
+    pub sensitive: SensitiveConfig,
+    pub confirm: ConfirmConfig,
+    pub outgoing_images: OutgoingImagesConfig,
+    pub queue: QueueConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Largest image accepted from a device, in bytes.
+    pub max_image_bytes: usize,
+    /// Payload size of each outgoing IMAGE_CHUNK, in bytes.
+    pub image_chunk_bytes: usize,
+    /// Seconds between keepalive PINGs, and the idle time before the OS starts probing a
+    /// connection (set when it is accepted).
+    pub keepalive_secs: u64,
+    /// Seconds a device may stay silent after a PING before the session is closed.
+    pub pong_timeout_secs: u64,
+    /// Seconds an outgoing image waits for the device to grant window before it fails.
+    pub window_stall_secs: u64,
+    /// Number of received items kept in the history.
+    pub history_size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SensitiveConfig {
+    pub detect: bool,
+    /// Seconds before sensitive content is cleared from the clipboard (0 = never).
+    pub clear_after_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfirmConfig {
+    pub enabled: bool,
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutgoingImagesConfig {
+    /// Longest edge in pixels (0 = keep size).
+    pub max_dimension: u32,
+    pub max_bytes: usize,
+    pub allow_lossy: bool,
+    pub jpeg_quality: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Queue content for a device that is not connected and deliver it when it connects.
+    pub enabled: bool,
+    /// Seconds before an undelivered item is dropped.
+    pub expiry_secs: u64,
+    /// Most items queued for one device.
+    pub max_items: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            bind: BindTarget::dual_stack(),
+            device_name: None,
+            selection: ClipboardSelection::default(),
+            selection_source: ClipboardSelection::default(),
+            limits: LimitsConfig::default(),
+            sensitive: SensitiveConfig::default(),
+            confirm: ConfirmConfig::default(),
+            outgoing_images: OutgoingImagesConfig::default(),
+            queue: QueueConfig::default(),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_image_bytes: MAX_IMAGE_SIZE,
+            image_chunk_bytes: IMAGE_CHUNK_SIZE,
+            keepalive_secs: DEFAULT_KEEPALIVE.as_secs(),
+            pong_timeout_secs: DEFAULT_PONG_TIMEOUT.as_secs(),
+            window_stall_secs: DEFAULT_WINDOW_STALL.as_secs(),
+            history_size: HISTORY_SIZE,
+        }
+    }
+}
+
+impl Default for SensitiveConfig {
+    fn default() -> Self {
+        Self {
+            detect: false,
+            clear_after_secs: DEFAULT_CLEAR_AFTER.as_secs(),
+        }
+    }
+}
+
+impl Default for ConfirmConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout_secs: DEFAULT_CONFIRM_TIMEOUT.as_secs(),
+        }
+    }
+}
+
+impl Default for OutgoingImagesConfig {
+    fn default() -> Self {
+        Self {
+            max_dimension: DEFAULT_MAX_DIMENSION,
+            max_bytes: MAX_IMAGE_SIZE,
+            allow_lossy: false,
+            jpeg_quality: DEFAULT_JPEG_QUALITY,
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            expiry_secs: DEFAULT_QUEUE_EXPIRY.as_secs(),
+            max_items: DEFAULT_QUEUE_MAX_ITEMS,
+        }
+    }
+}
+
+/// Settings read while the daemon runs; replaced as a whole when the config is reloaded.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub sensitive: SensitiveSettings,
+    pub confirm: ConfirmSettings,
+    /// How outgoing images are resized and encoded.
+    pub image_send: ImageSendPolicy,
+    /// Largest image accepted from a device.
+    pub max_image_bytes: usize,
+    /// Payload size of each outgoing IMAGE_CHUNK.
+    pub image_chunk_bytes: usize,
+    /// Interval between PINGs.
+    pub keepalive: Duration,
+    /// How long the peer may stay silent after a PING before it is considered gone.
+    pub pong_timeout: Duration,
+    /// How long a held image chunk waits for window before the image is abandoned.
+    pub window_stall_timeout: Duration,
+    pub queue: QueueSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Config::default().settings()
+    }
+}
+
+impl Config {
+    /// `$UCLIP_CONFIG` if set, otherwise `config.toml` in the platform config directory.
+    pub fn default_path() -> Result<PathBuf> {
+        if let Some(path) = std::env::var_os(CONFIG_PATH_ENV) {
+            return Ok(PathBuf::from(path));
+        }
+        let dir = directories::ProjectDirs::from("com", "uclip", "UniversalClipboard")
+            .context("could not determine config directory")?;
+        Ok(dir.config_dir().join(CONFIG_FILE_NAME))
+    }
+
+    /// Read the config file; a missing file means all defaults.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::from_toml(&text)
+                .with_context(|| format!("invalid config file {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    /// Reject values the daemon cannot work with.
+    pub fn validate(&self) -> Result<()> {
+        if self.bind.is_empty() {
+            bail!("bind must list at least one address");
+        }
+        if let Some(name) = &self.device_name {
+            if name.trim().is_empty() {
+                bail!("device_name must not be empty");
+            }
+            if name.len() > MAX_DEVICE_NAME_LEN {
+                bail!("device_name is longer than {} bytes", MAX_DEVICE_NAME_LEN);
+            }
+        }
+        let limits = &self.limits;
+        if limits.max_image_bytes == 0 || limits.max_image_bytes > MAX_IMAGE_BYTES_LIMIT {
+            bail!(
+                "limits.max_image_bytes must be between 1 and {}",
+                MAX_IMAGE_BYTES_LIMIT
+            );
+        }
+        if limits.image_chunk_bytes == 0 || limits.image_chunk_bytes > MAX_TEXT_SIZE {
+            bail!(
+                "limits.image_chunk_bytes must be between 1 and {} (one Noise message)",
+                MAX_TEXT_SIZE
+            );
+        }
+        if limits.keepalive_secs == 0 {
+            bail!("limits.keepalive_secs must be at least 1");
+        }
+        if limits.pong_timeout_secs == 0 {
+            bail!("limits.pong_timeout_secs must be at least 1");
+        }
+        if limits.window_stall_secs == 0 {
+            bail!("limits.window_stall_secs must be at least 1");
+        }
+        if limits.history_size == 0 {
+            bail!("limits.history_size must be at least 1");
+        }
+        if self.confirm.timeout_secs == 0 {
+            bail!("confirm.timeout_secs must be at least 1");
+        }
+        let images = &self.outgoing_images;
+        if images.max_bytes == 0 || images.max_bytes > MAX_IMAGE_SIZE {
+            bail!(
+                "outgoing_images.max_bytes must be between 1 and {}",
+                MAX_IMAGE_SIZE
+            );
+        }
+        if !(1..=100).contains(&images.jpeg_quality) {
+            bail!("outgoing_images.jpeg_quality must be between 1 and 100");
+        }
+        if self.queue.expiry_secs == 0 {
+            bail!("queue.expiry_secs must be at least 1");
+        }
+        if self.queue.max_items == 0 {
+            bail!("queue.max_items must be at least 1");
+        }
+        Ok(())
+    }
+
+    pub fn settings(&self) -> Settings {
+        let images = &self.outgoing_images;
+        Settings {
+            sensitive: SensitiveSettings {
+                detect: self.sensitive.detect,
+                clear_after: (self.sensitive.clear_after_secs > 0)
+                    .then(|| Duration::from_secs(self.sensitive.clear_after_secs)),
+            },
+            confirm: ConfirmSettings {
+                enabled: self.confirm.enabled,
+                timeout: Duration::from_secs(self.confirm.timeout_secs),
+            },
+            image_send: ImageSendPolicy {
+                max_dimension: (images.max_dimension > 0).then_some(images.max_dimension),
+                max_bytes: images.max_bytes,
+                allow_lossy: images.allow_lossy,
+                jpeg_quality: images.jpeg_quality,
+            },
+            max_image_bytes: self.limits.max_image_bytes,
+            image_chunk_bytes: self.limits.image_chunk_bytes,
+            keepalive: Duration::from_secs(self.limits.keepalive_secs),
+            pong_timeout: Duration::from_secs(self.limits.pong_timeout_secs),
+            window_stall_timeout: Duration::from_secs(self.limits.window_stall_secs),
+            queue: QueueSettings {
+                enabled: self.queue.enabled,
+                expiry: Duration::from_secs(self.queue.expiry_secs),
+                max_items: self.queue.max_items,
+            },
+        }
+    }
+}
+
+/// Values given on the command line or in the environment, applied over the file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigOverrides {
+    pub port: Option<u16>,
+    pub bind: Option<Vec<BindTarget>>,
+    pub device_name: Option<String>,
+    pub selection: Option<ClipboardSelection>,
+    pub selection_source: Option<ClipboardSelection>,
+    pub detect_sensitive: Option<bool>,
+    pub clear_sensitive_after: Option<u64>,
+    pub confirm: Option<bool>,
+    pub confirm_timeout: Option<u64>,
+}
+
+impl ConfigOverrides {
+    /// Read `UCLIP_PORT`, `UCLIP_BIND` (comma-separated), `UCLIP_DEVICE_NAME`,
+    /// `UCLIP_SELECTION`, `UCLIP_SELECTION_SOURCE`, `UCLIP_DETECT_SENSITIVE`,
+    /// `UCLIP_CLEAR_SENSITIVE_AFTER`, `UCLIP_CONFIRM` and `UCLIP_CONFIRM_TIMEOUT`.
+    pub fn from_env(vars: impl IntoIterator<Item = (String, String)>) -> Result<Self> {
+        let mut overrides = Self::default();
+        for (key, value) in vars {
+            let context = || format!("invalid value for {}: {:?}", key, value);
+            match key.as_str() {
+                "UCLIP_PORT" => overrides.port = Some(value.parse().with_context(context)?),
+                "UCLIP_BIND" => {
+                    overrides.bind = Some(
+                        value
+                            .split(',')
+                            .map(str::parse)
+                            .collect::<Result<_, _>>()
+                            .map_err(anyhow::Error::msg)
+                            .with_context(context)?,
+                    )
+                }
+                "UCLIP_DEVICE_NAME" => overrides.device_name = Some(value),
+                "UCLIP_SELECTION" => {
+                    overrides.selection = Some(
+                        value
+                            .parse()
+                            .map_err(anyhow::Error::msg)
+                            .with_context(context)?,
+                    )
+                }
+                "UCLIP_SELECTION_SOURCE" => {
+                    overrides.selection_source = Some(
+                        value
+                            .parse()
+                            .map_err(anyhow::Error::msg)
+                            .with_context(context)?,
+                    )
+                }
+                "UCLIP_DETECT_SENSITIVE" => {
+                    overrides.detect_sensitive = Some(parse_bool(&value).with_context(context)?)
+                }
+                "UCLIP_CLEAR_SENSITIVE_AFTER" => {
+                    overrides.clear_sensitive_after = Some(value.parse().with_context(context)?)
+                }
+                "UCLIP_CONFIRM" => {
+                    overrides.confirm = Some(parse_bool(&value).with_context(context)?)
+                }
+                "UCLIP_CONFIRM_TIMEOUT" => {
+                    overrides.confirm_timeout = Some(value.parse().with_context(context)?)
+                }
+                _ => {}
+            }
+        }
+        Ok(overrides)
+    }
+
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(bind) = &self.bind {
+            config.bind = bind.clone();
+        }
+        if let Some(name) = &self.device_name {
+            config.device_name = Some(name.clone());
+        }
+        if let Some(selection) = self.selection {
+            config.selection = selection;
+        }
+        if let Some(selection) = self.selection_source {
+            config.selection_source = selection;
+        }
+        if let Some(detect) = self.detect_sensitive {
+            config.sensitive.detect = detect;
+        }
+        if let Some(secs) = self.clear_sensitive_after {
+            config.sensitive.clear_after_secs = secs;
+        }
+        if let Some(enabled) = self.confirm {
+            config.confirm.enabled = enabled;
+        }
+        if let Some(secs) = self.confirm_timeout {
+            config.confirm.timeout_secs = secs;
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => bail!("expected true or false"),
+    }
+}
+
+/// Resolves the effective config from the file, the environment and fixed flag values.
+/// Kept in `AppState` so the daemon can reload it.
+#[derive(Debug, Clone)]
+pub struct ConfigLoader {
+    path: PathBuf,
+    flags: ConfigOverrides,
+}
+
+impl ConfigLoader {
+    pub fn new(path: PathBuf, flags: ConfigOverrides) -> Self {
+        Self { path, flags }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn load(&self) -> Result<Config> {
+        self.load_with_env(std::env::vars())
+    }
+
+    fn load_with_env(&self, vars: impl IntoIterator<Item = (String, String)>) -> Result<Config> {
+        let mut config = Config::load(&self.path)?;
+        ConfigOverrides::from_env(vars)?.apply(&mut config);
+        self.flags.apply(&mut config);
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Apply everything in `config` that may change while the daemon runs.
+pub async fn apply(state: &AppState, config: &Config) {
+    state.set_settings(config.settings());
+    state.clipboard.set_selection(SelectionSettings {
+        target: config.selection,
+        source: config.selection_source,
+    });
+    state
+        .history
+        .write()
+        .await
+        .set_capacity(config.limits.history_size);
+}
+
+/// Outcome of a config reload.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReloadReport {
+    /// Changed settings that only take effect after a restart.
+    pub restart_required: Vec<String>,
+}
+
+/// Re-read the config and apply what is safe to change while running. An invalid config
+/// leaves the current settings untouched and is kept as `AppState::config_error`.
+pub async fn reload(state: &AppState) -> Result<ReloadReport> {
+    let Some(loader) = &state.config else {
+        bail!("this daemon was not started from a config file");
+    };
+    let config = match loader.load() {
+        Ok(config) => config,
+        Err(e) => {
+            state.set_config_error(Some(format!("{:#}", e)));
+            return Err(e);
+        }
+    };
+    state.set_config_error(None);
+    apply(state, &config).await;
+
+    let mut restart_required = Vec::new();
+    if config.port != state.port {
+        restart_required.push("port".to_string());
+    }
+    if config.bind != state.bind {
+        restart_required.push("bind".to_string());
+    }
+    if config
+        .device_name
+        .as_ref()
+        .is_some_and(|name| *name != state.device_name)
+    {
+        restart_required.push("device_name".to_string());
+    }
+    if restart_required.is_empty() {
+        info!("config reloaded from {}", loader.path().display());
+    } else {
+        warn!(
+            "config reloaded from {}; restart to apply: {}",
+            loader.path().display(),
+            restart_required.join(", ")
+        );
+    }
+    state.emit(ServerEvent::ConfigReloaded {
+        restart_required: restart_required.clone(),
+    });
+    Ok(ReloadReport { restart_required })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Identity;
+    use crate::storage::DeviceStore;
+    use tempfile::TempDir;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_empty_file_is_default() {
+        let config = Config::from_toml("").unwrap();
+        assert_eq!(config, Config::default());
+        config.validate().unwrap();
+        assert_eq!(config.port, DEFAULT_PORT);
+        assert_eq!(config.limits.image_chunk_bytes, IMAGE_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn test_parse_full_file() {
+        let config = Config::from_toml(
+            r#"
+            port = 9900
+            device_name = "Studio"
+            selection = "both"
+            selection_source = "primary"
+
+            [limits]
+            max_image_bytes = 1048576
+            image_chunk_bytes = 32000
+            keepalive_secs = 10
+            pong_timeout_secs = 4
+            window_stall_secs = 20
+            history_size = 5
+
+            [sensitive]
+            detect = true
+            clear_after_secs = 0
+
+            [confirm]
+            enabled = true
+            timeout_secs = 15
+
+            [outgoing_images]
+            max_dimension = 0
+            max_bytes = 500000
+            allow_lossy = true
+            jpeg_quality = 70
+
+            [queue]
+            enabled = true
+            expiry_secs = 600
+            max_items = 10
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.device_name.as_deref(), Some("Studio"));
+        assert_eq!(config.selection, ClipboardSelection::Both);
+        assert_eq!(config.selection_source, ClipboardSelection::Primary);
+
+        let settings = config.settings();
+        assert!(settings.sensitive.detect);
+        assert_eq!(settings.sensitive.clear_after, None);
+        assert_eq!(settings.confirm.timeout, Duration::from_secs(15));
+        assert_eq!(settings.image_send.max_dimension, None);
+        assert!(settings.image_send.allow_lossy);
+        assert_eq!(settings.max_image_bytes, 1048576);
+        assert_eq!(settings.image_chunk_bytes, 32000);
+        assert_eq!(settings.keepalive, Duration::from_secs(10));
+        assert_eq!(settings.pong_timeout, Duration::from_secs(4));
+        assert_eq!(settings.window_stall_timeout, Duration::from_secs(20));
+        assert!(settings.queue.enabled);
+        assert_eq!(settings.queue.expiry, Duration::from_secs(600));
+        assert_eq!(settings.queue.max_items, 10);
+    }
+
+    #[test]
+    fn test_partial_table_keeps_other_defaults() {
+        let config = Config::from_toml("[limits]\nhistory_size = 3\n").unwrap();
+        assert_eq!(config.limits.history_size, 3);
+        assert_eq!(config.limits.max_image_bytes, MAX_IMAGE_SIZE);
+    }
+
+    #[test]
+    fn test_unknown_key_is_rejected() {
+        let err = Config::from_toml("prot = 1234\n").unwrap_err();
+        assert!(err.to_string().contains("prot"));
+    }
+
+    #[test]
+    fn test_validation() {
+        let invalid = [
+            "device_name = \"\"",
+            "bind = []",
+            "[limits]\nimage_chunk_bytes = 70000",
+            "[limits]\nmax_image_bytes = 0",
+            "[limits]\nkeepalive_secs = 0",
+            "[limits]\npong_timeout_secs = 0",
+            "[limits]\nwindow_stall_secs = 0",
+            "[limits]\nhistory_size = 0",
+            "[confirm]\ntimeout_secs = 0",
+            "[outgoing_images]\njpeg_quality = 0",
+            "[outgoing_images]\nmax_bytes = 999999999",
+            "[queue]\nexpiry_secs = 0",
+            "[queue]\nmax_items = 0",
+        ];
+        for text in invalid {
+            let config = Config::from_toml(text).unwrap();
+            assert!(config.validate().is_err(), "accepted: {}", text);
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_toml() {
+        let config = Config {
+            device_name: Some("Desk".to_string()),
+            limits: LimitsConfig {
+                keepalive_secs: 5,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let parsed = Config::from_toml(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(parsed, config);
+    }
+
+    #[test]
+    fn test_missing_file_is_default() {
+        let dir = TempDir::new().unwrap();
+        let config = Config::load(&dir.path().join("config.toml")).unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let overrides = ConfigOverrides::from_env(env(&[
+            ("UCLIP_PORT", "9999"),
+            ("UCLIP_DETECT_SENSITIVE", "yes"),
+            ("UCLIP_SELECTION", "primary"),
+            ("UCLIP_SELECTION_SOURCE", "both"),
+            ("UCLIP_BIND", "127.0.0.1, en0"),
+            ("HOME", "/home/me"),
+        ]))
+        .unwrap();
+        assert_eq!(overrides.port, Some(9999));
+        assert_eq!(overrides.detect_sensitive, Some(true));
+        assert_eq!(overrides.selection, Some(ClipboardSelection::Primary));
+        assert_eq!(overrides.selection_source, Some(ClipboardSelection::Both));
+        assert_eq!(
+            overrides.bind,
+            Some(vec![
+                BindTarget::Addr("127.0.0.1".parse().unwrap()),
+                BindTarget::Interface("en0".to_string())
+            ])
+        );
+
+        let err = ConfigOverrides::from_env(env(&[("UCLIP_PORT", "high")])).unwrap_err();
+        assert!(err.to_string().contains("UCLIP_PORT"));
+    }
+
+    #[test]
+    fn test_precedence_flags_over_env_over_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            "port = 1111\ndevice_name = \"file\"\n[confirm]\ntimeout_secs = 5\n",
+        )
+        .unwrap();
+        let flags = ConfigOverrides {
+            port: Some(3333),
+            ..Default::default()
+        };
+        let loader = ConfigLoader::new(path, flags);
+        let config = loader
+            .load_with_env(env(&[("UCLIP_PORT", "2222"), ("UCLIP_DEVICE_NAME", "env")]))
+            .unwrap();
+        assert_eq!(config.port, 3333);
+        assert_eq!(config.device_name.as_deref(), Some("env"));
+        assert_eq!(config.confirm.timeout_secs, 5);
+    }
+
+    #[test]
+    fn test_loader_validates() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "[limits]\nhistory_size = 0\n").unwrap();
+        let loader = ConfigLoader::new(path, ConfigOverrides::default());
+        assert!(loader.load_with_env(Vec::new()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reload_applies_safe_settings() {
+        let dir = TempDir::new().unwrap();
+        let store = DeviceStore::new(dir.path().join("store")).unwrap();
+        let identity = Identity::load_or_generate(&store).unwrap();
+        let path = dir.path().join("config.toml");
+        // The default clipboard worker keeps selection settings and only opens the system
+        // clipboard when content is read or written
+        let mut state = AppState::new(identity, "123456".into(), "Desk".into(), store, 9876);
+        state.config = Some(ConfigLoader::new(path.clone(), ConfigOverrides::default()));
+
+        std::fs::write(
+            &path,
+            "port = 9000\nbind = [\"::\"]\nselection_source = \"primary\"\n[sensitive]\ndetect = true\n[limits]\nhistory_size = 2\n",
+        )
+        .unwrap();
+        let report = reload(&state).await.unwrap();
+        assert_eq!(report.restart_required, vec!["port", "bind"]);
+        assert!(state.settings().sensitive.detect);
+        assert_eq!(
+            state.clipboard.selection().source,
+            ClipboardSelection::Primary
+        );
+        let mut history = state.history.write().await;
+        for i in 0..5 {
+            history.push(crate::history::HistoryEntry::text("phone", &i.to_string()));
+        }
+        assert_eq!(history.len(), 2);
+        drop(history);
+
+        // A broken file keeps the running settings
+        std::fs::write(&path, "[sensitive]\ndetect = \"maybe\"\n").unwrap();
+        assert!(reload(&state).await.is_err());
+        assert!(state.settings().sensitive.detect);
+        assert!(state
+            .config_error()
+            .unwrap()
+            .contains("invalid config file"));
+
+        // Fixing it clears the error
+        std::fs::write(&path, "[sensitive]\ndetect = false\n").unwrap();
+        reload(&state).await.unwrap();
+        assert_eq!(state.config_error(), None);
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Default time to wait for the user to accept or reject incoming content.
+pub const DEFAULT_CONFIRM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Confirm-before-apply settings for incoming content.
+#[derive(Debug, Clone)]
+pub struct ConfirmSettings {
+    /// Ask before applying content from every device, not just those whose policy requires it.
+    pub enabled: bool,
+    /// How long a parked item waits for a decision before it is rejected.
+    pub timeout: Duration,
+}
+
+impl Default for ConfirmSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout: DEFAULT_CONFIRM_TIMEOUT,
+        }
+    }
+}
+
+/// The user's answer to a confirmation request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Accept,
+    Reject,
+}
+
+/// Outstanding confirmation requests, keyed by request id.
+#[derive(Default)]
+pub struct Confirmations {
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Decision>>>,
+}
+
+impl Confirmations {
+    /// Open a new request. The receiver resolves once `resolve` is called with its id.
+    pub fn register(&self) -> (u64, oneshot::Receiver<Decision>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        (id, rx)
+    }
+
+    /// Deliver a decision. Returns false if the request is unknown or already expired.
+    pub fn resolve(&self, id: u64, decision: Decision) -> bool {
+        let tx = self.pending.lock().unwrap().remove(&id);
+        match tx {
+            Some(tx) => tx.send(decision).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Drop a request that is no longer waiting (timed out or session ended).
+    pub fn cancel(&self, id: u64) {
+        self.pending.lock().unwrap().remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_register_and_resolve() {
+        let confirmations = Confirmations::default();
+        let (id, rx) = confirmations.register();
+        assert!(confirmations.resolve(id, Decision::Accept));
+        assert_eq!(rx.await.unwrap(), Decision::Accept);
+        // Second resolve is a no-op
+        assert!(!confirmations.resolve(id, Decision::Reject));
+    }
+
+    #[test]
+    fn test_ids_are_unique() {
+        let confirmations = Confirmations::default();
+        let (a, _rx_a) = confirmations.register();
+        let (b, _rx_b) = confirmations.register();
+        assert_ne!(a, b);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_request_cannot_resolve() {
+        let confirmations = Confirmations::default();
+        let (id, rx) = confirmations.register();
+        confirmations.cancel(id);
+        assert!(!confirmations.resolve(id, Decision::Accept));
+        assert!(rx.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_after_receiver_dropped() {
+        let confirmations = Confirmations::default();
+        let (id, rx) = confirmations.register();
+        drop(rx);
+        assert!(!confirmations.resolve(id, Decision::Accept));
+    }
+}
@@ -0,0 +1,1159 @@
+//! Local control socket for a running daemon.
+//!
+//! Clients speak line-delimited JSON-RPC 2.0 over a Unix domain socket in the store
+//! directory. Each request line gets exactly one response line, except `subscribe`: after
+//! its response the connection only carries `event` notifications until either side
+//! closes it.
+
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::clipboard;
+use crate::config;
+use crate::delivery::{Delivery, PendingDelivery};
+use crate::events::{AppState, ServerEvent};
+use crate::imaging;
+use crate::pairing::PairingStatus;
+use crate::protocol::MAX_TEXT_SIZE;
+use crate::queue::{self, Outbound, OutboundContent, QueuedItem};
+use crate::stats::SessionStats;
+use crate::storage::DeviceStore;
+
+/// File name of the control socket inside the store directory.
+pub const SOCKET_NAME: &str = "control.sock";
+
+/// Standard JSON-RPC error codes.
+pub const PARSE_ERROR: i64 = -32700;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// No device is connected, so there is nothing to send to.
+pub const NO_SESSION: i64 = -32000;
+/// The device (or its policy on this side) refused the content.
+pub const REJECTED: i64 = -32001;
+/// The device did not acknowledge the content in time.
+pub const TIMEOUT: i64 = -32002;
+
+/// Where the daemon using `store` listens for control connections.
+pub fn socket_path(store: &DeviceStore) -> PathBuf {
+    store.base_dir().join(SOCKET_NAME)
+}
+
+/// Error object of a failed call.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+#[error("{message}")]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(INTERNAL_ERROR, e.to_string())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Request {
+    #[serde(default)]
+    jsonrpc: String,
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Response {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+impl Response {
+    fn new(id: Value, outcome: Result<Value, RpcError>) -> Self {
+        let (result, error) = match outcome {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Notification {
+    jsonrpc: &'static str,
+    method: &'static str,
+    params: Value,
+}
+
+/// Result of `status`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusInfo {
+    pub device_name: String,
+    pub port: u16,
+    pub public_key: String,
+    pub pairing_code: String,
+    pub pairing: PairingStatus,
+    pub connected_device: Option<String>,
+    pub paired_devices: usize,
+    /// Counters of the connected device's session.
+    #[serde(default)]
+    pub session: Option<SessionStats>,
+}
+
+/// One entry of `devices`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceEntry {
+    pub name: String,
+    pub public_key: String,
+    pub connected: bool,
+}
+
+/// Params of `unpair`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnpairParams {
+    pub name: String,
+}
+
+/// Params of `send_text`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendTextParams {
+    pub text: String,
+    #[serde(default)]
+    pub sensitive: bool,
+    /// Wait up to this many seconds for the device's ACK; return once queued if absent.
+    #[serde(default)]
+    pub wait_secs: Option<u64>,
+    /// Paired device to queue for when it is not the connected one.
+    #[serde(default)]
+    pub device: Option<String>,
+    /// Drop the item after this many seconds if it has to be queued.
+    #[serde(default)]
+    pub expire_secs: Option<u64>,
+}
+
+/// Params of `send_image`: an encoded image, resized and re-encoded to fit the daemon's
+/// image send policy before it goes out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendImageParams {
+    /// Base64 of the encoded image file.
+    pub data: String,
+    #[serde(default)]
+    pub mime_type: Option<String>,
+    /// Wait up to this many seconds for the device's ACK; return once queued if absent.
+    #[serde(default)]
+    pub wait_secs: Option<u64>,
+    /// Paired device to queue for when it is not the connected one.
+    #[serde(default)]
+    pub device: Option<String>,
+    /// Drop the item after this many seconds if it has to be queued.
+    #[serde(default)]
+    pub expire_secs: Option<u64>,
+}
+
+impl SendImageParams {
+    pub fn new(bytes: &[u8], mime_type: Option<&str>, wait_secs: Option<u64>) -> Self {
+        Self {
+            data: base64::engine::general_purpose::STANDARD.encode(bytes),
+            mime_type: mime_type.map(str::to_string),
+            wait_secs,
+            device: None,
+            expire_secs: None,
+        }
+    }
+}
+
+/// Result of `send_text` and `send_image`: what was sent to the connected device, or
+/// queued for a device that is not connected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Queued {
+    pub device: String,
+    pub bytes: usize,
+    /// The device acknowledged the content (only waited for with `wait_secs`).
+    #[serde(default)]
+    pub delivered: bool,
+    #[serde(default)]
+    pub mime_type: Option<String>,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    /// ID in the device's outbound queue if it was not connected.
+    #[serde(default)]
+    pub queue_id: Option<u64>,
+}
+
+/// Params of `queue`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QueueParams {
+    /// Only list items for this device.
+    #[serde(default)]
+    pub device: Option<String>,
+}
+
+/// Params of `cancel_queued`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CancelQueuedParams {
+    pub id: u64,
+}
+
+/// Params of `subscribe`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SubscribeParams {
+    /// Only stream events whose `type` is listed, e.g. `ClipboardReceived`; all if empty.
+    #[serde(default)]
+    pub types: Vec<String>,
+}
+
+/// Params of `open_pairing`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OpenPairingParams {
+    /// Close the window again after this many seconds; open until closed if absent.
+    #[serde(default)]
+    pub seconds: Option<u64>,
+}
+
+/// Serve the control socket at `path` until `cancel` fires, then remove it.
+/// Fails if another daemon is already answering on the socket.
+pub async fn run_control_server(
+    path: PathBuf,
+    state: Arc<AppState>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let listener = bind(&path).await?;
+    info!("control socket listening on {}", path.display());
+
+    loop {
+        let stream = tokio::select! {
+            result = listener.accept() => result?.0,
+            _ = cancel.cancelled() => break,
+        };
+        let state = state.clone();
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &state, &cancel).await {
+                warn!("control connection ended: {}", e);
+            }
+        });
+    }
+
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
+
+async fn bind(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            bail!("a daemon is already listening on {}", path.display());
+        }
+        // Left behind by a daemon that did not shut down cleanly
+        std::fs::remove_file(path)
+            .with_context(|| format!("failed to remove stale socket {}", path.display()))?;
+    }
+    // The socket hands out the pairing code, so it must never be reachable by other users,
+    // not even between bind and chmod: bind it inside a directory only we can enter, then
+    // move it into place once it is private
+    let dir = path.with_file_name(format!(".control-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("failed to create {}", dir.display()))?;
+    let result = bind_private(&dir.join("s"), path);
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+fn bind_private(staged: &Path, path: &Path) -> Result<UnixListener> {
+    let listener = UnixListener::bind(staged)
+        .with_context(|| format!("failed to bind control socket {}", path.display()))?;
+    std::fs::set_permissions(staged, std::fs::Permissions::from_mode(0o600))?;
+    std::fs::rename(staged, path)
+        .with_context(|| format!("failed to move control socket to {}", path.display()))?;
+    Ok(listener)
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    state: &AppState,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => match line? {
+                Some(line) => line,
+                None => return Ok(()),
+            },
+            _ = cancel.cancelled() => return Ok(()),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let request: Request = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = RpcError::new(PARSE_ERROR, format!("invalid request: {}", e));
+                write_line(&mut writer, &Response::new(Value::Null, Err(error))).await?;
+                continue;
+            }
+        };
+
+        if request.method == "subscribe" {
+            let params = parse_params(request.params).and_then(|params: SubscribeParams| {
+                match params
+                    .types
+                    .iter()
+                    .find(|t| !ServerEvent::TYPES.contains(&t.as_str()))
+                {
+                    Some(unknown) => Err(RpcError::new(
+                        INVALID_PARAMS,
+                        format!("unknown event type: {}", unknown),
+                    )),
+                    None => Ok(params),
+                }
+            });
+            let params = match params {
+                Ok(params) => params,
+                Err(error) => {
+                    write_line(&mut writer, &Response::new(request.id, Err(error))).await?;
+                    continue;
+                }
+            };
+            // Subscribe before answering so no event falls between the two
+            let events = state.subscribe();
+            let ok = Response::new(request.id, Ok(Value::Bool(true)));
+            write_line(&mut writer, &ok).await?;
+            return stream_events(events, &params.types, &mut lines, &mut writer, cancel).await;
+        }
+
+        let outcome = dispatch(state, &request.method, request.params).await;
+        write_line(&mut writer, &Response::new(request.id, outcome)).await?;
+    }
+}
+
+async fn stream_events(
+    mut events: broadcast::Receiver<ServerEvent>,
+    types: &[String],
+    lines: &mut Lines<BufReader<OwnedReadHalf>>,
+    writer: &mut OwnedWriteHalf,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    let event = serde_json::to_value(&event)?;
+                    let wanted = types.is_empty()
+                        || event["type"].as_str().is_some_and(|t| types.iter().any(|w| w == t));
+                    if wanted {
+                        let notification = Notification {
+                            jsonrpc: "2.0",
+                            method: "event",
+                            params: event,
+                        };
+                        write_line(writer, &notification).await?;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("control subscriber missed {} events", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            // Anything the client sends is ignored; end of input means it went away
+            line = lines.next_line() => {
+                if line?.is_none() {
+                    return Ok(());
+                }
+            }
+            _ = cancel.cancelled() => return Ok(()),
+        }
+    }
+}
+
+async fn write_line(writer: &mut OwnedWriteHalf, value: &impl Serialize) -> Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    Ok(())
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    // Allow omitting params entirely for methods whose fields are all optional
+    let params = if params.is_null() {
+        Value::Object(Default::default())
+    } else {
+        params
+    };
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+fn to_result(value: impl Serialize) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))
+}
+
+async fn dispatch(state: &AppState, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "status" => to_result(status(state).await?),
+        "devices" => to_result(devices(state).await?),
+        "unpair" => {
+            let params: UnpairParams = parse_params(params)?;
+            let removed = state.store.remove_paired_device(&params.name)?;
+            state.queue.clear(&params.name)?;
+            to_result(removed)
+        }
+        "history" => to_result(state.history.read().await.entries()),
+        "send_text" => to_result(send_text(state, parse_params(params)?).await?),
+        "send_image" => to_result(send_image(state, parse_params(params)?).await?),
+        "queue" => {
+            let params: QueueParams = parse_params(params)?;
+            to_result(state.queue.items(params.device.as_deref())?)
+        }
+        "cancel_queued" => {
+            let params: CancelQueuedParams = parse_params(params)?;
+            to_result(queue::cancel(state, params.id)?)
+        }
+        "open_pairing" => {
+            let params: OpenPairingParams = parse_params(params)?;
+            state.pairing.open(params.seconds.map(Duration::from_secs));
+            to_result(pairing_changed(state))
+        }
+        "close_pairing" => {
+            state.pairing.close();
+            to_result(pairing_changed(state))
+        }
+        "reload_config" => to_result(config::reload(state).await?),
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("unknown method: {}", method),
+        )),
+    }
+}
+
+async fn status(state: &AppState) -> Result<StatusInfo> {
+    Ok(StatusInfo {
+        device_name: state.device_name.clone(),
+        port: state.listen_port().unwrap_or(state.port),
+        public_key: state.identity.public_key_hex(),
+        pairing_code: state.pairing_code.clone(),
+        pairing: state.pairing.status(),
+        connected_device: state.connected_device.read().await.clone(),
+        paired_devices: state.store.list_paired_devices()?.len(),
+        session: state.stats.snapshot(),
+    })
+}
+
+async fn devices(state: &AppState) -> Result<Vec<DeviceEntry>> {
+    let connected = state.connected_device.read().await.clone();
+    let mut devices: Vec<DeviceEntry> = state
+        .store
+        .list_paired_devices()?
+        .into_iter()
+        .map(|(name, public_key)| DeviceEntry {
+            connected: connected.as_deref() == Some(name.as_str()),
+            name,
+            public_key,
+        })
+        .collect();
+    devices.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(devices)
+}
+
+fn pairing_changed(state: &AppState) -> PairingStatus {
+    let status = state.pairing.status();
+    state.emit(ServerEvent::PairingWindowChanged {
+        open: status.open,
+        remaining_secs: status.remaining_secs,
+    });
+    status
+}
+
+async fn send_text(state: &AppState, params: SendTextParams) -> Result<Queued, RpcError> {
+    if params.text.len() > MAX_TEXT_SIZE {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            format!(
+                "text too large to send ({} bytes, max {})",
+                params.text.len(),
+                MAX_TEXT_SIZE
+            ),
+        ));
+    }
+    let content = OutboundContent::Text {
+        text: params.text,
+        sensitive: params.sensitive,
+    };
+    let outbound = send_or_queue(state, params.device, content, params.expire_secs).await?;
+    finish_send(outbound, params.wait_secs, None).await
+}
+
+async fn send_image(state: &AppState, params: SendImageParams) -> Result<Queued, RpcError> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(params.data.as_bytes())
+        .map_err(|e| RpcError::new(INVALID_PARAMS, format!("invalid base64 image data: {}", e)))?;
+
+    // Decoding and re-encoding large images is CPU-bound
+    let policy = state.settings().image_send;
+    let mime_type = params.mime_type;
+    let prepared = tokio::task::spawn_blocking(move || {
+        let (rgba, width, height) = clipboard::decode_image_to_rgba(&bytes, mime_type.as_deref())?;
+        imaging::prepare_image(rgba, width, height, &policy)
+    })
+    .await
+    .map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))?
+    .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?;
+
+    let size = (prepared.width, prepared.height);
+    let content = OutboundContent::Image {
+        bytes: prepared.bytes,
+        mime_type: prepared.mime_type,
+        width: prepared.width,
+        height: prepared.height,
+    };
+    let outbound = send_or_queue(state, params.device, content, params.expire_secs).await?;
+    finish_send(outbound, params.wait_secs, Some(size)).await
+}
+
+async fn send_or_queue(
+    state: &AppState,
+    device: Option<String>,
+    content: OutboundContent,
+    expire_secs: Option<u64>,
+) -> Result<Outbound, RpcError> {
+    let expiry = expire_secs.map(Duration::from_secs);
+    match queue::send_or_queue(state, device.as_deref(), content, expiry).await {
+        Ok(Some(outbound)) => Ok(outbound),
+        Ok(None) => Err(RpcError::new(NO_SESSION, "no device connected")),
+        Err(e) => Err(RpcError::new(INVALID_PARAMS, format!("{:#}", e))),
+    }
+}
+
+/// Describe a sent or queued item, waiting for the device's ACK first if asked to.
+async fn finish_send(
+    outbound: Outbound,
+    wait_secs: Option<u64>,
+    size: Option<(u32, u32)>,
+) -> Result<Queued, RpcError> {
+    match outbound {
+        Outbound::Sent {
+            device,
+            pending,
+            bytes,
+            mime_type,
+        } => {
+            let delivered = match wait_secs {
+                Some(secs) => {
+                    await_delivery(pending, &device, secs).await?;
+                    true
+                }
+                None => false,
+            };
+            Ok(Queued {
+                device,
+                bytes,
+                delivered,
+                mime_type,
+                width: size.map(|(width, _)| width),
+                height: size.map(|(_, height)| height),
+                queue_id: None,
+            })
+        }
+        Outbound::Queued(item) => Ok(queued_item(item)),
+    }
+}
+
+fn queued_item(item: QueuedItem) -> Queued {
+    let (mime_type, width, height) = match &item.content {
+        queue::QueuedContent::Text { .. } => (None, None, None),
+        queue::QueuedContent::Image {
+            mime_type,
+            width,
+            height,
+            ..
+        } => (Some(mime_type.clone()), Some(*width), Some(*height)),
+    };
+    Queued {
+        bytes: item.bytes(),
+        device: item.device,
+        delivered: false,
+        mime_type,
+        width,
+        height,
+        queue_id: Some(item.id),
+    }
+}
+
+/// Wait for `device` to acknowledge the text or image just queued, or to refuse it.
+async fn await_delivery(
+    pending: PendingDelivery,
+    device: &str,
+    wait_secs: u64,
+) -> Result<(), RpcError> {
+    match pending.outcome(Duration::from_secs(wait_secs)).await {
+        Delivery::Delivered => Ok(()),
+        Delivery::Rejected { reason, .. } => Err(RpcError::new(REJECTED, reason)),
+        Delivery::TimedOut => Err(RpcError::new(
+            TIMEOUT,
+            format!("{} did not acknowledge within {}s", device, wait_secs),
+        )),
+        Delivery::Disconnected => Err(RpcError::new(
+            NO_SESSION,
+            format!("{} disconnected before acknowledging", device),
+        )),
+    }
+}
+
+/// Client side of the control socket.
+pub struct ControlClient {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    next_id: u64,
+}
+
+impl ControlClient {
+    pub async fn connect(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .with_context(|| format!("failed to connect to {}", path.display()))?;
+        Ok(Self::new(stream))
+    }
+
+    /// Connect if a daemon is listening on `path`; `None` if none is running.
+    pub async fn connect_if_running(path: &Path) -> Result<Option<Self>> {
+        match UnixStream::connect(path).await {
+            Ok(stream) => Ok(Some(Self::new(stream))),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e).with_context(|| format!("failed to connect to {}", path.display())),
+        }
+    }
+
+    fn new(stream: UnixStream) -> Self {
+        let (reader, writer) = stream.into_split();
+        Self {
+            lines: BufReader::new(reader).lines(),
+            writer,
+            next_id: 1,
+        }
+    }
+
+    /// Call `method` and wait for its result. A JSON-RPC error comes back as an
+    /// `RpcError` inside the returned `anyhow::Error`.
+    pub async fn call<T: DeserializeOwned>(
+        &mut self,
+        method: &str,
+        params: impl Serialize,
+    ) -> Result<T> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = Request {
+            jsonrpc: "2.0".to_string(),
+            id: Value::from(id),
+            method: method.to_string(),
+            params: serde_json::to_value(params)?,
+        };
+        write_line(&mut self.writer, &request).await?;
+
+        loop {
+            let Some(line) = self.lines.next_line().await? else {
+                bail!("daemon closed the control connection");
+            };
+            let value: Value = serde_json::from_str(&line)?;
+            if value.get("method").is_some() {
+                continue; // notification
+            }
+            let response: Response = serde_json::from_value(value)?;
+            if response.id != id {
+                continue;
+            }
+            if let Some(error) = response.error {
+                return Err(error.into());
+            }
+            return Ok(serde_json::from_value(
+                response.result.unwrap_or(Value::Null),
+            )?);
+        }
+    }
+
+    /// Start receiving events, only those of the given `types` unless it is empty; read
+    /// them with `next_event`. The connection cannot be used for calls afterwards.
+    pub async fn subscribe(&mut self, types: &[String]) -> Result<()> {
+        let params = SubscribeParams {
+            types: types.to_vec(),
+        };
+        self.call::<bool>("subscribe", params).await?;
+        Ok(())
+    }
+
+    /// Next event as sent by the daemon (`{"type": ..., "data": ...}`), or `None` once
+    /// the daemon has closed the connection.
+    pub async fn next_event(&mut self) -> Result<Option<Value>> {
+        loop {
+            let Some(line) = self.lines.next_line().await? else {
+                return Ok(None);
+            };
+            let mut value: Value = serde_json::from_str(&line)?;
+            if value.get("method").and_then(Value::as_str) == Some("event") {
+                return Ok(Some(value["params"].take()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::MemoryClipboard;
+    use crate::crypto::Identity;
+    use crate::history::HistoryEntry;
+    use crate::protocol::{ClipboardMeta, Message, MessageType};
+    use crate::server::OUTBOUND_CAPACITY;
+    use tempfile::TempDir;
+    use tokio::sync::mpsc;
+
+    struct Daemon {
+        _dir: TempDir,
+        path: PathBuf,
+        state: Arc<AppState>,
+        cancel: CancellationToken,
+        server: tokio::task::JoinHandle<Result<()>>,
+    }
+
+    async fn start_daemon() -> Daemon {
+        let dir = TempDir::new().unwrap();
+        let store = DeviceStore::new(dir.path().to_path_buf()).unwrap();
+        let identity = Identity::load_or_generate(&store).unwrap();
+        store.save_paired_device("phone", &[7u8; 32]).unwrap();
+        let path = socket_path(&store);
+        let mut state = AppState::new(identity, "123456".into(), "test-mac".into(), store, 9876);
+        state.clipboard = Arc::new(MemoryClipboard::default());
+        let state = Arc::new(state);
+        let cancel = CancellationToken::new();
+        let server = tokio::spawn(run_control_server(
+            path.clone(),
+            state.clone(),
+            cancel.clone(),
+        ));
+        // Wait for the socket to appear
+        for _ in 0..100 {
+            if path.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        Daemon {
+            _dir: dir,
+            path,
+            state,
+            cancel,
+            server,
+        }
+    }
+
+    async fn connect_session(state: &AppState) -> mpsc::Receiver<Message> {
+        let (tx, rx) = mpsc::channel(OUTBOUND_CAPACITY);
+        *state.session_tx.write().await = Some(tx);
+        *state.connected_device.write().await = Some("phone".to_string());
+        rx
+    }
+
+    fn rpc_code(e: &anyhow::Error) -> i64 {
+        e.downcast_ref::<RpcError>()
+            .expect("expected an RpcError")
+            .code
+    }
+
+    #[tokio::test]
+    async fn test_status_and_devices() {
+        let daemon = start_daemon().await;
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+
+        let status: StatusInfo = client.call("status", ()).await.unwrap();
+        assert_eq!(status.pairing_code, "123456");
+        assert_eq!(status.device_name, "test-mac");
+        assert_eq!(status.paired_devices, 1);
+        assert!(status.pairing.open);
+        assert_eq!(status.connected_device, None);
+
+        let _rx = connect_session(&daemon.state).await;
+        let devices: Vec<DeviceEntry> = client.call("devices", ()).await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "phone");
+        assert!(devices[0].connected);
+    }
+
+    #[tokio::test]
+    async fn test_unpair() {
+        let daemon = start_daemon().await;
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+        let params = UnpairParams {
+            name: "phone".to_string(),
+        };
+        assert!(client.call::<bool>("unpair", &params).await.unwrap());
+        assert!(!client.call::<bool>("unpair", &params).await.unwrap());
+        assert!(daemon.state.store.list_paired_devices().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_send_text_without_session() {
+        let daemon = start_daemon().await;
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+        let params = SendTextParams {
+            text: "hi".to_string(),
+            sensitive: false,
+            wait_secs: None,
+            device: None,
+            expire_secs: None,
+        };
+        let err = client
+            .call::<Queued>("send_text", &params)
+            .await
+            .unwrap_err();
+        assert_eq!(rpc_code(&err), NO_SESSION);
+    }
+
+    #[tokio::test]
+    async fn test_offline_send_is_queued_and_cancellable() {
+        let daemon = start_daemon().await;
+        let mut settings = daemon.state.settings();
+        settings.queue.enabled = true;
+        daemon.state.set_settings(settings);
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+
+        let queued: Queued = client.call("send_text", text_params(5)).await.unwrap();
+        assert_eq!(queued.device, "phone");
+        assert!(!queued.delivered);
+        let id = queued.queue_id.expect("queued while offline");
+
+        let items: Vec<QueuedItem> = client.call("queue", QueueParams::default()).await.unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].id, id);
+
+        let params = CancelQueuedParams { id };
+        assert!(client.call::<bool>("cancel_queued", &params).await.unwrap());
+        assert!(!client.call::<bool>("cancel_queued", &params).await.unwrap());
+        assert!(daemon.state.queue.items(None).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_send_sensitive_text() {
+        let daemon = start_daemon().await;
+        let mut rx = connect_session(&daemon.state).await;
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+        let params = SendTextParams {
+            text: "hunter2".to_string(),
+            sensitive: true,
+            wait_secs: None,
+            device: None,
+            expire_secs: None,
+        };
+        let queued: Queued = client.call("send_text", &params).await.unwrap();
+        assert_eq!(queued.device, "phone");
+        assert_eq!(queued.bytes, 7);
+
+        assert_eq!(
+            rx.recv().await.unwrap().msg_type,
+            MessageType::ClipboardMeta
+        );
+        let msg = rx.recv().await.unwrap();
+        assert_eq!(msg.msg_type, MessageType::ClipboardSend);
+        assert_eq!(msg.payload_text().unwrap(), "hunter2");
+    }
+
+    #[tokio::test]
+    async fn test_send_image() {
+        let daemon = start_daemon().await;
+        let mut rx = connect_session(&daemon.state).await;
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+        let png = clipboard::encode_rgba_to_png(&[255u8; 4 * 4 * 3], 4, 3).unwrap();
+        let params = SendImageParams::new(&png, None, None);
+        let queued: Queued = client.call("send_image", &params).await.unwrap();
+        assert_eq!((queued.width, queued.height), (Some(4), Some(3)));
+        assert_eq!(queued.mime_type.as_deref(), Some("image/png"));
+
+        assert_eq!(
+            rx.recv().await.unwrap().msg_type,
+            MessageType::ImageSendStart
+        );
+        assert_eq!(rx.recv().await.unwrap().msg_type, MessageType::ImageChunk);
+        assert_eq!(rx.recv().await.unwrap().msg_type, MessageType::ImageSendEnd);
+    }
+
+    /// Message ID of the text queued on the session channel.
+    async fn sent_text_id(rx: &mut mpsc::Receiver<Message>) -> u64 {
+        let meta = rx.recv().await.unwrap();
+        assert_eq!(meta.msg_type, MessageType::ClipboardMeta);
+        let meta = ClipboardMeta::from_json(&meta.payload_text().unwrap()).unwrap();
+        assert_eq!(
+            rx.recv().await.unwrap().msg_type,
+            MessageType::ClipboardSend
+        );
+        meta.id.expect("sent text carries a message ID")
+    }
+
+    fn text_params(wait_secs: u64) -> SendTextParams {
+        SendTextParams {
+            text: "hello".to_string(),
+            sensitive: false,
+            wait_secs: Some(wait_secs),
+            device: None,
+            expire_secs: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_waits_for_ack() {
+        let daemon = start_daemon().await;
+        let mut rx = connect_session(&daemon.state).await;
+        let state = daemon.state.clone();
+        // Play the session: acknowledge whatever arrives
+        tokio::spawn(async move {
+            let id = sent_text_id(&mut rx).await;
+            state.deliveries.resolve(id, Delivery::Delivered);
+        });
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+        let queued: Queued = client.call("send_text", text_params(5)).await.unwrap();
+        assert!(queued.delivered);
+    }
+
+    #[tokio::test]
+    async fn test_send_reports_rejection() {
+        let daemon = start_daemon().await;
+        let mut rx = connect_session(&daemon.state).await;
+        let state = daemon.state.clone();
+        tokio::spawn(async move {
+            let id = sent_text_id(&mut rx).await;
+            let rejected = Delivery::Rejected {
+                code: "declined".to_string(),
+                reason: "user declined".to_string(),
+            };
+            state.deliveries.resolve(id, rejected);
+        });
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+        let err = client
+            .call::<Queued>("send_text", text_params(5))
+            .await
+            .unwrap_err();
+        assert_eq!(rpc_code(&err), REJECTED);
+        assert_eq!(err.to_string(), "user declined");
+    }
+
+    #[tokio::test]
+    async fn test_send_reports_disconnect() {
+        let daemon = start_daemon().await;
+        let mut rx = connect_session(&daemon.state).await;
+        let state = daemon.state.clone();
+        tokio::spawn(async move {
+            sent_text_id(&mut rx).await;
+            state.deliveries.disconnect_all();
+        });
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+        let err = client
+            .call::<Queued>("send_text", text_params(5))
+            .await
+            .unwrap_err();
+        assert_eq!(rpc_code(&err), NO_SESSION);
+    }
+
+    #[tokio::test]
+    async fn test_send_times_out() {
+        let daemon = start_daemon().await;
+        let _rx = connect_session(&daemon.state).await;
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+        let err = client
+            .call::<Queued>("send_text", text_params(0))
+            .await
+            .unwrap_err();
+        assert_eq!(rpc_code(&err), TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_pairing_window_control() {
+        let daemon = start_daemon().await;
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+
+        let closed: PairingStatus = client.call("close_pairing", ()).await.unwrap();
+        assert!(!closed.open);
+        assert!(!daemon.state.pairing.is_open());
+
+        let params = OpenPairingParams { seconds: Some(60) };
+        let opened: PairingStatus = client.call("open_pairing", &params).await.unwrap();
+        assert!(opened.open);
+        assert_eq!(opened.remaining_secs, Some(60));
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let daemon = start_daemon().await;
+        daemon
+            .state
+            .history
+            .write()
+            .await
+            .push(HistoryEntry::text("phone", "hello"));
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+        let entries: Vec<HistoryEntry> = client.call("history", ()).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].preview, "hello");
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let daemon = start_daemon().await;
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+        let err = client.call::<Value>("reboot", ()).await.unwrap_err();
+        assert_eq!(rpc_code(&err), METHOD_NOT_FOUND);
+        let err = client.call::<bool>("unpair", ()).await.unwrap_err();
+        assert_eq!(rpc_code(&err), INVALID_PARAMS);
+
+        // Malformed lines get an error response and the connection stays usable
+        let mut stream = UnixStream::connect(&daemon.path).await.unwrap();
+        stream.write_all(b"not json\n").await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let line = lines.next_line().await.unwrap().unwrap();
+        let response: Response = serde_json::from_str(&line).unwrap();
+        assert_eq!(response.error.unwrap().code, PARSE_ERROR);
+        assert!(client.call::<StatusInfo>("status", ()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_streams_events() {
+        let daemon = start_daemon().await;
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+        client.subscribe(&[]).await.unwrap();
+
+        daemon.state.emit(ServerEvent::ClipboardSent { chars: 5 });
+        let event = client.next_event().await.unwrap().unwrap();
+        assert_eq!(event["type"], "ClipboardSent");
+        assert_eq!(event["data"]["chars"], 5);
+
+        daemon.cancel.cancel();
+        assert!(client.next_event().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_filters_by_type() {
+        let daemon = start_daemon().await;
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+        let types = ["DeviceConnected".to_string(), "ImageSent".to_string()];
+        client.subscribe(&types).await.unwrap();
+
+        daemon.state.emit(ServerEvent::ClipboardSent { chars: 5 });
+        daemon.state.emit(ServerEvent::DeviceConnected {
+            name: "phone".to_string(),
+        });
+        daemon.state.emit(ServerEvent::SensitiveCleared);
+        daemon.state.emit(ServerEvent::ImageSent { bytes: 9 });
+
+        let event = client.next_event().await.unwrap().unwrap();
+        assert_eq!(event["type"], "DeviceConnected");
+        let event = client.next_event().await.unwrap().unwrap();
+        assert_eq!(event["type"], "ImageSent");
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_rejects_unknown_type() {
+        let daemon = start_daemon().await;
+        let mut client = ControlClient::connect(&daemon.path).await.unwrap();
+        let types = [
+            "DeviceConnected".to_string(),
+            "ClipboardRecieved".to_string(),
+        ];
+        let err = client.subscribe(&types).await.unwrap_err();
+        assert_eq!(rpc_code(&err), INVALID_PARAMS);
+        assert!(err.to_string().contains("ClipboardRecieved"));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_removes_socket() {
+        let daemon = start_daemon().await;
+        assert!(ControlClient::connect_if_running(&daemon.path)
+            .await
+            .unwrap()
+            .is_some());
+        daemon.cancel.cancel();
+        daemon.server.await.unwrap().unwrap();
+        assert!(!daemon.path.exists());
+        assert!(ControlClient::connect_if_running(&daemon.path)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_socket_is_private() {
+        let daemon = start_daemon().await;
+        let mode = std::fs::metadata(&daemon.path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Only the socket is left behind in the store directory, not the staging directory
+        let dir = daemon.path.parent().unwrap();
+        assert!(std::fs::read_dir(dir).unwrap().all(|e| !e
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with(".control-")));
+        assert!(ControlClient::connect_if_running(&daemon.path)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_second_daemon_refused_and_stale_socket_replaced() {
+        let daemon = start_daemon().await;
+        let err = bind(&daemon.path).await.unwrap_err();
+        assert!(err.to_string().contains("already listening"));
+
+        daemon.cancel.cancel();
+        daemon.server.await.unwrap().unwrap();
+        // A socket file nobody listens on
+        drop(std::os::unix::net::UnixListener::bind(&daemon.path).unwrap());
+        assert!(daemon.path.exists());
+        assert!(bind(&daemon.path).await.is_ok());
+    }
+}
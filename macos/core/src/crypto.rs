@@ -1,13 +1,14 @@
 use anyhow::{bail, Context, Result};
 use hkdf::Hkdf;
 use rand::Rng;
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 use snow::{Builder, TransportState};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::future::Future;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tracing::{debug, info};
 
-use crate::protocol::{HANDSHAKE_PAIRED, HANDSHAKE_PAIRING};
+use crate::protocol::{Message, HANDSHAKE_PAIRED, HANDSHAKE_PAIRING};
 use crate::storage::DeviceStore;
 
 /// Noise protocol pattern for initial pairing (with pre-shared key).
@@ -62,15 +63,43 @@ impl Identity {
     pub fn public_key_hex(&self) -> String {
         hex::encode(&self.public_key)
     }
+
+    pub fn fingerprint(&self) -> String {
+        key_fingerprint(&self.public_key)
+    }
+}
+
+/// Short identifier for a static public key, published over mDNS: the first 8 bytes of
+/// its SHA-256, hex encoded.
+pub fn key_fingerprint(public_key: &[u8]) -> String {
+    hex::encode(&Sha256::digest(public_key)[..8])
+}
+
+/// Sends and receives protocol messages for a session. Implemented by `NoiseTransport`;
+/// other implementations let the session logic run without a socket (tests, fuzzing).
+pub trait MessageTransport: Send {
+    fn send_message(&mut self, msg: &Message) -> impl Future<Output = Result<()>> + Send;
+
+    fn recv_message(&mut self) -> impl Future<Output = Result<Message>> + Send;
 }
 
-/// Encrypted transport wrapping a TCP stream with Noise.
-pub struct NoiseTransport {
+/// Encrypted transport wrapping a byte stream (normally a TCP connection) with Noise.
+pub struct NoiseTransport<S = TcpStream> {
     transport: TransportState,
-    stream: TcpStream,
+    stream: S,
+    /// Bytes read of a frame that is not complete yet.
+    pending: Vec<u8>,
 }
 
-impl NoiseTransport {
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> NoiseTransport<S> {
+    fn new(transport: TransportState, stream: S) -> Self {
+        Self {
+            transport,
+            stream,
+            pending: Vec::new(),
+        }
+    }
+
     /// Send an encrypted message.
     pub async fn send(&mut self, plaintext: &[u8]) -> Result<()> {
         let mut buf = vec![0u8; MAX_NOISE_MSG_LEN];
@@ -86,13 +115,24 @@ impl NoiseTransport {
     }
 
     /// Receive and decrypt a message.
+    ///
+    /// Cancel-safe: a partly received frame is kept for the next call, so the session can
+    /// wait for a message alongside timers in `select!` without losing its place.
     pub async fn recv(&mut self) -> Result<Vec<u8>> {
-        let len = self.stream.read_u16().await? as usize;
-        if len > MAX_NOISE_MSG_LEN {
-            bail!("noise message too large: {} bytes", len);
-        }
-        let mut ciphertext = vec![0u8; len];
-        self.stream.read_exact(&mut ciphertext).await?;
+        let ciphertext = loop {
+            if self.pending.len() >= 2 {
+                let len = u16::from_be_bytes([self.pending[0], self.pending[1]]) as usize;
+                if len > MAX_NOISE_MSG_LEN {
+                    bail!("noise message too large: {} bytes", len);
+                }
+                if self.pending.len() >= 2 + len {
+                    break self.pending.drain(..2 + len).skip(2).collect::<Vec<u8>>();
+                }
+            }
+            if self.stream.read_buf(&mut self.pending).await? == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+        };
         let mut plaintext = vec![0u8; MAX_NOISE_MSG_LEN];
         let plain_len = self
             .transport
@@ -102,31 +142,40 @@ impl NoiseTransport {
     }
 
     /// Send a protocol message (encode then encrypt).
-    pub async fn send_message(&mut self, msg: &crate::protocol::Message) -> Result<()> {
+    pub async fn send_message(&mut self, msg: &Message) -> Result<()> {
         let encoded = msg.encode();
         self.send(&encoded).await
     }
 
     /// Receive and decode a protocol message.
-    pub async fn recv_message(&mut self) -> Result<crate::protocol::Message> {
+    pub async fn recv_message(&mut self) -> Result<Message> {
         let data = self.recv().await?;
-        crate::protocol::Message::decode(&data)
+        Message::decode(&data)
     }
 
-    /// Get the inner TCP stream reference (for shutdown).
-    #[allow(dead_code)]
-    pub fn stream_mut(&mut self) -> &mut TcpStream {
+    /// Get the inner stream reference (for shutdown).
+    pub fn stream_mut(&mut self) -> &mut S {
         &mut self.stream
     }
 }
 
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> MessageTransport for NoiseTransport<S> {
+    fn send_message(&mut self, msg: &Message) -> impl Future<Output = Result<()>> + Send {
+        NoiseTransport::send_message(self, msg)
+    }
+
+    fn recv_message(&mut self) -> impl Future<Output = Result<Message>> + Send {
+        NoiseTransport::recv_message(self)
+    }
+}
+
 /// Perform a Noise XXpsk0 handshake as the responder (receiver/macOS side).
 /// Returns the transport and the remote's static public key.
-pub async fn handshake_pairing_responder(
-    mut stream: TcpStream,
+pub async fn handshake_pairing_responder<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    mut stream: S,
     identity: &Identity,
     pairing_code: &str,
-) -> Result<(NoiseTransport, Vec<u8>)> {
+) -> Result<(NoiseTransport<S>, Vec<u8>)> {
     let psk = derive_psk_from_code(pairing_code);
 
     let builder = Builder::new(NOISE_PATTERN_PAIRING.parse()?)
@@ -167,15 +216,15 @@ pub async fn handshake_pairing_responder(
     );
 
     let transport = handshake.into_transport_mode()?;
-    Ok((NoiseTransport { transport, stream }, remote_static))
+    Ok((NoiseTransport::new(transport, stream), remote_static))
 }
 
 /// Perform a Noise KK handshake as the responder for a paired device.
-pub async fn handshake_paired_responder(
-    mut stream: TcpStream,
+pub async fn handshake_paired_responder<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    mut stream: S,
     identity: &Identity,
     remote_static_key: &[u8],
-) -> Result<NoiseTransport> {
+) -> Result<NoiseTransport<S>> {
     let builder = Builder::new(NOISE_PATTERN_PAIRED.parse()?)
         .local_private_key(&identity.private_key)
         .remote_public_key(remote_static_key);
@@ -198,22 +247,106 @@ pub async fn handshake_paired_responder(
 
     info!("paired handshake complete");
     let transport = handshake.into_transport_mode()?;
-    Ok(NoiseTransport { transport, stream })
+    Ok(NoiseTransport::new(transport, stream))
 }
 
-/// Determine the handshake type and dispatch accordingly.
-pub async fn accept_connection(
-    mut stream: TcpStream,
+/// Pair with a receiver as the initiator (the Android side of a Noise XXpsk0 handshake).
+/// Returns the transport and the receiver's static public key.
+pub async fn handshake_pairing_initiator<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    mut stream: S,
     identity: &Identity,
     pairing_code: &str,
+) -> Result<(NoiseTransport<S>, Vec<u8>)> {
+    let psk = derive_psk_from_code(pairing_code);
+
+    let builder = Builder::new(NOISE_PATTERN_PAIRING.parse()?)
+        .local_private_key(&identity.private_key)
+        .psk(0, &psk);
+    let mut handshake = builder.build_initiator()?;
+    let mut buf = vec![0u8; MAX_NOISE_MSG_LEN];
+
+    stream.write_u8(HANDSHAKE_PAIRING).await?;
+
+    // -> e (send message 1)
+    debug!("pairing: sending message 1");
+    let len = handshake.write_message(&[], &mut buf)?;
+    stream.write_u16(len as u16).await?;
+    stream.write_all(&buf[..len]).await?;
+    stream.flush().await?;
+
+    // <- e, ee, s, es (read message 2)
+    debug!("pairing: waiting for message 2");
+    let len = stream.read_u16().await? as usize;
+    let mut msg = vec![0u8; len];
+    stream.read_exact(&mut msg).await?;
+    handshake.read_message(&msg, &mut buf)?;
+
+    // -> s, se (send message 3)
+    debug!("pairing: sending message 3");
+    let len = handshake.write_message(&[], &mut buf)?;
+    stream.write_u16(len as u16).await?;
+    stream.write_all(&buf[..len]).await?;
+    stream.flush().await?;
+
+    let remote_static = handshake
+        .get_remote_static()
+        .context("no remote static key after handshake")?
+        .to_vec();
+
+    let transport = handshake.into_transport_mode()?;
+    Ok((NoiseTransport::new(transport, stream), remote_static))
+}
+
+/// Reconnect to a paired receiver as the initiator (Noise KK).
+pub async fn handshake_paired_initiator<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    mut stream: S,
+    identity: &Identity,
+    remote_static_key: &[u8],
+) -> Result<NoiseTransport<S>> {
+    let builder = Builder::new(NOISE_PATTERN_PAIRED.parse()?)
+        .local_private_key(&identity.private_key)
+        .remote_public_key(remote_static_key);
+    let mut handshake = builder.build_initiator()?;
+    let mut buf = vec![0u8; MAX_NOISE_MSG_LEN];
+
+    // Marker and our static key so the receiver can look us up
+    stream.write_u8(HANDSHAKE_PAIRED).await?;
+    stream.write_all(&identity.public_key).await?;
+
+    // -> e, es, ss (send message 1)
+    debug!("paired: sending message 1");
+    let len = handshake.write_message(&[], &mut buf)?;
+    stream.write_u16(len as u16).await?;
+    stream.write_all(&buf[..len]).await?;
+    stream.flush().await?;
+
+    // <- e, ee, se (read message 2)
+    debug!("paired: waiting for message 2");
+    let len = stream.read_u16().await? as usize;
+    let mut msg = vec![0u8; len];
+    stream.read_exact(&mut msg).await?;
+    handshake.read_message(&msg, &mut buf)?;
+
+    let transport = handshake.into_transport_mode()?;
+    Ok(NoiseTransport::new(transport, stream))
+}
+
+/// Determine the handshake type and dispatch accordingly.
+pub async fn accept_connection<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    mut stream: S,
+    identity: &Identity,
+    pairing_code: Option<&str>,
     store: &DeviceStore,
-) -> Result<(NoiseTransport, String)> {
+) -> Result<(NoiseTransport<S>, String)> {
     // Read 1-byte handshake type marker
     let handshake_type = stream.read_u8().await?;
 
     match handshake_type {
         HANDSHAKE_PAIRING => {
             info!("incoming pairing request");
+            let Some(pairing_code) = pairing_code else {
+                bail!("pairing is closed");
+            };
             let (transport, remote_key) =
                 handshake_pairing_responder(stream, identity, pairing_code).await?;
             let device_name = format!("device-{}", hex::encode(&remote_key[..4]));
@@ -301,6 +434,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_key_fingerprint() {
+        // SHA-256("") = e3b0c44298fc1c14...
+        assert_eq!(key_fingerprint(&[]), "e3b0c44298fc1c14");
+        assert_eq!(key_fingerprint(&[1; 32]).len(), 16);
+        assert_ne!(key_fingerprint(&[1; 32]), key_fingerprint(&[2; 32]));
+    }
+
     #[test]
     fn test_identity_public_key_hex() {
         let identity = Identity {
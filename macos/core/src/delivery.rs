@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+/// How a send to the connected device ended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Delivery {
+    /// The device acknowledged the item.
+    Delivered,
+    /// The device (or our policy for it) refused the item.
+    Rejected { code: String, reason: String },
+    /// No acknowledgement arrived in time.
+    TimedOut,
+    /// The session ended before the device answered.
+    Disconnected,
+}
+
+impl fmt::Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Delivered => write!(f, "delivered"),
+            Self::Rejected { reason, .. } => write!(f, "rejected: {}", reason),
+            Self::TimedOut => write!(f, "not acknowledged in time"),
+            Self::Disconnected => write!(f, "disconnected before acknowledging"),
+        }
+    }
+}
+
+type Waiting = Mutex<HashMap<u64, oneshot::Sender<Delivery>>>;
+
+/// Sends waiting for their ACK or ERROR, keyed by the message ID sent with them.
+#[derive(Default)]
+pub struct Deliveries {
+    next_id: AtomicU64,
+    pending: Arc<Waiting>,
+}
+
+impl Deliveries {
+    /// Allocate a message ID for an item about to be sent.
+    pub fn register(&self) -> PendingDelivery {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        PendingDelivery {
+            id,
+            rx,
+            waiting: Arc::downgrade(&self.pending),
+        }
+    }
+
+    /// Report the outcome for `id`. Returns false if nobody is waiting for it.
+    pub fn resolve(&self, id: u64, outcome: Delivery) -> bool {
+        let tx = self.pending.lock().unwrap().remove(&id);
+        match tx {
+            Some(tx) => tx.send(outcome).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Drop a send that never went out.
+    pub fn cancel(&self, id: u64) {
+        self.pending.lock().unwrap().remove(&id);
+    }
+
+    /// The session ended: every outstanding send resolves as `Disconnected`.
+    pub fn disconnect_all(&self) {
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+/// A queued item whose outcome is not known yet.
+#[derive(Debug)]
+pub struct PendingDelivery {
+    /// Message ID the device echoes in its ACK or ERROR.
+    pub id: u64,
+    rx: oneshot::Receiver<Delivery>,
+    waiting: Weak<Waiting>,
+}
+
+impl PendingDelivery {
+    /// Wait up to `timeout` for the device to acknowledge or refuse the item.
+    /// Giving up stops tracking the ID, so a late answer is ignored.
+    pub async fn outcome(mut self, timeout: Duration) -> Delivery {
+        match tokio::time::timeout(timeout, &mut self.rx).await {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(_)) => Delivery::Disconnected,
+            Err(_) => Delivery::TimedOut,
+        }
+    }
+}
+
+impl Drop for PendingDelivery {
+    fn drop(&mut self) {
+        if let Some(waiting) = self.waiting.upgrade() {
+            waiting.lock().unwrap().remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(1);
+
+    #[tokio::test]
+    async fn test_register_and_resolve() {
+        let deliveries = Deliveries::default();
+        let pending = deliveries.register();
+        let id = pending.id;
+        assert!(deliveries.resolve(id, Delivery::Delivered));
+        assert_eq!(pending.outcome(WAIT).await, Delivery::Delivered);
+        // Second resolve is a no-op
+        assert!(!deliveries.resolve(id, Delivery::TimedOut));
+    }
+
+    #[test]
+    fn test_ids_are_unique() {
+        let deliveries = Deliveries::default();
+        assert_ne!(deliveries.register().id, deliveries.register().id);
+    }
+
+    #[tokio::test]
+    async fn test_rejection_carries_reason() {
+        let deliveries = Deliveries::default();
+        let pending = deliveries.register();
+        let rejected = Delivery::Rejected {
+            code: "declined".to_string(),
+            reason: "rejected by user".to_string(),
+        };
+        deliveries.resolve(pending.id, rejected.clone());
+        let outcome = pending.outcome(WAIT).await;
+        assert_eq!(outcome, rejected);
+        assert_eq!(outcome.to_string(), "rejected: rejected by user");
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_send_times_out() {
+        let deliveries = Deliveries::default();
+        let pending = deliveries.register();
+        let id = pending.id;
+        assert_eq!(
+            pending.outcome(Duration::from_millis(10)).await,
+            Delivery::TimedOut
+        );
+        // The ID is no longer tracked once nobody waits for it
+        assert!(deliveries.pending.lock().unwrap().is_empty());
+        assert!(!deliveries.resolve(id, Delivery::Delivered));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_resolves_outstanding() {
+        let deliveries = Deliveries::default();
+        let a = deliveries.register();
+        let b = deliveries.register();
+        deliveries.disconnect_all();
+        assert_eq!(a.outcome(WAIT).await, Delivery::Disconnected);
+        assert_eq!(b.outcome(WAIT).await, Delivery::Disconnected);
+    }
+
+    #[test]
+    fn test_outcome_serializes_with_status() {
+        let json = serde_json::to_value(Delivery::Rejected {
+            code: "too_large".to_string(),
+            reason: "too big".to_string(),
+        })
+        .unwrap();
+        assert_eq!(json["status"], "rejected");
+        assert_eq!(json["code"], "too_large");
+        assert_eq!(
+            serde_json::to_value(Delivery::TimedOut).unwrap()["status"],
+            "timed_out"
+        );
+    }
+}
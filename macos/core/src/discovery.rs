@@ -1,36 +1,384 @@
 use anyhow::Result;
-use mdns_sd::{ServiceDaemon, ServiceInfo};
-use tracing::info;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::crypto::key_fingerprint;
+use crate::events::AppState;
+use crate::protocol::PROTOCOL_VERSION;
+use crate::storage::DeviceStore;
 
 const SERVICE_TYPE: &str = "_uclip._tcp.local.";
 
+/// What this receiver supports, published as `caps`.
+pub const CAPABILITIES: &[&str] = &[
+    "text",
+    "image",
+    "sensitive",
+    "bye",
+    "error_codes",
+    "ids",
+    "window",
+];
+
+/// Contents of the TXT record: `v`, `fp`, `caps` and `pair`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceTxt {
+    /// Protocol version (`v`).
+    pub version: u32,
+    /// Fingerprint of the receiver's static key (`fp`), see `crypto::key_fingerprint`.
+    pub fingerprint: String,
+    /// Comma-separated in `caps`.
+    pub capabilities: Vec<String>,
+    /// Whether new devices may pair right now (`pair=1` or `pair=0`).
+    pub pairing_open: bool,
+}
+
+impl ServiceTxt {
+    /// TXT record for this daemon as it is now.
+    pub fn local(state: &AppState) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            fingerprint: state.identity.fingerprint(),
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            pairing_open: state.pairing.is_open(),
+        }
+    }
+
+    fn properties(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("v", self.version.to_string()),
+            ("fp", self.fingerprint.clone()),
+            ("caps", self.capabilities.join(",")),
+            (
+                "pair",
+                if self.pairing_open { "1" } else { "0" }.to_string(),
+            ),
+        ]
+    }
+
+    /// Read the TXT record of a resolved service; `None` for receivers that publish none.
+    pub fn from_service(info: &ServiceInfo) -> Option<Self> {
+        let version = info.get_property_val_str("v")?.parse().ok()?;
+        Some(Self {
+            version,
+            fingerprint: info.get_property_val_str("fp").unwrap_or("").to_string(),
+            capabilities: info
+                .get_property_val_str("caps")
+                .unwrap_or("")
+                .split(',')
+                .filter(|c| !c.is_empty())
+                .map(str::to_string)
+                .collect(),
+            pairing_open: info.get_property_val_str("pair") == Some("1"),
+        })
+    }
+}
+
 /// Register this device as a Universal Clipboard receiver via mDNS.
 pub struct DiscoveryServer {
     mdns: ServiceDaemon,
-    service_fullname: String,
+    device_name: String,
+    addrs: Vec<IpAddr>,
+    port: u16,
+    txt: ServiceTxt,
+    service_fullname: Option<String>,
 }
 
 impl DiscoveryServer {
-    pub fn new(port: u16, device_name: &str) -> Result<Self> {
-        let mdns = ServiceDaemon::new()?;
-        let host_name = format!("{}.local.", device_name.replace(' ', "-"));
-        let service_info = ServiceInfo::new(SERVICE_TYPE, device_name, &host_name, "", port, None)?;
+    /// Advertise `addrs` only, normally `net::advertised_addrs` of the listening sockets.
+    /// With no addresses (e.g. listening on loopback only) nothing is advertised.
+    pub fn new(port: u16, device_name: &str, addrs: &[IpAddr], txt: ServiceTxt) -> Result<Self> {
+        let mut server = Self {
+            mdns: ServiceDaemon::new()?,
+            device_name: device_name.to_string(),
+            addrs: addrs.to_vec(),
+            port,
+            txt,
+            service_fullname: None,
+        };
+        if addrs.is_empty() {
+            warn!("mDNS: no reachable address to advertise");
+            return Ok(server);
+        }
+
+        server.register()?;
+        info!(
+            "mDNS: advertising {} on port {} at {:?}",
+            device_name, port, addrs
+        );
+        Ok(server)
+    }
+
+    /// Register, or re-announce with the current TXT record.
+    fn register(&mut self) -> Result<()> {
+        let host_name = format!("{}.local.", self.device_name.replace(' ', "-"));
+        let service_info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &self.device_name,
+            &host_name,
+            &self.addrs[..],
+            self.port,
+            &self.txt.properties()[..],
+        )?;
         let fullname = service_info.get_fullname().to_string();
+        self.mdns.register(service_info)?;
+        self.service_fullname = Some(fullname);
+        Ok(())
+    }
+
+    /// Update `pair` in the TXT record.
+    pub fn set_pairing_open(&mut self, open: bool) -> Result<()> {
+        if self.txt.pairing_open == open {
+            return Ok(());
+        }
+        self.txt.pairing_open = open;
+        if self.service_fullname.is_some() {
+            self.register()?;
+            info!(
+                "mDNS: pairing window {}",
+                if open { "open" } else { "closed" }
+            );
+        }
+        Ok(())
+    }
+
+    /// Keep `pair` in sync with the pairing window until cancelled, then unregister.
+    pub async fn track_pairing(mut self, state: Arc<AppState>, cancel: CancellationToken) {
+        let mut events = state.subscribe();
+        loop {
+            let status = state.pairing.status();
+            if let Err(e) = self.set_pairing_open(status.open) {
+                warn!("failed to update mDNS TXT record: {}", e);
+            }
+            // A timed window closes without an event, so wake up when it expires
+            let expiry = status.remaining_secs.map(Duration::from_secs);
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                event = events.recv() => {
+                    if let Err(RecvError::Closed) = event {
+                        return;
+                    }
+                }
+                _ = time::sleep(expiry.unwrap_or_default()), if expiry.is_some() => {}
+            }
+        }
+    }
+}
+
+impl Drop for DiscoveryServer {
+    fn drop(&mut self) {
+        let Some(fullname) = &self.service_fullname else {
+            return;
+        };
+        // Wait briefly so the goodbye announcement goes out before the daemon stops
+        match self.mdns.unregister(fullname) {
+            Ok(status) => {
+                let _ = status.recv_timeout(Duration::from_millis(200));
+            }
+            Err(e) => warn!("failed to unregister mDNS service: {}", e),
+        }
+    }
+}
+
+/// A receiver found on the network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Peer {
+    /// Advertised device name.
+    pub name: String,
+    /// Full service name, unique on the network.
+    pub fullname: String,
+    pub host: String,
+    pub addrs: Vec<IpAddr>,
+    pub port: u16,
+    pub txt: Option<ServiceTxt>,
+    /// Name of the paired device whose key matches the advertised fingerprint.
+    pub paired: Option<String>,
+    /// This machine's own receiver.
+    pub local: bool,
+}
 
-        mdns.register(service_info)?;
-        info!("mDNS: advertising {} on port {}", device_name, port);
+/// Receivers appearing on and disappearing from the network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum DiscoveryEvent {
+    /// A receiver was resolved, or its record (addresses, TXT) changed.
+    PeerFound(Peer),
+    PeerLost {
+        name: String,
+        fullname: String,
+    },
+}
+
+/// Browse for Universal Clipboard receivers via mDNS.
+pub struct DiscoveryClient {
+    mdns: ServiceDaemon,
+    events: mdns_sd::Receiver<ServiceEvent>,
+    /// (fingerprint, device name) of every paired device.
+    paired: Vec<(String, String)>,
+    own_fingerprint: Option<String>,
+}
 
+impl DiscoveryClient {
+    /// Start browsing. Paired devices and our own identity in `store` are used to mark
+    /// the receivers that are already known.
+    pub fn browse(store: &DeviceStore) -> Result<Self> {
+        let paired = store
+            .list_paired_devices()?
+            .into_iter()
+            .filter_map(|(name, key)| Some((key_fingerprint(&hex::decode(key).ok()?), name)))
+            .collect();
+        let own_fingerprint = store.load_identity()?.map(|id| id.fingerprint());
+        let mdns = ServiceDaemon::new()?;
+        let events = mdns.browse(SERVICE_TYPE)?;
         Ok(Self {
             mdns,
-            service_fullname: fullname,
+            events,
+            paired,
+            own_fingerprint,
         })
     }
+
+    /// Wait for the next change; `None` once browsing has stopped.
+    pub async fn next_event(&mut self) -> Option<DiscoveryEvent> {
+        loop {
+            match self.events.recv_async().await.ok()? {
+                ServiceEvent::ServiceResolved(info) => {
+                    return Some(DiscoveryEvent::PeerFound(self.peer(&info)));
+                }
+                ServiceEvent::ServiceRemoved(_, fullname) => {
+                    return Some(DiscoveryEvent::PeerLost {
+                        name: instance_name(&fullname),
+                        fullname,
+                    });
+                }
+                other => debug!("mDNS: {:?}", other),
+            }
+        }
+    }
+
+    fn peer(&self, info: &ServiceInfo) -> Peer {
+        let txt = ServiceTxt::from_service(info);
+        let fingerprint = txt.as_ref().map(|txt| txt.fingerprint.as_str());
+        let mut addrs: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+        addrs.sort();
+        Peer {
+            name: instance_name(info.get_fullname()),
+            fullname: info.get_fullname().to_string(),
+            host: info.get_hostname().to_string(),
+            addrs,
+            port: info.get_port(),
+            paired: self
+                .paired
+                .iter()
+                .find(|(fp, _)| Some(fp.as_str()) == fingerprint)
+                .map(|(_, name)| name.clone()),
+            local: fingerprint.is_some() && fingerprint == self.own_fingerprint.as_deref(),
+            txt,
+        }
+    }
 }
 
-impl Drop for DiscoveryServer {
+impl Drop for DiscoveryClient {
     fn drop(&mut self) {
-        if let Err(e) = self.mdns.unregister(&self.service_fullname) {
-            tracing::warn!("failed to unregister mDNS service: {}", e);
+        // Wait briefly so the daemon thread does not log about our closed channels
+        if let Ok(status) = self.mdns.shutdown() {
+            let _ = status.recv_timeout(Duration::from_millis(200));
         }
     }
 }
+
+/// `Name._uclip._tcp.local.` -> `Name`.
+fn instance_name(fullname: &str) -> String {
+    fullname
+        .strip_suffix(SERVICE_TYPE)
+        .map(|name| name.trim_end_matches('.'))
+        .unwrap_or(fullname)
+        .to_string()
+}
+
+/// Browse for `wait` and return the receivers still present at the end, by name.
+pub async fn discover(store: &DeviceStore, wait: Duration) -> Result<Vec<Peer>> {
+    let mut client = DiscoveryClient::browse(store)?;
+    let mut peers: Vec<Peer> = Vec::new();
+    let deadline = time::sleep(wait);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            event = client.next_event() => match event {
+                Some(DiscoveryEvent::PeerFound(peer)) => {
+                    peers.retain(|p| p.fullname != peer.fullname);
+                    peers.push(peer);
+                }
+                Some(DiscoveryEvent::PeerLost { fullname, .. }) => {
+                    peers.retain(|p| p.fullname != fullname);
+                }
+                None => break,
+            },
+        }
+    }
+    peers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instance_name() {
+        assert_eq!(instance_name("Studio Mac._uclip._tcp.local."), "Studio Mac");
+        assert_eq!(instance_name("odd"), "odd");
+    }
+
+    #[test]
+    fn test_txt_from_service() {
+        let txt = ServiceTxt {
+            version: 1,
+            fingerprint: "0011223344556677".to_string(),
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            pairing_open: true,
+        };
+        let addrs: Vec<IpAddr> = vec!["192.168.1.5".parse().unwrap()];
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            "Studio",
+            "Studio.local.",
+            &addrs[..],
+            9876,
+            &txt.properties()[..],
+        )
+        .unwrap();
+        assert_eq!(ServiceTxt::from_service(&info), Some(txt));
+
+        let bare =
+            ServiceInfo::new(SERVICE_TYPE, "Old", "Old.local.", &addrs[..], 9876, None).unwrap();
+        assert_eq!(ServiceTxt::from_service(&bare), None);
+    }
+
+    #[test]
+    fn test_txt_properties() {
+        let txt = ServiceTxt {
+            version: 1,
+            fingerprint: "e3b0c44298fc1c14".to_string(),
+            capabilities: vec!["text".to_string(), "image".to_string()],
+            pairing_open: false,
+        };
+        assert_eq!(
+            txt.properties(),
+            vec![
+                ("v", "1".to_string()),
+                ("fp", "e3b0c44298fc1c14".to_string()),
+                ("caps", "text,image".to_string()),
+                ("pair", "0".to_string()),
+            ]
+        );
+    }
+}
@@ -1,27 +1,53 @@
 use serde::Serialize;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::sync::{broadcast, mpsc, RwLock};
 
+use crate::clipboard::{ClipboardBackend, ClipboardWorker};
+use crate::config::{ConfigLoader, Settings};
+use crate::confirm::Confirmations;
 use crate::crypto::Identity;
-use crate::protocol::Message;
+use crate::delivery::Deliveries;
+use crate::history::{History, HISTORY_SIZE};
+use crate::net::BindTarget;
+use crate::pairing::PairingWindow;
+use crate::protocol::{DeviceInfo, Message};
+use crate::queue::OutboundQueue;
+use crate::stats::{SessionCounters, SessionStats};
 use crate::storage::DeviceStore;
 
 /// Events emitted by the server for UI consumption.
 #[derive(Debug, Clone, Serialize)]
 #[serde(tag = "type", content = "data")]
 pub enum ServerEvent {
+    /// `port` is the port actually listened on, which may differ from the configured one.
     ServerStarted {
         port: u16,
         pairing_code: String,
     },
+    /// The server could not listen on the configured addresses and is not running.
+    BindFailed {
+        port: u16,
+        reason: String,
+    },
     DeviceConnected {
         name: String,
     },
     DeviceDisconnected {
         name: String,
+        /// BYE reason when either side closed the session on purpose (e.g. `shutdown`);
+        /// `None` if the connection was lost.
+        reason: Option<String>,
+    },
+    /// `name` sent nothing within `timeout_secs` of a PING; its session is closed as lost.
+    PeerUnresponsive {
+        name: String,
+        timeout_secs: u64,
     },
     ClipboardReceived {
         chars: usize,
+        preview: String,
+        sensitive: bool,
     },
     ClipboardSent {
         chars: usize,
@@ -48,18 +74,114 @@ pub enum ServerEvent {
     ImageTransferFailed {
         reason: String,
     },
+    /// Sensitive content was wiped from the clipboard after its timeout.
+    SensitiveCleared,
+    /// A transfer to or from `device` was refused by its policy.
+    TransferRejected {
+        device: String,
+        incoming: bool,
+        code: String,
+        reason: String,
+    },
+    /// Incoming content is parked until `AppState::confirmations` resolves `id`.
+    ConfirmationRequested {
+        id: u64,
+        device: String,
+        kind: String,
+        preview: String,
+        bytes: usize,
+        timeout_secs: u64,
+    },
+    ConfirmationResolved {
+        id: u64,
+        accepted: bool,
+    },
+    /// New devices may (or may no longer) pair with the pairing code.
+    PairingWindowChanged {
+        open: bool,
+        remaining_secs: Option<u64>,
+    },
+    /// Items were queued for `device` or left its queue; `pending` are still waiting.
+    QueueChanged {
+        device: String,
+        pending: usize,
+    },
+    /// Counters of the current session, emitted every `stats::STATS_INTERVAL`.
+    SessionStats(SessionStats),
+    /// The config file was re-read; `restart_required` lists changes not yet applied.
+    ConfigReloaded {
+        restart_required: Vec<String>,
+    },
+}
+
+impl ServerEvent {
+    /// Every value an event's `type` can take, for checking subscription filters.
+    /// `test_types_list_every_variant` keeps it in step with the variants.
+    pub const TYPES: &'static [&'static str] = &[
+        "ServerStarted",
+        "BindFailed",
+        "DeviceConnected",
+        "DeviceDisconnected",
+        "PeerUnresponsive",
+        "ClipboardReceived",
+        "ClipboardSent",
+        "DevicePaired",
+        "HandshakeFailed",
+        "ImageTransferProgress",
+        "ImageReceived",
+        "ImageSent",
+        "ImageTransferFailed",
+        "SensitiveCleared",
+        "TransferRejected",
+        "ConfirmationRequested",
+        "ConfirmationResolved",
+        "PairingWindowChanged",
+        "QueueChanged",
+        "SessionStats",
+        "ConfigReloaded",
+    ];
 }
 
 /// Shared application state accessible from server, CLI, and Tauri.
 pub struct AppState {
     pub identity: Identity,
     pub pairing_code: String,
+    /// Whether the pairing code is currently accepted.
+    pub pairing: PairingWindow,
     pub device_name: String,
     pub store: DeviceStore,
+    /// Configured port; the server falls back to another one if it is taken, see
+    /// `listen_port`.
     pub port: u16,
+    /// Addresses the server was started on (the default is every interface).
+    pub bind: Vec<BindTarget>,
+    /// Clipboard that received content is written to (the system clipboard by default).
+    pub clipboard: Arc<dyn ClipboardBackend>,
     pub connected_device: Arc<RwLock<Option<String>>>,
-    pub session_tx: Arc<RwLock<Option<mpsc::UnboundedSender<Message>>>>,
+    pub session_tx: Arc<RwLock<Option<mpsc::Sender<Message>>>>,
+    /// Held while one item's messages go into `session_tx`, so items sent at the same time
+    /// (queue, control socket, app) never interleave their messages.
+    pub send_lock: tokio::sync::Mutex<()>,
+    /// DEVICE_INFO of the connected peer, if any.
+    pub peer_info: Arc<RwLock<Option<DeviceInfo>>>,
     pub event_tx: broadcast::Sender<ServerEvent>,
+    pub history: Arc<RwLock<History>>,
+    pub confirmations: Confirmations,
+    /// Sent items waiting for the connected device's ACK.
+    pub deliveries: Deliveries,
+    /// Content waiting for devices that are not connected.
+    pub queue: OutboundQueue,
+    /// Traffic and transfer counters of the connected device's session.
+    pub stats: SessionCounters,
+    /// Where the settings came from, for reloading; `None` if not started from a config.
+    pub config: Option<ConfigLoader>,
+    settings: Mutex<Settings>,
+    /// Port the server is listening on, 0 while it is not running.
+    listen_port: AtomicU16,
+    /// Why the last attempt to listen failed.
+    bind_error: Mutex<Option<String>>,
+    /// Why the config file could not be used, while it stays broken.
+    config_error: Mutex<Option<String>>,
 }
 
 impl AppState {
@@ -71,19 +193,76 @@ impl AppState {
         port: u16,
     ) -> Self {
         let (event_tx, _) = broadcast::channel(64);
+        let queue = OutboundQueue::new(store.base_dir().join("queue"));
         Self {
             identity,
             pairing_code,
+            pairing: PairingWindow::default(),
             device_name,
             store,
             port,
+            bind: BindTarget::dual_stack(),
+            clipboard: Arc::new(ClipboardWorker::spawn()),
             connected_device: Arc::new(RwLock::new(None)),
             session_tx: Arc::new(RwLock::new(None)),
+            send_lock: tokio::sync::Mutex::new(()),
+            peer_info: Arc::new(RwLock::new(None)),
             event_tx,
+            history: Arc::new(RwLock::new(History::new(HISTORY_SIZE))),
+            confirmations: Confirmations::default(),
+            deliveries: Deliveries::default(),
+            queue,
+            stats: SessionCounters::default(),
+            config: None,
+            settings: Mutex::new(Settings::default()),
+            listen_port: AtomicU16::new(0),
+            bind_error: Mutex::new(None),
+            config_error: Mutex::new(None),
+        }
+    }
+
+    /// Snapshot of the current settings.
+    pub fn settings(&self) -> Settings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    pub fn set_settings(&self, settings: Settings) {
+        *self.settings.lock().unwrap() = settings;
+    }
+
+    /// Port the server is listening on, if it is running.
+    pub fn listen_port(&self) -> Option<u16> {
+        match self.listen_port.load(Ordering::Relaxed) {
+            0 => None,
+            port => Some(port),
         }
     }
 
+    pub fn set_listen_port(&self, port: Option<u16>) {
+        self.listen_port.store(port.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Why the server could not listen, if its last attempt failed.
+    pub fn bind_error(&self) -> Option<String> {
+        self.bind_error.lock().unwrap().clone()
+    }
+
+    pub fn set_bind_error(&self, error: Option<String>) {
+        *self.bind_error.lock().unwrap() = error;
+    }
+
+    /// Why the config file was rejected, if the last attempt to load it failed.
+    pub fn config_error(&self) -> Option<String> {
+        self.config_error.lock().unwrap().clone()
+    }
+
+    pub fn set_config_error(&self, error: Option<String>) {
+        *self.config_error.lock().unwrap() = error;
+    }
+
     pub fn emit(&self, event: ServerEvent) {
+        // Transfer outcomes are counted from the events that report them
+        self.stats.observe(&event);
         // Ignore send errors (no active receivers)
         let _ = self.event_tx.send(event);
     }
@@ -98,6 +277,123 @@ mod tests {
     use super::*;
     use crate::protocol::Message;
 
+    /// One event of every variant. A new variant stops this compiling until it is added
+    /// to the match; give it a sample alongside, and `test_types_list_every_variant` fails
+    /// until it is in `ServerEvent::TYPES` too.
+    fn one_of_each() -> Vec<ServerEvent> {
+        let text = String::new;
+        let events = vec![
+            ServerEvent::ServerStarted {
+                port: 1,
+                pairing_code: text(),
+            },
+            ServerEvent::BindFailed {
+                port: 1,
+                reason: text(),
+            },
+            ServerEvent::DeviceConnected { name: text() },
+            ServerEvent::DeviceDisconnected {
+                name: text(),
+                reason: None,
+            },
+            ServerEvent::PeerUnresponsive {
+                name: text(),
+                timeout_secs: 1,
+            },
+            ServerEvent::ClipboardReceived {
+                chars: 1,
+                preview: text(),
+                sensitive: false,
+            },
+            ServerEvent::ClipboardSent { chars: 1 },
+            ServerEvent::DevicePaired { name: text() },
+            ServerEvent::HandshakeFailed {
+                addr: text(),
+                reason: text(),
+            },
+            ServerEvent::ImageTransferProgress {
+                bytes_transferred: 0,
+                bytes_total: 1,
+            },
+            ServerEvent::ImageReceived {
+                width: 1,
+                height: 1,
+                bytes: 1,
+            },
+            ServerEvent::ImageSent { bytes: 1 },
+            ServerEvent::ImageTransferFailed { reason: text() },
+            ServerEvent::SensitiveCleared,
+            ServerEvent::TransferRejected {
+                device: text(),
+                incoming: true,
+                code: text(),
+                reason: text(),
+            },
+            ServerEvent::ConfirmationRequested {
+                id: 1,
+                device: text(),
+                kind: text(),
+                preview: text(),
+                bytes: 1,
+                timeout_secs: 1,
+            },
+            ServerEvent::ConfirmationResolved {
+                id: 1,
+                accepted: true,
+            },
+            ServerEvent::PairingWindowChanged {
+                open: true,
+                remaining_secs: None,
+            },
+            ServerEvent::QueueChanged {
+                device: text(),
+                pending: 0,
+            },
+            ServerEvent::SessionStats(SessionStats::default()),
+            ServerEvent::ConfigReloaded {
+                restart_required: Vec::new(),
+            },
+        ];
+        for event in &events {
+            match event {
+                ServerEvent::ServerStarted { .. }
+                | ServerEvent::BindFailed { .. }
+                | ServerEvent::DeviceConnected { .. }
+                | ServerEvent::DeviceDisconnected { .. }
+                | ServerEvent::PeerUnresponsive { .. }
+                | ServerEvent::ClipboardReceived { .. }
+                | ServerEvent::ClipboardSent { .. }
+                | ServerEvent::DevicePaired { .. }
+                | ServerEvent::HandshakeFailed { .. }
+                | ServerEvent::ImageTransferProgress { .. }
+                | ServerEvent::ImageReceived { .. }
+                | ServerEvent::ImageSent { .. }
+                | ServerEvent::ImageTransferFailed { .. }
+                | ServerEvent::SensitiveCleared
+                | ServerEvent::TransferRejected { .. }
+                | ServerEvent::ConfirmationRequested { .. }
+                | ServerEvent::ConfirmationResolved { .. }
+                | ServerEvent::PairingWindowChanged { .. }
+                | ServerEvent::QueueChanged { .. }
+                | ServerEvent::SessionStats(_)
+                | ServerEvent::ConfigReloaded { .. } => {}
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn test_types_list_every_variant() {
+        let types: Vec<String> = one_of_each()
+            .iter()
+            .map(|event| {
+                let json = serde_json::to_value(event).unwrap();
+                json["type"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(types, ServerEvent::TYPES);
+    }
+
     #[test]
     fn test_clipboard_sent_event_serializes() {
         let event = ServerEvent::ClipboardSent { chars: 42 };
@@ -115,12 +411,75 @@ mod tests {
         assert_eq!(parsed["data"]["chars"], 100);
     }
 
+    #[test]
+    fn test_clipboard_received_serializes() {
+        let event = ServerEvent::ClipboardReceived {
+            chars: 7,
+            preview: "[sensitive, 7 chars]".to_string(),
+            sensitive: true,
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["type"], "ClipboardReceived");
+        assert_eq!(parsed["data"]["chars"], 7);
+        assert_eq!(parsed["data"]["preview"], "[sensitive, 7 chars]");
+        assert_eq!(parsed["data"]["sensitive"], true);
+    }
+
+    #[test]
+    fn test_session_stats_serializes() {
+        let event = ServerEvent::SessionStats(SessionStats {
+            device: "phone".to_string(),
+            bytes_in: 42,
+            rtt_ms: Some(12),
+            ..Default::default()
+        });
+        let parsed: serde_json::Value = serde_json::to_value(&event).unwrap();
+        assert_eq!(parsed["type"], "SessionStats");
+        assert_eq!(parsed["data"]["device"], "phone");
+        assert_eq!(parsed["data"]["bytes_in"], 42);
+        assert_eq!(parsed["data"]["rtt_ms"], 12);
+        assert!(parsed["data"]["avg_rtt_ms"].is_null());
+    }
+
+    #[test]
+    fn test_transfer_rejected_serializes() {
+        let event = ServerEvent::TransferRejected {
+            device: "phone".to_string(),
+            incoming: true,
+            code: "too_large".to_string(),
+            reason: "content too large (2 bytes, max 1)".to_string(),
+        };
+        let parsed = serde_json::to_value(&event).unwrap();
+        assert_eq!(parsed["type"], "TransferRejected");
+        assert_eq!(parsed["data"]["device"], "phone");
+        assert_eq!(parsed["data"]["incoming"], true);
+        assert_eq!(parsed["data"]["code"], "too_large");
+    }
+
+    #[test]
+    fn test_confirmation_requested_serializes() {
+        let event = ServerEvent::ConfirmationRequested {
+            id: 3,
+            device: "phone".to_string(),
+            kind: "text".to_string(),
+            preview: "hello".to_string(),
+            bytes: 5,
+            timeout_secs: 30,
+        };
+        let parsed = serde_json::to_value(&event).unwrap();
+        assert_eq!(parsed["type"], "ConfirmationRequested");
+        assert_eq!(parsed["data"]["id"], 3);
+        assert_eq!(parsed["data"]["kind"], "text");
+        assert_eq!(parsed["data"]["preview"], "hello");
+        assert_eq!(parsed["data"]["timeout_secs"], 30);
+    }
+
     #[tokio::test]
     async fn test_session_tx_is_none_by_default() {
         // We can't easily construct AppState without a real Identity/DeviceStore,
         // so test the pattern directly.
-        let session_tx: Arc<RwLock<Option<mpsc::UnboundedSender<Message>>>> =
-            Arc::new(RwLock::new(None));
+        let session_tx: Arc<RwLock<Option<mpsc::Sender<Message>>>> = Arc::new(RwLock::new(None));
         assert!(session_tx.read().await.is_none());
     }
 
@@ -174,15 +533,15 @@ mod tests {
 
     #[tokio::test]
     async fn test_session_tx_channel_works() {
-        let (tx, mut rx) = mpsc::unbounded_channel();
-        let session_tx: Arc<RwLock<Option<mpsc::UnboundedSender<Message>>>> =
+        let (tx, mut rx) = mpsc::channel(1);
+        let session_tx: Arc<RwLock<Option<mpsc::Sender<Message>>>> =
             Arc::new(RwLock::new(Some(tx)));
 
         // Send a message through the stored sender
         {
             let guard = session_tx.read().await;
             let sender = guard.as_ref().unwrap();
-            sender.send(Message::clipboard_send("test")).unwrap();
+            sender.send(Message::clipboard_send("test")).await.unwrap();
         }
 
         let msg = rx.recv().await.unwrap();
@@ -0,0 +1,111 @@
+//! Credit-based flow control for IMAGE_CHUNK data.
+//!
+//! A device that advertises a `window` in its DEVICE_INFO buffers at most that many bytes
+//! of chunk payload it has not processed yet. It hands credit back with WINDOW_UPDATE as it
+//! processes chunks, and the sender holds further chunks while the window is full. Peers
+//! that advertise no window get no flow control beyond TCP's.
+
+/// Our side of the peer's window: how much unconfirmed chunk data is in flight.
+#[derive(Debug, Clone)]
+pub struct SendWindow {
+    size: usize,
+    in_flight: usize,
+}
+
+impl SendWindow {
+    pub fn new(size: usize) -> Self {
+        Self { size, in_flight: 0 }
+    }
+
+    /// Whether a chunk of `bytes` may go out now. With nothing in flight a chunk is always
+    /// allowed, so a window smaller than one chunk slows the transfer instead of stalling it.
+    pub fn allows(&self, bytes: usize) -> bool {
+        self.in_flight == 0 || self.in_flight + bytes <= self.size
+    }
+
+    pub fn sent(&mut self, bytes: usize) {
+        self.in_flight += bytes;
+    }
+
+    /// The peer processed `bytes` more chunk data.
+    pub fn granted(&mut self, bytes: usize) {
+        self.in_flight = self.in_flight.saturating_sub(bytes);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+}
+
+/// The window we advertise: chunk data processed but not yet handed back as credit.
+#[derive(Debug, Clone)]
+pub struct ReceiveWindow {
+    size: usize,
+    consumed: usize,
+}
+
+impl ReceiveWindow {
+    pub fn new(size: usize) -> Self {
+        Self { size, consumed: 0 }
+    }
+
+    /// Note a processed chunk of `bytes`. Returns the credit to grant once a quarter of the
+    /// window has been processed, so updates are not sent for every chunk.
+    pub fn consumed(&mut self, bytes: usize) -> Option<usize> {
+        self.consumed += bytes;
+        if self.consumed >= (self.size / 4).max(1) {
+            self.flush()
+        } else {
+            None
+        }
+    }
+
+    /// Credit for everything processed so far, e.g. at the end of an image.
+    pub fn flush(&mut self) -> Option<usize> {
+        let credit = std::mem::take(&mut self.consumed);
+        (credit > 0).then_some(credit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_window_holds_chunks_until_granted() {
+        let mut window = SendWindow::new(100);
+        assert!(window.allows(60));
+        window.sent(60);
+        assert!(window.allows(40));
+        assert!(!window.allows(41));
+        window.sent(40);
+        assert!(!window.allows(1));
+
+        window.granted(50);
+        assert_eq!(window.in_flight(), 50);
+        assert!(window.allows(50));
+        window.granted(500);
+        assert_eq!(window.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_chunk_larger_than_window_still_goes_out() {
+        let mut window = SendWindow::new(10);
+        assert!(window.allows(64));
+        window.sent(64);
+        assert!(!window.allows(1));
+        window.granted(64);
+        assert!(window.allows(64));
+    }
+
+    #[test]
+    fn test_receive_window_grants_in_quarters() {
+        let mut window = ReceiveWindow::new(100);
+        assert_eq!(window.consumed(10), None);
+        assert_eq!(window.consumed(10), None);
+        assert_eq!(window.consumed(10), Some(30));
+        assert_eq!(window.consumed(5), None);
+        assert_eq!(window.flush(), Some(5));
+        assert_eq!(window.flush(), None);
+    }
+}
@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of received items remembered per process.
+pub const HISTORY_SIZE: usize = 20;
+
+/// Maximum characters shown in a text preview.
+pub const PREVIEW_MAX_CHARS: usize = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryKind {
+    Text,
+    Image,
+}
+
+/// A received clipboard item. Only a preview is kept, never the full content.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub kind: HistoryKind,
+    pub preview: String,
+    pub device: String,
+    pub bytes: usize,
+    pub timestamp: u64,
+}
+
+impl HistoryEntry {
+    pub fn text(device: &str, text: &str) -> Self {
+        Self {
+            kind: HistoryKind::Text,
+            preview: make_preview(text),
+            device: device.to_string(),
+            bytes: text.len(),
+            timestamp: now_millis(),
+        }
+    }
+
+    pub fn image(device: &str, width: u32, height: u32, bytes: usize) -> Self {
+        Self {
+            kind: HistoryKind::Image,
+            preview: format!("Image ({}x{}, {} KB)", width, height, bytes / 1024),
+            device: device.to_string(),
+            bytes,
+            timestamp: now_millis(),
+        }
+    }
+}
+
+/// Bounded list of recently received items, newest first.
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, entry: HistoryEntry) {
+        self.entries.push_front(entry);
+        self.entries.truncate(self.capacity);
+    }
+
+    /// Change how many items are kept, dropping the oldest if there are too many.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.entries.truncate(capacity);
+    }
+
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries.iter().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Collapse text to a single line and truncate it for display.
+pub fn make_preview(text: &str) -> String {
+    let single_line: String = text
+        .chars()
+        .map(|c| if c == '\n' || c == '\r' { ' ' } else { c })
+        .collect();
+    let trimmed = single_line.trim();
+    if trimmed.chars().count() > PREVIEW_MAX_CHARS {
+        trimmed.chars().take(PREVIEW_MAX_CHARS).collect::<String>() + "…"
+    } else {
+        trimmed.to_string()
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_newest_first_and_bounded() {
+        let mut history = History::new(2);
+        assert!(history.is_empty());
+        history.push(HistoryEntry::text("phone", "one"));
+        history.push(HistoryEntry::text("phone", "two"));
+        history.push(HistoryEntry::text("phone", "three"));
+
+        let entries = history.entries();
+        assert_eq!(history.len(), 2);
+        assert_eq!(entries[0].preview, "three");
+        assert_eq!(entries[1].preview, "two");
+    }
+
+    #[test]
+    fn test_set_capacity_drops_oldest() {
+        let mut history = History::new(3);
+        for text in ["one", "two", "three"] {
+            history.push(HistoryEntry::text("phone", text));
+        }
+        history.set_capacity(1);
+        assert_eq!(history.entries()[0].preview, "three");
+        assert_eq!(history.len(), 1);
+        history.set_capacity(2);
+        history.push(HistoryEntry::text("phone", "four"));
+        assert_eq!(history.len(), 2);
+    }
+
+    #[test]
+    fn test_make_preview() {
+        assert_eq!(make_preview("  line one\nline two  "), "line one line two");
+        let long = "x".repeat(PREVIEW_MAX_CHARS + 10);
+        let preview = make_preview(&long);
+        assert_eq!(preview.chars().count(), PREVIEW_MAX_CHARS + 1);
+        assert!(preview.ends_with('…'));
+    }
+
+    #[test]
+    fn test_image_entry() {
+        let entry = HistoryEntry::image("phone", 1920, 1080, 2048);
+        assert_eq!(entry.kind, HistoryKind::Image);
+        assert_eq!(entry.preview, "Image (1920x1080, 2 KB)");
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["kind"], "image");
+    }
+}
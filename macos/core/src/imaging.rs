@@ -0,0 +1,296 @@
+use anyhow::{bail, Context, Result};
+use image::imageops::FilterType;
+use image::{ImageEncoder, RgbImage, RgbaImage};
+use tracing::debug;
+
+use crate::clipboard::encode_rgba_to_png;
+use crate::protocol::MAX_IMAGE_SIZE;
+
+/// Default longest edge for outgoing images (4K UHD).
+pub const DEFAULT_MAX_DIMENSION: u32 = 3840;
+
+/// Default JPEG quality for lossy re-encoding.
+pub const DEFAULT_JPEG_QUALITY: u8 = 85;
+
+/// Lowest JPEG quality tried before the image is downscaled further.
+const MIN_JPEG_QUALITY: u8 = 50;
+
+/// Each downscaling step keeps this fraction of the width and height.
+const SHRINK_FACTOR: f32 = 0.75;
+
+/// Images are never shrunk below this edge length to fit the budget.
+const MIN_DIMENSION: u32 = 64;
+
+/// How outgoing images are resized and encoded before they are sent.
+#[derive(Debug, Clone)]
+pub struct ImageSendPolicy {
+    /// Longest edge in pixels; larger images are downscaled (`None` = keep size).
+    pub max_dimension: Option<u32>,
+    /// Target size of the encoded image in bytes (capped at `MAX_IMAGE_SIZE`).
+    pub max_bytes: usize,
+    /// Allow JPEG re-encoding when lossless PNG does not fit the budget.
+    pub allow_lossy: bool,
+    /// Starting JPEG quality (1-100) when lossy re-encoding is allowed.
+    pub jpeg_quality: u8,
+}
+
+impl Default for ImageSendPolicy {
+    fn default() -> Self {
+        Self {
+            max_dimension: Some(DEFAULT_MAX_DIMENSION),
+            max_bytes: MAX_IMAGE_SIZE,
+            allow_lossy: false,
+            jpeg_quality: DEFAULT_JPEG_QUALITY,
+        }
+    }
+}
+
+/// An encoded image ready to send, with its original dimensions for display.
+#[derive(Debug, Clone)]
+pub struct PreparedImage {
+    pub bytes: Vec<u8>,
+    pub mime_type: String,
+    pub width: u32,
+    pub height: u32,
+    pub original_width: u32,
+    pub original_height: u32,
+}
+
+impl PreparedImage {
+    /// Whether the image was resized or re-encoded lossily.
+    pub fn was_reduced(&self) -> bool {
+        self.width != self.original_width
+            || self.height != self.original_height
+            || self.mime_type != "image/png"
+    }
+
+    /// Human-readable summary, e.g. `Image (5120x2880 → 3840x2160, 2048 KB JPEG)`.
+    pub fn preview(&self) -> String {
+        let format = self
+            .mime_type
+            .strip_prefix("image/")
+            .unwrap_or(&self.mime_type)
+            .to_uppercase();
+        if self.width == self.original_width && self.height == self.original_height {
+            format!(
+                "Image ({}x{}, {} KB {})",
+                self.width,
+                self.height,
+                self.bytes.len() / 1024,
+                format
+            )
+        } else {
+            format!(
+                "Image ({}x{} → {}x{}, {} KB {})",
+                self.original_width,
+                self.original_height,
+                self.width,
+                self.height,
+                self.bytes.len() / 1024,
+                format
+            )
+        }
+    }
+}
+
+/// Downscale and encode RGBA pixels so the result fits `policy`.
+///
+/// PNG is preferred; JPEG is tried at decreasing quality only when lossy encoding is
+/// allowed. If neither fits, the image is shrunk step by step until it does.
+pub fn prepare_image(
+    rgba: Vec<u8>,
+    width: u32,
+    height: u32,
+    policy: &ImageSendPolicy,
+) -> Result<PreparedImage> {
+    let mut img =
+        RgbaImage::from_raw(width, height, rgba).context("RGBA buffer does not match size")?;
+    if let Some(max) = policy.max_dimension {
+        if width.max(height) > max {
+            let (w, h) = fit_within(width, height, max);
+            debug!("downscaling {}x{} to {}x{}", width, height, w, h);
+            img = image::imageops::resize(&img, w, h, FilterType::Triangle);
+        }
+    }
+
+    let budget = policy.max_bytes.min(MAX_IMAGE_SIZE);
+    loop {
+        let (w, h) = img.dimensions();
+        let png = encode_rgba_to_png(img.as_raw(), w, h)?;
+        if png.len() <= budget {
+            return Ok(prepared(png, "image/png", w, h, width, height));
+        }
+
+        if policy.allow_lossy {
+            let rgb = flatten_alpha(&img);
+            let mut quality = policy.jpeg_quality.clamp(1, 100);
+            loop {
+                let jpeg = encode_rgb_to_jpeg(&rgb, quality)?;
+                if jpeg.len() <= budget {
+                    return Ok(prepared(jpeg, "image/jpeg", w, h, width, height));
+                }
+                if quality <= MIN_JPEG_QUALITY {
+                    break;
+                }
+                quality = quality.saturating_sub(15).max(MIN_JPEG_QUALITY);
+            }
+        }
+
+        if w.max(h) <= MIN_DIMENSION {
+            bail!("image cannot be reduced to fit {} bytes", budget);
+        }
+        let next_w = ((w as f32 * SHRINK_FACTOR) as u32).max(1);
+        let next_h = ((h as f32 * SHRINK_FACTOR) as u32).max(1);
+        debug!(
+            "{}x{} exceeds {} bytes, shrinking to {}x{}",
+            w, h, budget, next_w, next_h
+        );
+        img = image::imageops::resize(&img, next_w, next_h, FilterType::Triangle);
+    }
+}
+
+fn prepared(
+    bytes: Vec<u8>,
+    mime_type: &str,
+    width: u32,
+    height: u32,
+    original_width: u32,
+    original_height: u32,
+) -> PreparedImage {
+    PreparedImage {
+        bytes,
+        mime_type: mime_type.to_string(),
+        width,
+        height,
+        original_width,
+        original_height,
+    }
+}
+
+/// Scale (width, height) so the longest edge is at most `max`, keeping the aspect ratio.
+fn fit_within(width: u32, height: u32, max: u32) -> (u32, u32) {
+    let scale = max as f64 / width.max(height) as f64;
+    let w = ((width as f64 * scale).round() as u32).max(1);
+    let h = ((height as f64 * scale).round() as u32).max(1);
+    (w, h)
+}
+
+/// JPEG has no alpha channel: composite onto white so transparency doesn't turn black.
+fn flatten_alpha(img: &RgbaImage) -> RgbImage {
+    RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let [r, g, b, a] = img.get_pixel(x, y).0;
+        let blend = |c: u8| ((c as u16 * a as u16 + 255 * (255 - a as u16)) / 255) as u8;
+        image::Rgb([blend(r), blend(g), blend(b)])
+    })
+}
+
+fn encode_rgb_to_jpeg(img: &RgbImage, quality: u8) -> Result<Vec<u8>> {
+    use image::codecs::jpeg::JpegEncoder;
+    use std::io::Cursor;
+
+    let mut buf = Cursor::new(Vec::new());
+    JpegEncoder::new_with_quality(&mut buf, quality)
+        .write_image(
+            img.as_raw(),
+            img.width(),
+            img.height(),
+            image::ExtendedColorType::Rgb8,
+        )
+        .context("failed to encode JPEG")?;
+    Ok(buf.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pseudo-random pixels compress poorly, which makes budgets easy to exceed.
+    fn noisy_rgba(width: u32, height: u32) -> Vec<u8> {
+        let mut state = 0x1234_5678u32;
+        (0..width * height * 4)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state & 0xFF) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_small_image_passes_through_as_png() {
+        let prepared =
+            prepare_image(vec![0u8; 4 * 10 * 10], 10, 10, &ImageSendPolicy::default()).unwrap();
+        assert_eq!(prepared.mime_type, "image/png");
+        assert_eq!((prepared.width, prepared.height), (10, 10));
+        assert!(!prepared.was_reduced());
+        assert_eq!(prepared.preview(), "Image (10x10, 0 KB PNG)");
+    }
+
+    #[test]
+    fn test_downscales_to_max_dimension() {
+        let policy = ImageSendPolicy {
+            max_dimension: Some(100),
+            ..Default::default()
+        };
+        let prepared = prepare_image(vec![0u8; 4 * 400 * 200], 400, 200, &policy).unwrap();
+        assert_eq!((prepared.width, prepared.height), (100, 50));
+        assert_eq!(
+            (prepared.original_width, prepared.original_height),
+            (400, 200)
+        );
+        assert!(prepared.was_reduced());
+        assert!(prepared.preview().contains("400x200 → 100x50"));
+    }
+
+    #[test]
+    fn test_shrinks_png_to_fit_budget() {
+        let policy = ImageSendPolicy {
+            max_dimension: None,
+            max_bytes: 20_000,
+            ..Default::default()
+        };
+        let prepared = prepare_image(noisy_rgba(200, 200), 200, 200, &policy).unwrap();
+        assert_eq!(prepared.mime_type, "image/png");
+        assert!(prepared.bytes.len() <= 20_000);
+        assert!(prepared.width < 200);
+    }
+
+    #[test]
+    fn test_lossy_reencoding_when_allowed() {
+        let policy = ImageSendPolicy {
+            max_dimension: None,
+            max_bytes: 60_000,
+            allow_lossy: true,
+            ..Default::default()
+        };
+        let prepared = prepare_image(noisy_rgba(200, 200), 200, 200, &policy).unwrap();
+        assert_eq!(prepared.mime_type, "image/jpeg");
+        assert!(prepared.bytes.len() <= 60_000);
+        assert_eq!(&prepared.bytes[..2], &[0xFF, 0xD8]);
+    }
+
+    #[test]
+    fn test_impossible_budget_fails() {
+        let policy = ImageSendPolicy {
+            max_dimension: None,
+            max_bytes: 10,
+            ..Default::default()
+        };
+        assert!(prepare_image(noisy_rgba(100, 100), 100, 100, &policy).is_err());
+    }
+
+    #[test]
+    fn test_fit_within_keeps_aspect_ratio() {
+        assert_eq!(fit_within(5120, 2880, 3840), (3840, 2160));
+        assert_eq!(fit_within(1000, 4000, 2000), (500, 2000));
+    }
+
+    #[test]
+    fn test_flatten_alpha_uses_white_background() {
+        let img = RgbaImage::from_raw(2, 1, vec![0, 0, 0, 0, 10, 20, 30, 255]).unwrap();
+        let rgb = flatten_alpha(&img);
+        assert_eq!(rgb.get_pixel(0, 0).0, [255, 255, 255]);
+        assert_eq!(rgb.get_pixel(1, 0).0, [10, 20, 30]);
+    }
+}
@@ -1,7 +1,21 @@
 pub mod clipboard;
+pub mod config;
+pub mod confirm;
+#[cfg(unix)]
+pub mod control;
 pub mod crypto;
+pub mod delivery;
 pub mod discovery;
 pub mod events;
+pub mod flow;
+pub mod history;
+pub mod imaging;
+pub mod net;
+pub mod pairing;
+pub mod policy;
 pub mod protocol;
+pub mod queue;
+pub mod sensitive;
 pub mod server;
+pub mod stats;
 pub mod storage;
@@ -0,0 +1,374 @@
+//! Listening sockets for the receiver: which addresses to bind and which to advertise,
+//! and the options set on accepted connections.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::str::FromStr;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::warn;
+
+/// Same backlog tokio uses for `TcpListener::bind`.
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Time between TCP keepalive probes once an idle connection is being checked.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+const KEEPALIVE_PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where to listen: an IP address (`0.0.0.0` and `::` mean every interface) or the name of a
+/// network interface, standing for all of its addresses.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum BindTarget {
+    Addr(IpAddr),
+    Interface(String),
+}
+
+impl BindTarget {
+    pub const ANY_V4: Self = Self::Addr(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    pub const ANY_V6: Self = Self::Addr(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+
+    /// Every interface, IPv4 and IPv6.
+    pub fn dual_stack() -> Vec<Self> {
+        vec![Self::ANY_V4, Self::ANY_V6]
+    }
+}
+
+impl FromStr for BindTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(ip) = s.parse() {
+            return Ok(Self::Addr(ip));
+        }
+        let valid_name = !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if valid_name && !s.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            Ok(Self::Interface(s.to_string()))
+        } else {
+            Err(format!(
+                "invalid bind address '{}' (expected an IP address or interface name)",
+                s
+            ))
+        }
+    }
+}
+
+impl fmt::Display for BindTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Addr(ip) => ip.fmt(f),
+            Self::Interface(name) => f.write_str(name),
+        }
+    }
+}
+
+impl TryFrom<String> for BindTarget {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<BindTarget> for String {
+    fn from(target: BindTarget) -> Self {
+        target.to_string()
+    }
+}
+
+/// Socket addresses for `targets` on `port`. Interface names are looked up now, so an
+/// interface that comes up later is not picked up until a restart.
+pub fn resolve(targets: &[BindTarget], port: u16) -> Result<Vec<SocketAddr>> {
+    let mut addrs = Vec::new();
+    for target in targets {
+        let resolved = match target {
+            BindTarget::Addr(ip) => vec![SocketAddr::new(*ip, port)],
+            BindTarget::Interface(name) => {
+                let found: Vec<SocketAddr> = interfaces()?
+                    .iter()
+                    .filter(|iface| iface.name == *name)
+                    .map(|iface| interface_addr(iface, port))
+                    .collect();
+                if found.is_empty() {
+                    bail!("no network interface named {} with an address", name);
+                }
+                found
+            }
+        };
+        for addr in resolved {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+    Ok(addrs)
+}
+
+/// Link-local IPv6 addresses need the interface as scope to be bindable.
+fn interface_addr(iface: &if_addrs::Interface, port: u16) -> SocketAddr {
+    match iface.ip() {
+        IpAddr::V6(ip) if iface.is_link_local() => {
+            SocketAddrV6::new(ip, port, 0, iface.index.unwrap_or(0)).into()
+        }
+        ip => SocketAddr::new(ip, port),
+    }
+}
+
+fn interfaces() -> Result<Vec<if_addrs::Interface>> {
+    if_addrs::get_if_addrs().context("failed to list network interfaces")
+}
+
+/// Listen on every address in `addrs`. Must be called from within a Tokio runtime.
+///
+/// IPv6 sockets are IPv6-only, so `0.0.0.0` and `::` can be bound side by side. `::` is
+/// skipped with a warning when the host has no IPv6 and another address was bound. With
+/// port 0 all listeners share the port picked for the first one.
+pub fn bind(addrs: &[SocketAddr]) -> Result<Vec<TcpListener>> {
+    let mut listeners: Vec<TcpListener> = Vec::new();
+    for addr in addrs {
+        let mut addr = *addr;
+        if addr.port() == 0 {
+            if let Some(first) = listeners.first() {
+                addr.set_port(first.local_addr()?.port());
+            }
+        }
+        match bind_one(addr) {
+            Ok(listener) => listeners.push(listener),
+            Err(e)
+                if addrs.len() > 1
+                    && addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+                    && e.kind() != io::ErrorKind::AddrInUse =>
+            {
+                warn!("not listening on {}: {}", addr, e);
+            }
+            Err(e) => return Err(e).with_context(|| format!("failed to listen on {}", addr)),
+        }
+    }
+    if listeners.is_empty() {
+        bail!("no address to listen on");
+    }
+    Ok(listeners)
+}
+
+/// Listen on `targets` at `port`, or on a port picked by the OS if `port` is taken on any of
+/// them.
+pub fn bind_with_fallback(targets: &[BindTarget], port: u16) -> Result<Vec<TcpListener>> {
+    match resolve(targets, port).and_then(|addrs| bind(&addrs)) {
+        Err(e) if port != 0 && is_addr_in_use(&e) => {
+            warn!("port {} is in use, falling back to a free port", port);
+            bind(&resolve(targets, 0)?)
+        }
+        result => result,
+    }
+}
+
+fn is_addr_in_use(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::AddrInUse)
+    })
+}
+
+fn bind_one(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Accept the next connection on whichever listener has one first.
+pub fn accept_any(
+    listeners: &[TcpListener],
+) -> impl Future<Output = io::Result<(TcpStream, SocketAddr)>> + '_ {
+    std::future::poll_fn(move |cx| {
+        for listener in listeners {
+            if let Poll::Ready(result) = listener.poll_accept(cx) {
+                return Poll::Ready(result);
+            }
+        }
+        Poll::Pending
+    })
+}
+
+/// Have the OS probe a connection that has been idle for `idle`, so a peer that vanished
+/// is noticed even while no PING is outstanding.
+pub fn set_tcp_keepalive(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+    let keepalive = TcpKeepalive::new().with_time(idle);
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    let keepalive = keepalive.with_interval(KEEPALIVE_PROBE_INTERVAL);
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+/// Addresses to advertise over mDNS for the given listening addresses: wildcards stand for
+/// every non-loopback address of their family, loopback is never advertised.
+pub fn advertised_addrs(bound: &[SocketAddr]) -> Vec<IpAddr> {
+    let local = if bound.iter().any(|addr| addr.ip().is_unspecified()) {
+        match interfaces() {
+            Ok(interfaces) => interfaces
+                .iter()
+                .filter(|iface| !iface.is_loopback())
+                .map(|iface| iface.ip())
+                .collect(),
+            Err(e) => {
+                warn!("{:#}", e);
+                Vec::new()
+            }
+        }
+    } else {
+        Vec::new()
+    };
+    expand_addrs(bound, &local)
+}
+
+fn expand_addrs(bound: &[SocketAddr], local: &[IpAddr]) -> Vec<IpAddr> {
+    let mut addrs = Vec::new();
+    for ip in bound.iter().map(SocketAddr::ip) {
+        let expanded: Vec<IpAddr> = if ip.is_unspecified() {
+            local
+                .iter()
+                .filter(|local| local.is_ipv4() == ip.is_ipv4())
+                .copied()
+                .collect()
+        } else if ip.is_loopback() {
+            Vec::new()
+        } else {
+            vec![ip]
+        };
+        for ip in expanded {
+            if !addrs.contains(&ip) {
+                addrs.push(ip);
+            }
+        }
+    }
+    addrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bind_target() {
+        assert_eq!("0.0.0.0".parse(), Ok(BindTarget::ANY_V4));
+        assert_eq!(" :: ".parse(), Ok(BindTarget::ANY_V6));
+        assert_eq!(
+            "fd00::2".parse(),
+            Ok(BindTarget::Addr("fd00::2".parse().unwrap()))
+        );
+        assert_eq!("en0".parse(), Ok(BindTarget::Interface("en0".to_string())));
+        assert!("".parse::<BindTarget>().is_err());
+        assert!("10.0.0.300".parse::<BindTarget>().is_err());
+        assert!("192.168.1.5:9876".parse::<BindTarget>().is_err());
+        assert_eq!(BindTarget::ANY_V6.to_string(), "::");
+    }
+
+    #[test]
+    fn test_resolve_addresses() {
+        let targets = [
+            BindTarget::ANY_V4,
+            BindTarget::Addr("127.0.0.1".parse().unwrap()),
+            BindTarget::ANY_V4,
+        ];
+        let addrs = resolve(&targets, 9876).unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                "0.0.0.0:9876".parse::<SocketAddr>().unwrap(),
+                "127.0.0.1:9876".parse().unwrap()
+            ]
+        );
+        let missing = [BindTarget::Interface("no-such-iface0".to_string())];
+        assert!(resolve(&missing, 9876).is_err());
+    }
+
+    #[test]
+    fn test_expand_advertised_addrs() {
+        let local: Vec<IpAddr> = vec![
+            "192.168.1.5".parse().unwrap(),
+            "172.17.0.1".parse().unwrap(),
+            "fd00::2".parse().unwrap(),
+        ];
+        let bound: Vec<SocketAddr> = vec!["0.0.0.0:1".parse().unwrap()];
+        assert_eq!(expand_addrs(&bound, &local), local[..2].to_vec());
+
+        let bound: Vec<SocketAddr> = vec![
+            "192.168.1.5:1".parse().unwrap(),
+            "[::1]:1".parse().unwrap(),
+            "[::]:1".parse().unwrap(),
+        ];
+        assert_eq!(expand_addrs(&bound, &local), vec![local[0], local[2]]);
+    }
+
+    #[tokio::test]
+    async fn test_bind_shares_picked_port() {
+        let addrs: Vec<SocketAddr> =
+            vec!["127.0.0.1:0".parse().unwrap(), "[::]:0".parse().unwrap()];
+        let listeners = bind(&addrs).unwrap();
+        let first = listeners[0].local_addr().unwrap();
+        assert_eq!(first.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        for listener in &listeners {
+            assert_eq!(listener.local_addr().unwrap().port(), first.port());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_falls_back_when_port_taken() {
+        let taken = bind(&["127.0.0.1:0".parse().unwrap()]).unwrap();
+        let port = taken[0].local_addr().unwrap().port();
+        let loopback = [BindTarget::Addr(IpAddr::V4(Ipv4Addr::LOCALHOST))];
+
+        let err = bind(&resolve(&loopback, port).unwrap()).unwrap_err();
+        assert!(is_addr_in_use(&err));
+        let listeners = bind_with_fallback(&loopback, port).unwrap();
+        assert_ne!(listeners[0].local_addr().unwrap().port(), port);
+
+        // Other errors are not papered over
+        let missing = [BindTarget::Interface("no-such-iface0".to_string())];
+        assert!(bind_with_fallback(&missing, port).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_accept_any_listener() {
+        let addrs: Vec<SocketAddr> = vec![
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.2:0".parse().unwrap(),
+        ];
+        let Ok(listeners) = bind(&addrs) else {
+            // 127.0.0.2 is not configured on every host (macOS)
+            return;
+        };
+        let second = listeners[1].local_addr().unwrap();
+        let client = TcpStream::connect(second);
+        let (accepted, client) = tokio::join!(accept_any(&listeners), client);
+        let (stream, peer) = accepted.unwrap();
+        assert_eq!(stream.local_addr().unwrap(), second);
+        assert_eq!(peer, client.unwrap().local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_set_tcp_keepalive() {
+        let listener = bind(&["127.0.0.1:0".parse().unwrap()]).unwrap();
+        let addr = listener[0].local_addr().unwrap();
+        let (accepted, _client) = tokio::join!(listener[0].accept(), TcpStream::connect(addr));
+        let (stream, _) = accepted.unwrap();
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+        set_tcp_keepalive(&stream, Duration::from_secs(30)).unwrap();
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+    }
+}
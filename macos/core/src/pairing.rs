@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Whether new devices may pair using the pairing code. Already paired devices can always
+/// reconnect; only the pairing handshake is gated.
+pub struct PairingWindow {
+    state: Mutex<WindowState>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum WindowState {
+    Open,
+    Closed,
+    Until(Instant),
+}
+
+/// Snapshot of the pairing window for status output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairingStatus {
+    pub open: bool,
+    /// Seconds until the window closes on its own, if it was opened with a timeout.
+    pub remaining_secs: Option<u64>,
+}
+
+impl Default for PairingWindow {
+    /// Open, so a fresh daemon accepts pairing requests as it always has.
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(WindowState::Open),
+        }
+    }
+}
+
+impl PairingWindow {
+    pub fn is_open(&self) -> bool {
+        self.status().open
+    }
+
+    /// Open the window, indefinitely or until `duration` has passed.
+    pub fn open(&self, duration: Option<Duration>) {
+        *self.state.lock().unwrap() = match duration {
+            Some(d) => WindowState::Until(Instant::now() + d),
+            None => WindowState::Open,
+        };
+    }
+
+    pub fn close(&self) {
+        *self.state.lock().unwrap() = WindowState::Closed;
+    }
+
+    pub fn status(&self) -> PairingStatus {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            WindowState::Open => PairingStatus {
+                open: true,
+                remaining_secs: None,
+            },
+            WindowState::Closed => PairingStatus {
+                open: false,
+                remaining_secs: None,
+            },
+            WindowState::Until(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    *state = WindowState::Closed;
+                    PairingStatus {
+                        open: false,
+                        remaining_secs: None,
+                    }
+                } else {
+                    PairingStatus {
+                        open: true,
+                        remaining_secs: Some(remaining.as_secs_f64().ceil() as u64),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_by_default() {
+        let window = PairingWindow::default();
+        assert!(window.is_open());
+        assert_eq!(window.status().remaining_secs, None);
+    }
+
+    #[test]
+    fn test_close_and_reopen() {
+        let window = PairingWindow::default();
+        window.close();
+        assert!(!window.is_open());
+        window.open(None);
+        assert!(window.is_open());
+    }
+
+    #[test]
+    fn test_timed_window_reports_remaining() {
+        let window = PairingWindow::default();
+        window.open(Some(Duration::from_secs(120)));
+        let status = window.status();
+        assert!(status.open);
+        assert_eq!(status.remaining_secs, Some(120));
+    }
+
+    #[test]
+    fn test_timed_window_expires() {
+        let window = PairingWindow::default();
+        window.open(Some(Duration::from_millis(10)));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!window.is_open());
+        assert_eq!(
+            window.status(),
+            PairingStatus {
+                open: false,
+                remaining_secs: None
+            }
+        );
+    }
+}
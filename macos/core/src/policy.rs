@@ -0,0 +1,237 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Which way content may flow between us and a paired device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    /// Content may be sent and received.
+    #[default]
+    Both,
+    /// We only accept content from the device and never send to it.
+    ReceiveOnly,
+    /// We only send content to the device; anything it pushes is rejected.
+    SendOnly,
+}
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "both" => Ok(Self::Both),
+            "receive-only" => Ok(Self::ReceiveOnly),
+            "send-only" => Ok(Self::SendOnly),
+            _ => Err(format!(
+                "invalid direction '{}' (expected both, receive-only or send-only)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Both => "both",
+            Self::ReceiveOnly => "receive-only",
+            Self::SendOnly => "send-only",
+        })
+    }
+}
+
+/// Kind of content carried by a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentKind {
+    Text,
+    Image,
+}
+
+impl fmt::Display for ContentKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Text => "text",
+            Self::Image => "image",
+        })
+    }
+}
+
+/// Transfer rules for a single paired device, persisted by `DeviceStore`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DevicePolicy {
+    pub allow_text: bool,
+    pub allow_images: bool,
+    /// Largest incoming item in bytes (`None` = protocol limit only).
+    pub max_bytes: Option<usize>,
+    /// Ask the user before incoming content is written to the clipboard.
+    pub require_confirmation: bool,
+    pub direction: Direction,
+}
+
+impl Default for DevicePolicy {
+    fn default() -> Self {
+        Self {
+            allow_text: true,
+            allow_images: true,
+            max_bytes: None,
+            require_confirmation: false,
+            direction: Direction::Both,
+        }
+    }
+}
+
+/// Why a transfer was refused by policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    Direction,
+    ContentType(ContentKind),
+    TooLarge {
+        bytes: usize,
+        max: usize,
+    },
+    /// The user rejected the item when asked to confirm it.
+    Declined,
+    /// Nobody answered the confirmation request in time.
+    ConfirmationTimedOut,
+}
+
+impl Rejection {
+    /// Stable machine-readable code sent to the peer.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Direction => "direction_not_allowed",
+            Self::ContentType(_) => "content_type_not_allowed",
+            Self::TooLarge { .. } => "too_large",
+            Self::Declined => "declined",
+            Self::ConfirmationTimedOut => "confirmation_timeout",
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Direction => write!(f, "transfers in this direction are not allowed"),
+            Self::ContentType(kind) => write!(f, "{} content is not allowed", kind),
+            Self::TooLarge { bytes, max } => {
+                write!(f, "content too large ({} bytes, max {})", bytes, max)
+            }
+            Self::Declined => write!(f, "rejected by user"),
+            Self::ConfirmationTimedOut => write!(f, "no confirmation received in time"),
+        }
+    }
+}
+
+impl DevicePolicy {
+    /// Check whether the device may push `bytes` of `kind` to us.
+    pub fn check_incoming(&self, kind: ContentKind, bytes: usize) -> Result<(), Rejection> {
+        if self.direction == Direction::SendOnly {
+            return Err(Rejection::Direction);
+        }
+        let allowed = match kind {
+            ContentKind::Text => self.allow_text,
+            ContentKind::Image => self.allow_images,
+        };
+        if !allowed {
+            return Err(Rejection::ContentType(kind));
+        }
+        if let Some(max) = self.max_bytes {
+            if bytes > max {
+                return Err(Rejection::TooLarge { bytes, max });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check whether we may send content to the device.
+    pub fn check_outgoing(&self) -> Result<(), Rejection> {
+        if self.direction == Direction::ReceiveOnly {
+            return Err(Rejection::Direction);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_allows_everything() {
+        let policy = DevicePolicy::default();
+        assert!(policy.check_incoming(ContentKind::Text, 10).is_ok());
+        assert!(policy
+            .check_incoming(ContentKind::Image, 20 * 1024 * 1024)
+            .is_ok());
+        assert!(policy.check_outgoing().is_ok());
+    }
+
+    #[test]
+    fn test_content_type_and_size_limits() {
+        let policy = DevicePolicy {
+            allow_images: false,
+            max_bytes: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.check_incoming(ContentKind::Image, 10),
+            Err(Rejection::ContentType(ContentKind::Image))
+        );
+        assert!(policy.check_incoming(ContentKind::Text, 100).is_ok());
+        assert_eq!(
+            policy.check_incoming(ContentKind::Text, 101),
+            Err(Rejection::TooLarge {
+                bytes: 101,
+                max: 100
+            })
+        );
+    }
+
+    #[test]
+    fn test_direction() {
+        let send_only = DevicePolicy {
+            direction: Direction::SendOnly,
+            ..Default::default()
+        };
+        assert_eq!(
+            send_only.check_incoming(ContentKind::Text, 1),
+            Err(Rejection::Direction)
+        );
+        assert!(send_only.check_outgoing().is_ok());
+
+        let receive_only = DevicePolicy {
+            direction: Direction::ReceiveOnly,
+            ..Default::default()
+        };
+        assert!(receive_only.check_incoming(ContentKind::Text, 1).is_ok());
+        assert_eq!(receive_only.check_outgoing(), Err(Rejection::Direction));
+    }
+
+    #[test]
+    fn test_direction_parse_and_serde() {
+        assert_eq!("receive-only".parse(), Ok(Direction::ReceiveOnly));
+        assert!("sideways".parse::<Direction>().is_err());
+        assert_eq!(Direction::SendOnly.to_string(), "send-only");
+        let json = serde_json::to_string(&Direction::SendOnly).unwrap();
+        assert_eq!(json, r#""send-only""#);
+    }
+
+    #[test]
+    fn test_policy_deserializes_partial() {
+        let policy: DevicePolicy = serde_json::from_str(r#"{"allow_text":false}"#).unwrap();
+        assert!(!policy.allow_text);
+        assert!(policy.allow_images);
+        assert_eq!(policy.direction, Direction::Both);
+    }
+
+    #[test]
+    fn test_rejection_codes() {
+        assert_eq!(Rejection::Direction.code(), "direction_not_allowed");
+        assert_eq!(
+            Rejection::TooLarge { bytes: 2, max: 1 }.to_string(),
+            "content too large (2 bytes, max 1)"
+        );
+    }
+}
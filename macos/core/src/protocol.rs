@@ -2,6 +2,8 @@ use anyhow::{bail, Result};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
+use crate::policy::Rejection;
+
 /// Message types for the clipboard sync protocol.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -16,6 +18,9 @@ pub enum MessageType {
     ImageChunk = 0x08,
     ImageSendEnd = 0x09,
     ImageAck = 0x0A,
+    ClipboardMeta = 0x0B,
+    Bye = 0x0C,
+    WindowUpdate = 0x0D,
 }
 
 impl TryFrom<u8> for MessageType {
@@ -33,6 +38,9 @@ impl TryFrom<u8> for MessageType {
             0x08 => Ok(Self::ImageChunk),
             0x09 => Ok(Self::ImageSendEnd),
             0x0A => Ok(Self::ImageAck),
+            0x0B => Ok(Self::ClipboardMeta),
+            0x0C => Ok(Self::Bye),
+            0x0D => Ok(Self::WindowUpdate),
             _ => bail!("unknown message type: 0x{:02x}", value),
         }
     }
@@ -54,8 +62,9 @@ impl Message {
         Self::new(MessageType::ClipboardSend, text.as_bytes().to_vec())
     }
 
-    pub fn clipboard_ack() -> Self {
-        Self::new(MessageType::ClipboardAck, vec![])
+    /// CLIPBOARD_ACK echoing the ID of the acknowledged item, if it had one.
+    pub fn clipboard_ack(id: Option<u64>) -> Self {
+        Self::ack(MessageType::ClipboardAck, id)
     }
 
     pub fn ping() -> Self {
@@ -66,13 +75,37 @@ impl Message {
         Self::new(MessageType::Pong, vec![])
     }
 
-    pub fn device_info(name: &str) -> Self {
-        let json = serde_json::json!({ "name": name });
-        Self::new(MessageType::DeviceInfo, json.to_string().into_bytes())
+    /// DEVICE_INFO advertising our name, supported image types and receive window.
+    pub fn device_info(name: &str, window: Option<usize>) -> Self {
+        let info = DeviceInfo {
+            name: name.to_string(),
+            image_types: SUPPORTED_IMAGE_TYPES
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+            window,
+        };
+        let json = serde_json::to_string(&info).expect("device info serializes");
+        Self::new(MessageType::DeviceInfo, json.into_bytes())
+    }
+
+    pub fn error(err: &ProtocolError) -> Self {
+        Self::error_for(err, None)
+    }
+
+    /// ERROR about the item sent with message ID `id`, if it had one.
+    pub fn error_for(err: &ProtocolError, id: Option<u64>) -> Self {
+        Self::error_payload(&ErrorPayload::from(err).with_id(id))
+    }
+
+    /// Error with a machine-readable code: `{"code": "...", "message": "..."}`.
+    pub fn error_with_code(code: &str, msg: &str) -> Self {
+        Self::error_payload(&ErrorPayload::new(code, msg))
     }
 
-    pub fn error(msg: &str) -> Self {
-        Self::new(MessageType::Error, msg.as_bytes().to_vec())
+    pub fn error_payload(payload: &ErrorPayload) -> Self {
+        let json = serde_json::to_string(payload).expect("error payload serializes");
+        Self::new(MessageType::Error, json.into_bytes())
     }
 
     pub fn image_send_start(metadata_json: &str) -> Self {
@@ -90,8 +123,58 @@ impl Message {
         Self::new(MessageType::ImageSendEnd, vec![])
     }
 
-    pub fn image_ack() -> Self {
-        Self::new(MessageType::ImageAck, vec![])
+    /// IMAGE_ACK echoing the ID of the acknowledged image, if it had one.
+    pub fn image_ack(id: Option<u64>) -> Self {
+        Self::ack(MessageType::ImageAck, id)
+    }
+
+    fn ack(msg_type: MessageType, id: Option<u64>) -> Self {
+        match id {
+            Some(id) => Self::new(
+                msg_type,
+                serde_json::json!({ "id": id }).to_string().into_bytes(),
+            ),
+            None => Self::new(msg_type, vec![]),
+        }
+    }
+
+    /// ID echoed in an ACK; `None` for the empty ACKs of peers without message IDs.
+    pub fn ack_id(&self) -> Option<u64> {
+        #[derive(serde::Deserialize)]
+        struct Ack {
+            id: Option<u64>,
+        }
+        serde_json::from_slice::<Ack>(&self.payload).ok()?.id
+    }
+
+    pub fn clipboard_meta(meta: &ClipboardMeta) -> Self {
+        let json = serde_json::to_string(meta).expect("clipboard metadata serializes");
+        Self::new(MessageType::ClipboardMeta, json.into_bytes())
+    }
+
+    /// WINDOW_UPDATE granting the sender `bytes` more chunk data.
+    pub fn window_update(bytes: usize) -> Self {
+        let json = serde_json::json!({ "bytes": bytes }).to_string();
+        Self::new(MessageType::WindowUpdate, json.into_bytes())
+    }
+
+    /// Credit granted by a WINDOW_UPDATE.
+    pub fn window_credit(&self) -> Result<usize> {
+        #[derive(serde::Deserialize)]
+        struct WindowUpdate {
+            bytes: usize,
+        }
+        Ok(serde_json::from_slice::<WindowUpdate>(&self.payload)?.bytes)
+    }
+
+    pub fn bye(bye: &Bye) -> Self {
+        let json = serde_json::to_string(bye).expect("bye serializes");
+        Self::new(MessageType::Bye, json.into_bytes())
+    }
+
+    /// Size of the encoded message: header and payload.
+    pub fn wire_len(&self) -> usize {
+        5 + self.payload.len()
     }
 
     /// Encode message into wire format: [type(1) | length(4) | payload(N)]
@@ -128,6 +211,188 @@ impl Message {
     }
 }
 
+/// Protocol version advertised over mDNS; bumped on incompatible changes.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Image MIME types this implementation can decode and place on the clipboard.
+pub const SUPPORTED_IMAGE_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+/// MIME type assumed for peers that do not advertise image types.
+pub const DEFAULT_IMAGE_TYPE: &str = "image/png";
+
+/// DEVICE_INFO payload exchanged after the handshake.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+    pub name: String,
+    /// Image MIME types the device accepts (older peers omit this: PNG only).
+    #[serde(default)]
+    pub image_types: Vec<String>,
+    /// Bytes of IMAGE_CHUNK payload the device buffers before granting more with
+    /// WINDOW_UPDATE; no flow control if absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<usize>,
+}
+
+impl DeviceInfo {
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Whether the device accepts images encoded as `mime_type`.
+    pub fn accepts_image_type(&self, mime_type: &str) -> bool {
+        if self.image_types.is_empty() {
+            return mime_type == DEFAULT_IMAGE_TYPE;
+        }
+        self.image_types.iter().any(|t| t == mime_type)
+    }
+}
+
+/// Optional metadata describing the CLIPBOARD_SEND that follows it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ClipboardMeta {
+    /// The sender marked this clip as sensitive (password, OTP, ...).
+    pub sensitive: bool,
+    /// Message ID echoed in the CLIPBOARD_ACK or ERROR for this clip.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+}
+
+impl ClipboardMeta {
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+/// BYE reason: the sender is shutting down.
+pub const BYE_SHUTDOWN: &str = "shutdown";
+
+/// BYE payload, sent before closing a session on purpose. Receivers must accept reason
+/// codes they do not know.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Bye {
+    pub reason: String,
+    /// Human-readable detail.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub message: String,
+}
+
+impl Bye {
+    pub fn new(reason: &str, message: &str) -> Self {
+        Self {
+            reason: reason.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+/// Errors this side reports to the peer in an ERROR message.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ProtocolError {
+    #[error("invalid image metadata")]
+    InvalidImageMetadata,
+    #[error("image too large ({bytes} bytes, max {max})")]
+    ImageTooLarge { bytes: usize, max: usize },
+    /// Another item is still being received or awaits confirmation.
+    #[error("transfer already in progress")]
+    TransferInProgress,
+    /// IMAGE_CHUNK or IMAGE_SEND_END without an IMAGE_SEND_START.
+    #[error("no active image transfer")]
+    NoActiveTransfer,
+    #[error("clipboard error: {0}")]
+    Clipboard(String),
+    /// The receiver granted no window for an image chunk in time; the image is abandoned.
+    #[error("no window granted in time, image abandoned")]
+    WindowStalled,
+    /// Refused by the device's policy or by the user.
+    #[error("{0}")]
+    Rejected(Rejection),
+}
+
+impl ProtocolError {
+    /// Stable machine-readable code sent to the peer.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidImageMetadata => "invalid_metadata",
+            Self::ImageTooLarge { .. } => "too_large",
+            Self::TransferInProgress => "transfer_in_progress",
+            Self::NoActiveTransfer => "no_active_transfer",
+            Self::Clipboard(_) => "clipboard_error",
+            Self::WindowStalled => "window_stalled",
+            Self::Rejected(rejection) => rejection.code(),
+        }
+    }
+}
+
+/// Code given to plain-text ERROR payloads from peers that predate error codes.
+pub const REMOTE_ERROR: &str = "remote_error";
+
+/// ERROR payload: `{"code":"...","message":"...","id":N}`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ErrorPayload {
+    pub code: String,
+    #[serde(default)]
+    pub message: String,
+    /// ID of the message or transfer the error refers to, when known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+}
+
+impl ErrorPayload {
+    pub fn new(code: &str, message: &str) -> Self {
+        Self {
+            code: code.to_string(),
+            message: message.to_string(),
+            id: None,
+        }
+    }
+
+    pub fn with_id(self, id: Option<u64>) -> Self {
+        Self { id, ..self }
+    }
+
+    /// Parse an ERROR payload, accepting the older free-text form as `remote_error`.
+    pub fn parse(text: &str) -> Self {
+        serde_json::from_str(text).unwrap_or_else(|_| Self::new(REMOTE_ERROR, text))
+    }
+}
+
+impl From<&ProtocolError> for ErrorPayload {
+    fn from(err: &ProtocolError) -> Self {
+        Self::new(err.code(), &err.to_string())
+    }
+}
+
+/// IMAGE_SEND_START payload. Missing fields default to zero / no type.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ImageMetadata {
+    pub width: u32,
+    pub height: u32,
+    pub total_bytes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    /// Message ID echoed in the IMAGE_ACK or ERROR for this image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+}
+
+impl ImageMetadata {
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("image metadata always serializes")
+    }
+}
+
 /// Maximum message payload size (25 MB — for image transfer metadata validation).
 #[allow(dead_code)]
 const MAX_PAYLOAD_SIZE: u32 = 26_214_400;
@@ -135,9 +400,15 @@ const MAX_PAYLOAD_SIZE: u32 = 26_214_400;
 /// Chunk size for image transfers (~60 KB, under Noise plaintext limit).
 pub const IMAGE_CHUNK_SIZE: usize = 60_000;
 
+/// Largest text that fits in a single CLIPBOARD_SEND: 65535 - 16 (AEAD tag) - 5 (header).
+pub const MAX_TEXT_SIZE: usize = 65514;
+
 /// Maximum total image size (25 MB).
 pub const MAX_IMAGE_SIZE: usize = 25 * 1024 * 1024;
 
+/// Image chunk data we accept ahead of processing it, advertised as our DEVICE_INFO window.
+pub const RECEIVE_WINDOW: usize = 16 * IMAGE_CHUNK_SIZE;
+
 /// Handshake type markers sent before the Noise handshake.
 pub const HANDSHAKE_PAIRING: u8 = 0x00;
 pub const HANDSHAKE_PAIRED: u8 = 0x01;
@@ -183,6 +454,8 @@ mod tests {
             (0x08, MessageType::ImageChunk),
             (0x09, MessageType::ImageSendEnd),
             (0x0A, MessageType::ImageAck),
+            (0x0B, MessageType::ClipboardMeta),
+            (0x0C, MessageType::Bye),
         ];
         for (byte, expected) in types {
             let parsed = MessageType::try_from(byte).unwrap();
@@ -194,7 +467,7 @@ mod tests {
     #[test]
     fn test_message_type_unknown_returns_error() {
         assert!(MessageType::try_from(0x00).is_err());
-        assert!(MessageType::try_from(0x0B).is_err());
+        assert!(MessageType::try_from(0x0E).is_err());
         assert!(MessageType::try_from(0xFF).is_err());
     }
 
@@ -214,7 +487,11 @@ mod tests {
 
     #[test]
     fn test_empty_payload_messages() {
-        for msg in [Message::clipboard_ack(), Message::ping(), Message::pong()] {
+        for msg in [
+            Message::clipboard_ack(None),
+            Message::ping(),
+            Message::pong(),
+        ] {
             let encoded = msg.encode();
             let len = u32::from_be_bytes([encoded[1], encoded[2], encoded[3], encoded[4]]);
             assert_eq!(len, 0);
@@ -228,7 +505,7 @@ mod tests {
 
     #[test]
     fn test_device_info_json() {
-        let msg = Message::device_info("My Mac");
+        let msg = Message::device_info("My Mac", None);
         let decoded = Message::decode(&msg.encode()).unwrap();
         assert_eq!(decoded.msg_type, MessageType::DeviceInfo);
 
@@ -237,12 +514,87 @@ mod tests {
         assert_eq!(json["name"], "My Mac");
     }
 
+    #[test]
+    fn test_device_info_advertises_image_types() {
+        let msg = Message::device_info("My Mac", None);
+        let info = DeviceInfo::from_json(&msg.payload_text().unwrap()).unwrap();
+        assert_eq!(info.name, "My Mac");
+        assert!(info.accepts_image_type("image/jpeg"));
+        assert!(info.accepts_image_type("image/webp"));
+        assert!(!info.accepts_image_type("image/heic"));
+    }
+
+    #[test]
+    fn test_device_info_window() {
+        let msg = Message::device_info("My Mac", Some(RECEIVE_WINDOW));
+        let info = DeviceInfo::from_json(&msg.payload_text().unwrap()).unwrap();
+        assert_eq!(info.window, Some(RECEIVE_WINDOW));
+        // Omitted when not advertised, as older peers do
+        let msg = Message::device_info("My Mac", None);
+        assert!(!msg.payload_text().unwrap().contains("window"));
+    }
+
+    #[test]
+    fn test_window_update_roundtrip() {
+        let msg = Message::decode(&Message::window_update(120_000).encode()).unwrap();
+        assert_eq!(msg.msg_type, MessageType::WindowUpdate);
+        assert_eq!(msg.window_credit().unwrap(), 120_000);
+        assert!(Message::new(MessageType::WindowUpdate, vec![])
+            .window_credit()
+            .is_err());
+    }
+
+    #[test]
+    fn test_legacy_device_info_accepts_png_only() {
+        let info = DeviceInfo::from_json(r#"{"name":"Pixel"}"#).unwrap();
+        assert!(info.image_types.is_empty());
+        assert!(info.accepts_image_type("image/png"));
+        assert!(!info.accepts_image_type("image/jpeg"));
+    }
+
     #[test]
     fn test_error_message() {
-        let msg = Message::error("something went wrong");
+        let msg = Message::error(&ProtocolError::NoActiveTransfer);
         let decoded = Message::decode(&msg.encode()).unwrap();
         assert_eq!(decoded.msg_type, MessageType::Error);
-        assert_eq!(decoded.payload_text().unwrap(), "something went wrong");
+        let payload = ErrorPayload::parse(&decoded.payload_text().unwrap());
+        assert_eq!(payload.code, "no_active_transfer");
+        assert_eq!(payload.message, "no active image transfer");
+        assert_eq!(payload.id, None);
+        assert!(!decoded.payload_text().unwrap().contains("\"id\""));
+    }
+
+    #[test]
+    fn test_protocol_error_codes() {
+        let err = ProtocolError::ImageTooLarge { bytes: 30, max: 20 };
+        assert_eq!(err.code(), "too_large");
+        assert_eq!(err.to_string(), "image too large (30 bytes, max 20)");
+        let err = ProtocolError::Rejected(Rejection::Declined);
+        assert_eq!(err.code(), "declined");
+        assert_eq!(err.to_string(), "rejected by user");
+    }
+
+    #[test]
+    fn test_error_payload_parses_legacy_text() {
+        let payload = ErrorPayload::parse("image too large");
+        assert_eq!(payload.code, REMOTE_ERROR);
+        assert_eq!(payload.message, "image too large");
+
+        let payload = ErrorPayload::parse(r#"{"code":"declined","id":7}"#);
+        assert_eq!(payload.code, "declined");
+        assert_eq!(payload.message, "");
+        assert_eq!(payload.id, Some(7));
+    }
+
+    #[test]
+    fn test_error_with_code_message() {
+        let msg = Message::error_with_code("too_large", "content too large");
+        let decoded = Message::decode(&msg.encode()).unwrap();
+        assert_eq!(decoded.msg_type, MessageType::Error);
+        let json: serde_json::Value =
+            serde_json::from_str(&decoded.payload_text().unwrap()).unwrap();
+        assert_eq!(json["code"], "too_large");
+        assert_eq!(json["message"], "content too large");
     }
 
     #[test]
@@ -287,17 +639,23 @@ mod tests {
     fn test_encode_decode_roundtrip_all_types() {
         let messages = vec![
             Message::clipboard_send("test data"),
-            Message::clipboard_ack(),
+            Message::clipboard_ack(Some(3)),
             Message::ping(),
             Message::pong(),
-            Message::device_info("test-device"),
-            Message::error("test error"),
+            Message::device_info("test-device", Some(4096)),
+            Message::window_update(4096),
+            Message::error(&ProtocolError::TransferInProgress),
             Message::image_send_start(
                 r#"{"width":100,"height":100,"totalBytes":1000,"mimeType":"image/png"}"#,
             ),
             Message::image_chunk(&[1, 2, 3, 4, 5]),
             Message::image_send_end(),
-            Message::image_ack(),
+            Message::image_ack(None),
+            Message::clipboard_meta(&ClipboardMeta {
+                sensitive: true,
+                id: Some(3),
+            }),
+            Message::bye(&Bye::new(BYE_SHUTDOWN, "")),
         ];
         for original in messages {
             let encoded = original.encode();
@@ -348,12 +706,24 @@ mod tests {
 
     #[test]
     fn test_image_ack_encode_decode() {
-        let msg = Message::image_ack();
+        let msg = Message::image_ack(None);
         let encoded = msg.encode();
         assert_eq!(encoded[0], 0x0A);
         let decoded = Message::decode(&encoded).unwrap();
         assert_eq!(decoded.msg_type, MessageType::ImageAck);
         assert!(decoded.payload.is_empty());
+        assert_eq!(decoded.ack_id(), None);
+    }
+
+    #[test]
+    fn test_ack_echoes_id() {
+        let msg = Message::decode(&Message::clipboard_ack(Some(42)).encode()).unwrap();
+        assert_eq!(msg.payload_text().unwrap(), r#"{"id":42}"#);
+        assert_eq!(msg.ack_id(), Some(42));
+        assert_eq!(Message::image_ack(Some(7)).ack_id(), Some(7));
+        // Junk in an ACK is treated like an ACK without ID
+        let junk = Message::new(MessageType::ClipboardAck, b"ok".to_vec());
+        assert_eq!(junk.ack_id(), None);
     }
 
     #[test]
@@ -373,6 +743,44 @@ mod tests {
         assert_eq!(MessageType::try_from(0x0A).unwrap(), MessageType::ImageAck);
     }
 
+    #[test]
+    fn test_clipboard_meta_encode_decode() {
+        let msg = Message::clipboard_meta(&ClipboardMeta {
+            sensitive: true,
+            id: None,
+        });
+        let encoded = msg.encode();
+        assert_eq!(encoded[0], 0x0B);
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(decoded.msg_type, MessageType::ClipboardMeta);
+        assert_eq!(decoded.payload_text().unwrap(), r#"{"sensitive":true}"#);
+    }
+
+    #[test]
+    fn test_clipboard_meta_missing_fields_default() {
+        let meta = ClipboardMeta::from_json("{}").unwrap();
+        assert!(!meta.sensitive);
+        assert_eq!(meta.id, None);
+        let meta = ClipboardMeta::from_json(r#"{"sensitive":true,"id":5,"extra":1}"#).unwrap();
+        assert!(meta.sensitive);
+        assert_eq!(meta.id, Some(5));
+    }
+
+    #[test]
+    fn test_bye_encode_decode() {
+        let msg = Message::bye(&Bye::new(BYE_SHUTDOWN, "quitting"));
+        let encoded = msg.encode();
+        assert_eq!(encoded[0], 0x0C);
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(
+            decoded.payload_text().unwrap(),
+            r#"{"reason":"shutdown","message":"quitting"}"#
+        );
+        let bye = Bye::from_json(&decoded.payload_text().unwrap()).unwrap();
+        assert_eq!(bye, Bye::new("shutdown", "quitting"));
+        assert_eq!(Bye::from_json("{}").unwrap().reason, "");
+    }
+
     #[test]
     fn test_image_chunk_size_constant() {
         assert_eq!(IMAGE_CHUNK_SIZE, 60_000);
@@ -382,4 +790,31 @@ mod tests {
     fn test_max_image_size_constant() {
         assert_eq!(MAX_IMAGE_SIZE, 25 * 1024 * 1024);
     }
+
+    #[test]
+    fn test_image_metadata_roundtrip() {
+        let meta = ImageMetadata {
+            width: 640,
+            height: 480,
+            total_bytes: 12345,
+            mime_type: Some("image/jpeg".to_string()),
+            id: Some(9),
+        };
+        let json = meta.to_json();
+        assert_eq!(
+            json,
+            r#"{"width":640,"height":480,"totalBytes":12345,"mimeType":"image/jpeg","id":9}"#
+        );
+        assert_eq!(ImageMetadata::from_json(&json).unwrap(), meta);
+    }
+
+    #[test]
+    fn test_image_metadata_lenient_and_invalid() {
+        let meta = ImageMetadata::from_json(r#"{"totalBytes":10,"extra":1}"#).unwrap();
+        assert_eq!(meta.total_bytes, 10);
+        assert_eq!((meta.width, meta.height), (0, 0));
+        assert_eq!(meta.mime_type, None);
+        assert!(ImageMetadata::from_json("not json").is_err());
+        assert!(ImageMetadata::from_json(r#"{"width":-1}"#).is_err());
+    }
 }
@@ -0,0 +1,780 @@
+//! Persistent per-device queue for content sent while its device is not connected.
+//!
+//! Items are kept in `queue/queue.json` inside the store directory, with image data next to
+//! it in `queue/<id>.bin`. They go out oldest first once their device connects and are
+//! dropped when delivered, refused, cancelled or expired. An item that is sent but not
+//! acknowledged stays queued and is sent again on the next connection.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::clipboard;
+use crate::delivery::{Delivery, PendingDelivery};
+use crate::events::{AppState, ServerEvent};
+use crate::history::{make_preview, now_millis};
+use crate::protocol::Message;
+use crate::server;
+
+/// How long an item waits for its device unless configured otherwise.
+pub const DEFAULT_QUEUE_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Most items queued for one device unless configured otherwise.
+pub const DEFAULT_QUEUE_MAX_ITEMS: usize = 50;
+
+/// How long a queued item waits for its ACK before it is left for the next connection.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Offline queue settings.
+#[derive(Debug, Clone)]
+pub struct QueueSettings {
+    /// Queue content for a device that is not connected instead of failing the send.
+    pub enabled: bool,
+    /// Default time before an undelivered item is dropped.
+    pub expiry: Duration,
+    /// Most items queued for one device.
+    pub max_items: usize,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            expiry: DEFAULT_QUEUE_EXPIRY,
+            max_items: DEFAULT_QUEUE_MAX_ITEMS,
+        }
+    }
+}
+
+/// What a queued item holds. Image data is stored separately.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum QueuedContent {
+    Text {
+        text: String,
+    },
+    Image {
+        mime_type: String,
+        width: u32,
+        height: u32,
+        bytes: usize,
+    },
+}
+
+/// One item waiting for its device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedItem {
+    pub id: u64,
+    pub device: String,
+    #[serde(flatten)]
+    pub content: QueuedContent,
+    /// Unix time in milliseconds.
+    pub queued_at: u64,
+    /// Unix time in milliseconds after which the item is dropped undelivered.
+    pub expires_at: u64,
+}
+
+impl QueuedItem {
+    /// Size of the content in bytes.
+    pub fn bytes(&self) -> usize {
+        match &self.content {
+            QueuedContent::Text { text } => text.len(),
+            QueuedContent::Image { bytes, .. } => *bytes,
+        }
+    }
+
+    /// Short single-line description for lists.
+    pub fn preview(&self) -> String {
+        match &self.content {
+            QueuedContent::Text { text } => make_preview(text),
+            QueuedContent::Image {
+                mime_type,
+                width,
+                height,
+                bytes,
+            } => format!(
+                "[image {}x{}, {}, {} bytes]",
+                width, height, mime_type, bytes
+            ),
+        }
+    }
+}
+
+/// Content to send to a device now, or to queue for it.
+#[derive(Debug, Clone)]
+pub enum OutboundContent {
+    Text {
+        text: String,
+        sensitive: bool,
+    },
+    /// An image already prepared by `imaging::prepare_image`; it is re-encoded for the
+    /// device's accepted types when it goes out.
+    Image {
+        bytes: Vec<u8>,
+        mime_type: String,
+        width: u32,
+        height: u32,
+    },
+}
+
+/// Where `send_or_queue` put the content.
+#[derive(Debug)]
+pub enum Outbound {
+    /// Sent to the connected device; `bytes` and `mime_type` as they went out.
+    Sent {
+        device: String,
+        pending: PendingDelivery,
+        bytes: usize,
+        mime_type: Option<String>,
+    },
+    /// Queued until the device connects.
+    Queued(QueuedItem),
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct StoredQueue {
+    next_id: u64,
+    items: Vec<QueuedItem>,
+}
+
+/// The outbound queue of every paired device.
+pub struct OutboundQueue {
+    dir: PathBuf,
+    /// Serializes read-modify-write cycles of the queue file.
+    lock: Mutex<()>,
+    /// Poked when there may be something to deliver.
+    ready: Notify,
+}
+
+impl OutboundQueue {
+    /// A queue stored in `dir`, which is created when the first item is queued.
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            lock: Mutex::new(()),
+            ready: Notify::new(),
+        }
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.dir.join("queue.json")
+    }
+
+    fn data_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{}.bin", id))
+    }
+
+    fn load(&self) -> Result<StoredQueue> {
+        let path = self.index_path();
+        if !path.exists() {
+            return Ok(StoredQueue::default());
+        }
+        let data = fs::read(&path)?;
+        match serde_json::from_slice(&data) {
+            Ok(queue) => Ok(queue),
+            Err(e) => {
+                warn!(
+                    "invalid queue file {:?}, starting an empty queue: {}",
+                    path, e
+                );
+                self.discard_unreadable()?;
+                Ok(StoredQueue::default())
+            }
+        }
+    }
+
+    /// Set an unreadable index aside (as `queue.json.bad`) with the image data only it
+    /// could refer to, so the queue keeps working.
+    fn discard_unreadable(&self) -> Result<()> {
+        fs::rename(self.index_path(), self.dir.join("queue.json.bad"))?;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "bin") {
+                let _ = fs::remove_file(path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the index to a temporary file and move it over the old one, so a crash
+    /// mid-write leaves the previous index intact.
+    fn save(&self, queue: &StoredQueue) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create queue dir: {:?}", self.dir))?;
+        let json = serde_json::to_string_pretty(queue)?;
+        let tmp = self.dir.join("queue.json.tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, self.index_path())?;
+        Ok(())
+    }
+
+    /// Load the queue with expired items already dropped.
+    fn load_current(&self, now: u64) -> Result<StoredQueue> {
+        let mut queue = self.load()?;
+        let (expired, items): (Vec<_>, Vec<_>) = queue
+            .items
+            .into_iter()
+            .partition(|item| item.expires_at <= now);
+        queue.items = items;
+        if !expired.is_empty() {
+            for item in &expired {
+                info!("queued item {} for {} expired", item.id, item.device);
+                self.remove_data(item);
+            }
+            self.save(&queue)?;
+        }
+        Ok(queue)
+    }
+
+    fn remove_data(&self, item: &QueuedItem) {
+        if matches!(item.content, QueuedContent::Image { .. }) {
+            let _ = fs::remove_file(self.data_path(item.id));
+        }
+    }
+
+    /// Add `content` to the end of `device`'s queue. Sensitive text is never written to
+    /// disk and is refused.
+    pub fn push(
+        &self,
+        device: &str,
+        content: &OutboundContent,
+        settings: &QueueSettings,
+        expiry: Option<Duration>,
+    ) -> Result<QueuedItem> {
+        self.push_at(device, content, settings, expiry, now_millis())
+    }
+
+    fn push_at(
+        &self,
+        device: &str,
+        content: &OutboundContent,
+        settings: &QueueSettings,
+        expiry: Option<Duration>,
+        now: u64,
+    ) -> Result<QueuedItem> {
+        let _guard = self.lock.lock().unwrap();
+        let mut queue = self.load_current(now)?;
+        let queued = queue.items.iter().filter(|i| i.device == device).count();
+        if queued >= settings.max_items {
+            bail!(
+                "{} already has {} items queued (max {})",
+                device,
+                queued,
+                settings.max_items
+            );
+        }
+
+        queue.next_id += 1;
+        let id = queue.next_id;
+        let content = match content {
+            OutboundContent::Text {
+                sensitive: true, ..
+            } => {
+                bail!(
+                    "sensitive text is not queued; send it while {} is connected",
+                    device
+                )
+            }
+            OutboundContent::Text { text, .. } => QueuedContent::Text { text: text.clone() },
+            OutboundContent::Image {
+                bytes,
+                mime_type,
+                width,
+                height,
+            } => {
+                fs::create_dir_all(&self.dir)
+                    .with_context(|| format!("failed to create queue dir: {:?}", self.dir))?;
+                fs::write(self.data_path(id), bytes)?;
+                QueuedContent::Image {
+                    mime_type: mime_type.clone(),
+                    width: *width,
+                    height: *height,
+                    bytes: bytes.len(),
+                }
+            }
+        };
+        let expiry = expiry.unwrap_or(settings.expiry);
+        let item = QueuedItem {
+            id,
+            device: device.to_string(),
+            content,
+            queued_at: now,
+            expires_at: now.saturating_add(expiry.as_millis() as u64),
+        };
+        queue.items.push(item.clone());
+        self.save(&queue)?;
+        Ok(item)
+    }
+
+    /// Items waiting for `device`, or for every device, oldest first.
+    pub fn items(&self, device: Option<&str>) -> Result<Vec<QueuedItem>> {
+        self.items_at(device, now_millis())
+    }
+
+    fn items_at(&self, device: Option<&str>, now: u64) -> Result<Vec<QueuedItem>> {
+        let _guard = self.lock.lock().unwrap();
+        let queue = self.load_current(now)?;
+        Ok(queue
+            .items
+            .into_iter()
+            .filter(|item| device.is_none_or(|device| item.device == device))
+            .collect())
+    }
+
+    /// Whether anything is waiting for `device`.
+    pub fn has_items(&self, device: &str) -> Result<bool> {
+        Ok(!self.items(Some(device))?.is_empty())
+    }
+
+    /// Stored data of a queued image.
+    pub fn image_data(&self, id: u64) -> Result<Vec<u8>> {
+        let path = self.data_path(id);
+        fs::read(&path).with_context(|| format!("failed to read queued image {:?}", path))
+    }
+
+    /// Drop an item. Returns false if it is not queued (any more).
+    pub fn remove(&self, id: u64) -> Result<bool> {
+        let _guard = self.lock.lock().unwrap();
+        let mut queue = self.load()?;
+        let Some(position) = queue.items.iter().position(|item| item.id == id) else {
+            return Ok(false);
+        };
+        let item = queue.items.remove(position);
+        self.save(&queue)?;
+        self.remove_data(&item);
+        Ok(true)
+    }
+
+    /// Drop everything queued for `device`, e.g. when it is unpaired.
+    pub fn clear(&self, device: &str) -> Result<usize> {
+        let _guard = self.lock.lock().unwrap();
+        let mut queue = self.load()?;
+        let (removed, items): (Vec<_>, Vec<_>) = queue
+            .items
+            .into_iter()
+            .partition(|item| item.device == device);
+        queue.items = items;
+        if !removed.is_empty() {
+            self.save(&queue)?;
+            for item in &removed {
+                self.remove_data(item);
+            }
+        }
+        Ok(removed.len())
+    }
+
+    /// Have `run_delivery` look for items to send to the connected device.
+    pub fn wake(&self) {
+        self.ready.notify_one();
+    }
+}
+
+/// The device to queue for: `requested` if given, otherwise the connected device, otherwise
+/// the only paired device. `None` if no device is paired.
+async fn target_device(state: &AppState, requested: Option<&str>) -> Result<Option<String>> {
+    let paired: Vec<String> = state
+        .store
+        .list_paired_devices()?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    if let Some(name) = requested {
+        if !paired.iter().any(|paired| paired == name) {
+            bail!("device not found: {}", name);
+        }
+        return Ok(Some(name.to_string()));
+    }
+    if let Some(connected) = state.connected_device.read().await.clone() {
+        return Ok(Some(connected));
+    }
+    match paired.as_slice() {
+        [] => Ok(None),
+        [only] => Ok(Some(only.clone())),
+        _ => bail!(
+            "{} devices are paired and none is connected; choose one to queue for",
+            paired.len()
+        ),
+    }
+}
+
+/// Send `content` to the connected device, or queue it if `device` names another device,
+/// nothing is connected, or earlier items for the device are still queued (so it keeps
+/// their order). Returns `None` if there is no session and queueing is off.
+pub async fn send_or_queue(
+    state: &AppState,
+    device: Option<&str>,
+    content: OutboundContent,
+    expiry: Option<Duration>,
+) -> Result<Option<Outbound>> {
+    let settings = state.settings().queue;
+    let Some(target) = target_device(state, device).await? else {
+        return Ok(None);
+    };
+    let connected = state.connected_device.read().await.clone();
+    let tx = state.session_tx.read().await.clone();
+    if let (Some(connected), Some(tx)) = (connected, tx) {
+        if connected == target && !(settings.enabled && state.queue.has_items(&target)?) {
+            match send_content(state, &tx, &content).await {
+                Ok((pending, bytes, mime_type)) => {
+                    return Ok(Some(Outbound::Sent {
+                        device: target,
+                        pending,
+                        bytes,
+                        mime_type,
+                    }))
+                }
+                // The session just ended: queue it like any other offline send
+                Err(e) if tx.is_closed() => debug!("session ended while sending: {}", e),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    if !settings.enabled {
+        return Ok(None);
+    }
+
+    let item = state.queue.push(&target, &content, &settings, expiry)?;
+    info!(
+        "queued {} bytes for {} (item {})",
+        item.bytes(),
+        item.device,
+        item.id
+    );
+    emit_changed(state, &target)?;
+    state.queue.wake();
+    Ok(Some(Outbound::Queued(item)))
+}
+
+/// Send `content` through the session, returning its delivery and size and type as sent.
+async fn send_content(
+    state: &AppState,
+    tx: &mpsc::Sender<Message>,
+    content: &OutboundContent,
+) -> Result<(PendingDelivery, usize, Option<String>)> {
+    match content {
+        OutboundContent::Text { text, sensitive } => {
+            let pending = server::send_text(tx, text, *sensitive, state).await?;
+            Ok((pending, text.len(), None))
+        }
+        OutboundContent::Image {
+            bytes,
+            mime_type,
+            width,
+            height,
+        } => {
+            // Fall back to PNG if the peer doesn't accept the prepared type; re-encoding
+            // is CPU-bound
+            let peer = state.peer_info.read().await.clone().unwrap_or_default();
+            let policy = state.settings().image_send;
+            let (bytes, mime_type, width, height) =
+                (bytes.clone(), mime_type.clone(), *width, *height);
+            let prepared = tokio::task::spawn_blocking(move || {
+                clipboard::encode_for_peer(bytes, &mime_type, width, height, &peer, &policy)
+            })
+            .await
+            .context("image encoding task panicked")??;
+            let pending = server::send_image_chunks(
+                tx,
+                &prepared.bytes,
+                &prepared.mime_type,
+                prepared.width,
+                prepared.height,
+                state,
+            )
+            .await?;
+            Ok((pending, prepared.bytes.len(), Some(prepared.mime_type)))
+        }
+    }
+}
+
+fn emit_changed(state: &AppState, device: &str) -> Result<()> {
+    let pending = state.queue.items(Some(device))?.len();
+    state.emit(ServerEvent::QueueChanged {
+        device: device.to_string(),
+        pending,
+    });
+    Ok(())
+}
+
+/// Cancel a queued item. Returns false if it was not queued (any more).
+pub fn cancel(state: &AppState, id: u64) -> Result<bool> {
+    let Some(item) = state.queue.items(None)?.into_iter().find(|i| i.id == id) else {
+        return Ok(false);
+    };
+    let removed = state.queue.remove(id)?;
+    if removed {
+        info!("cancelled queued item {} for {}", id, item.device);
+        emit_changed(state, &item.device)?;
+    }
+    Ok(removed)
+}
+
+/// Deliver queued items to the connected device, oldest first, whenever the queue is woken:
+/// when a session is ready and when an item is queued. Runs until `cancel` fires.
+pub async fn run_delivery(state: Arc<AppState>, cancel: CancellationToken) {
+    loop {
+        tokio::select! {
+            _ = state.queue.ready.notified() => {}
+            _ = cancel.cancelled() => return,
+        }
+        if let Err(e) = deliver_queued(&state, &cancel).await {
+            warn!("queued items not delivered: {:#}", e);
+        }
+    }
+}
+
+async fn deliver_queued(state: &AppState, cancel: &CancellationToken) -> Result<()> {
+    loop {
+        let Some(device) = state.connected_device.read().await.clone() else {
+            return Ok(());
+        };
+        let Some(tx) = state.session_tx.read().await.clone() else {
+            return Ok(());
+        };
+        let Some(item) = state.queue.items(Some(&device))?.into_iter().next() else {
+            return Ok(());
+        };
+
+        let content = match outbound_content(state, &item) {
+            Ok(content) => content,
+            Err(e) => {
+                drop_undeliverable(state, &item, &e)?;
+                continue;
+            }
+        };
+        let pending = match send_content(state, &tx, &content).await {
+            Ok((pending, _, _)) => pending,
+            // The session ended; the item goes out on the next one
+            Err(e) if tx.is_closed() => {
+                debug!("session ended while sending queued item {}: {}", item.id, e);
+                return Ok(());
+            }
+            // It would fail the same way next time and hold up everything behind it
+            Err(e) => {
+                drop_undeliverable(state, &item, &e)?;
+                continue;
+            }
+        };
+        let outcome = tokio::select! {
+            outcome = pending.outcome(DELIVERY_TIMEOUT) => outcome,
+            _ = cancel.cancelled() => return Ok(()),
+        };
+        match outcome {
+            Delivery::Delivered => info!("delivered queued item {} to {}", item.id, device),
+            Delivery::Rejected { reason, .. } => {
+                warn!("{} refused queued item {}: {}", device, item.id, reason)
+            }
+            Delivery::TimedOut | Delivery::Disconnected => {
+                info!(
+                    "queued item {} for {} not delivered: {}",
+                    item.id, device, outcome
+                );
+                return Ok(());
+            }
+        }
+        state.queue.remove(item.id)?;
+        emit_changed(state, &device)?;
+    }
+}
+
+fn outbound_content(state: &AppState, item: &QueuedItem) -> Result<OutboundContent> {
+    Ok(match &item.content {
+        QueuedContent::Text { text } => OutboundContent::Text {
+            text: text.clone(),
+            sensitive: false,
+        },
+        QueuedContent::Image {
+            mime_type,
+            width,
+            height,
+            ..
+        } => OutboundContent::Image {
+            bytes: state.queue.image_data(item.id)?,
+            mime_type: mime_type.clone(),
+            width: *width,
+            height: *height,
+        },
+    })
+}
+
+/// Drop a queued item that cannot be read or encoded, reporting it as a failed transfer.
+fn drop_undeliverable(state: &AppState, item: &QueuedItem, error: &anyhow::Error) -> Result<()> {
+    warn!(
+        "dropping queued item {} for {}: {:#}",
+        item.id, item.device, error
+    );
+    state.queue.remove(item.id)?;
+    state.emit(ServerEvent::TransferRejected {
+        device: item.device.clone(),
+        incoming: false,
+        code: "undeliverable".to_string(),
+        reason: format!("{:#}", error),
+    });
+    emit_changed(state, &item.device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn text(text: &str) -> OutboundContent {
+        OutboundContent::Text {
+            text: text.to_string(),
+            sensitive: false,
+        }
+    }
+
+    fn queue() -> (TempDir, OutboundQueue) {
+        let dir = TempDir::new().unwrap();
+        let queue = OutboundQueue::new(dir.path().join("queue"));
+        (dir, queue)
+    }
+
+    #[test]
+    fn test_items_keep_order_per_device() {
+        let (_dir, queue) = queue();
+        let settings = QueueSettings::default();
+        queue.push("phone", &text("one"), &settings, None).unwrap();
+        queue
+            .push("tablet", &text("other"), &settings, None)
+            .unwrap();
+        queue.push("phone", &text("two"), &settings, None).unwrap();
+
+        let items = queue.items(Some("phone")).unwrap();
+        let texts: Vec<String> = items.iter().map(QueuedItem::preview).collect();
+        assert_eq!(texts, vec!["one", "two"]);
+        assert_eq!(queue.items(None).unwrap().len(), 3);
+        assert!(queue.has_items("tablet").unwrap());
+        assert!(!queue.has_items("laptop").unwrap());
+    }
+
+    #[test]
+    fn test_queue_survives_restart() {
+        let (dir, queue) = queue();
+        let item = queue
+            .push("phone", &text("hello"), &QueueSettings::default(), None)
+            .unwrap();
+        drop(queue);
+        let queue = OutboundQueue::new(dir.path().join("queue"));
+        assert_eq!(queue.items(None).unwrap(), vec![item]);
+    }
+
+    #[test]
+    fn test_unreadable_index_is_recovered() {
+        let (dir, queue) = queue();
+        let settings = QueueSettings::default();
+        queue.push("phone", &text("lost"), &settings, None).unwrap();
+        let queue_dir = dir.path().join("queue");
+        fs::write(
+            queue_dir.join("queue.json"),
+            "{\"next_id\": 3, \"items\": [",
+        )
+        .unwrap();
+
+        assert!(queue.items(None).unwrap().is_empty());
+        assert!(!queue.has_items("phone").unwrap());
+        assert!(queue_dir.join("queue.json.bad").exists());
+        queue.push("phone", &text("new"), &settings, None).unwrap();
+        assert_eq!(queue.items(None).unwrap().len(), 1);
+        assert!(!queue_dir.join("queue.json.tmp").exists());
+    }
+
+    #[test]
+    fn test_remove_and_clear() {
+        let (_dir, queue) = queue();
+        let settings = QueueSettings::default();
+        let first = queue.push("phone", &text("one"), &settings, None).unwrap();
+        queue.push("phone", &text("two"), &settings, None).unwrap();
+        assert!(queue.remove(first.id).unwrap());
+        assert!(!queue.remove(first.id).unwrap());
+        assert_eq!(queue.items(None).unwrap().len(), 1);
+        assert_eq!(queue.clear("phone").unwrap(), 1);
+        assert!(queue.items(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ids_are_not_reused() {
+        let (_dir, queue) = queue();
+        let settings = QueueSettings::default();
+        let first = queue.push("phone", &text("one"), &settings, None).unwrap();
+        queue.remove(first.id).unwrap();
+        let second = queue.push("phone", &text("two"), &settings, None).unwrap();
+        assert_ne!(first.id, second.id);
+    }
+
+    #[test]
+    fn test_items_expire() {
+        let (_dir, queue) = queue();
+        let settings = QueueSettings {
+            expiry: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let short = Some(Duration::from_secs(1));
+        queue
+            .push_at("phone", &text("soon"), &settings, short, 1_000)
+            .unwrap();
+        queue
+            .push_at("phone", &text("later"), &settings, None, 1_000)
+            .unwrap();
+
+        assert_eq!(queue.items_at(None, 1_500).unwrap().len(), 2);
+        let left = queue.items_at(None, 2_000).unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].preview(), "later");
+        assert!(queue.items_at(None, 61_000).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_per_device_limit() {
+        let (_dir, queue) = queue();
+        let settings = QueueSettings {
+            max_items: 1,
+            ..Default::default()
+        };
+        queue.push("phone", &text("one"), &settings, None).unwrap();
+        assert!(queue.push("phone", &text("two"), &settings, None).is_err());
+        queue.push("tablet", &text("two"), &settings, None).unwrap();
+    }
+
+    #[test]
+    fn test_sensitive_text_is_not_queued() {
+        let (dir, queue) = queue();
+        let secret = OutboundContent::Text {
+            text: "hunter2".to_string(),
+            sensitive: true,
+        };
+        let err = queue
+            .push("phone", &secret, &QueueSettings::default(), None)
+            .unwrap_err();
+        assert!(err.to_string().contains("sensitive"));
+        assert!(!dir.path().join("queue").join("queue.json").exists());
+    }
+
+    #[test]
+    fn test_image_data_stored_separately() {
+        let (dir, queue) = queue();
+        let image = OutboundContent::Image {
+            bytes: vec![1, 2, 3],
+            mime_type: "image/png".to_string(),
+            width: 2,
+            height: 1,
+        };
+        let item = queue
+            .push("phone", &image, &QueueSettings::default(), None)
+            .unwrap();
+        assert_eq!(item.bytes(), 3);
+        assert_eq!(queue.image_data(item.id).unwrap(), vec![1, 2, 3]);
+        let index = fs::read_to_string(dir.path().join("queue").join("queue.json")).unwrap();
+        assert!(index.contains("\"kind\": \"image\""));
+
+        queue.remove(item.id).unwrap();
+        assert!(queue.image_data(item.id).is_err());
+    }
+}
@@ -1,26 +1,102 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use std::collections::VecDeque;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tokio::net::TcpListener;
-use tokio::sync::mpsc;
-use tokio::time;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::{self, Instant, MissedTickBehavior};
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
 use crate::clipboard;
-use crate::crypto::{self, NoiseTransport};
+use crate::confirm::Decision;
+use crate::crypto::{self, MessageTransport};
+use crate::delivery::{Delivery, PendingDelivery};
+use crate::discovery::{DiscoveryServer, ServiceTxt};
 use crate::events::{AppState, ServerEvent};
-use crate::protocol::{Message, MessageType, IMAGE_CHUNK_SIZE, MAX_IMAGE_SIZE};
+use crate::flow::{ReceiveWindow, SendWindow};
+use crate::history::{self, HistoryEntry};
+use crate::net;
+use crate::policy::{ContentKind, DevicePolicy, Rejection};
+use crate::protocol::{
+    Bye, ClipboardMeta, DeviceInfo, ErrorPayload, ImageMetadata, Message, MessageType,
+    ProtocolError, BYE_SHUTDOWN, RECEIVE_WINDOW,
+};
+use crate::queue;
+use crate::sensitive;
+use crate::stats::{CountingTransport, STATS_INTERVAL};
+use crate::storage::DeviceStore;
 
-/// Run the receiver server, accepting and handling one connection at a time.
-/// Supports graceful shutdown via CancellationToken.
+/// Listen on the configured addresses and port, falling back to a free port if it is taken.
+/// A failure is also reported as `ServerEvent::BindFailed` and kept as
+/// `AppState::bind_error` for UIs that start listening for events later.
+pub fn bind_listeners(state: &AppState) -> Result<Vec<TcpListener>> {
+    let result = net::bind_with_fallback(&state.bind, state.port);
+    match &result {
+        Ok(_) => state.set_bind_error(None),
+        Err(e) => {
+            error!("cannot listen on port {}: {:#}", state.port, e);
+            let reason = format!("{:#}", e);
+            state.set_bind_error(Some(reason.clone()));
+            state.emit(ServerEvent::BindFailed {
+                port: state.port,
+                reason,
+            });
+        }
+    }
+    result
+}
+
+/// Advertise the listening addresses over mDNS and run the server until cancelled.
+/// Without mDNS the server still runs; devices can connect by address.
+pub async fn serve(
+    listeners: Vec<TcpListener>,
+    state: Arc<AppState>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let bound = listeners
+        .iter()
+        .map(|listener| listener.local_addr())
+        .collect::<std::io::Result<Vec<_>>>()?;
+    if let Some(port) = bound.first().map(|addr| addr.port()) {
+        match DiscoveryServer::new(
+            port,
+            &state.device_name,
+            &net::advertised_addrs(&bound),
+            ServiceTxt::local(&state),
+        ) {
+            // The TXT record follows the pairing window
+            Ok(discovery) => {
+                tokio::spawn(discovery.track_pairing(state.clone(), cancel.clone()));
+            }
+            Err(e) => warn!("mDNS unavailable, not advertising: {}", e),
+        }
+    }
+    tokio::spawn(queue::run_delivery(state.clone(), cancel.clone()));
+    run_server(listeners, state, cancel).await
+}
+
+/// `bind_listeners` followed by `serve`.
+pub async fn start(state: Arc<AppState>, cancel: CancellationToken) -> Result<()> {
+    let listeners = bind_listeners(&state)?;
+    serve(listeners, state, cancel).await
+}
+
+/// Run the receiver server, accepting and handling one connection at a time across all
+/// `listeners`. Supports graceful shutdown via CancellationToken.
 pub async fn run_server(
-    listener: TcpListener,
+    listeners: Vec<TcpListener>,
     state: Arc<AppState>,
     cancel: CancellationToken,
 ) -> Result<()> {
-    let port = state.port;
-    info!("server listening on {}", listener.local_addr()?);
+    if listeners.is_empty() {
+        bail!("no listening socket");
+    }
+    let port = listeners[0].local_addr()?.port();
+    for listener in &listeners {
+        info!("server listening on {}", listener.local_addr()?);
+    }
+    state.set_listen_port(Some(port));
     info!("pairing code: {}", state.pairing_code);
 
     state.emit(ServerEvent::ServerStarted {
@@ -30,17 +106,30 @@ pub async fn run_server(
 
     loop {
         let (stream, addr) = tokio::select! {
-            result = listener.accept() => result?,
+            result = net::accept_any(&listeners) => match result {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    state.set_listen_port(None);
+                    return Err(e.into());
+                }
+            },
             _ = cancel.cancelled() => {
                 info!("server shutting down");
+                state.set_listen_port(None);
                 return Ok(());
             }
         };
         info!("connection from {}", addr);
+        if let Err(e) = net::set_tcp_keepalive(&stream, state.settings().keepalive) {
+            warn!("cannot enable TCP keepalive for {}: {}", addr, e);
+        }
 
-        match crypto::accept_connection(stream, &state.identity, &state.pairing_code, &state.store)
-            .await
-        {
+        // Only hand the pairing code to the handshake while the window is open
+        let pairing_code = state
+            .pairing
+            .is_open()
+            .then_some(state.pairing_code.as_str());
+        match crypto::accept_connection(stream, &state.identity, pairing_code, &state.store).await {
             Ok((transport, remote_name)) => {
                 info!("authenticated: {}", remote_name);
                 state.emit(ServerEvent::DeviceConnected {
@@ -51,12 +140,20 @@ pub async fn run_server(
                     *connected = Some(remote_name.clone());
                 }
 
-                if let Err(e) = handle_session(transport, &state, &cancel).await {
-                    warn!("session with {} ended: {}", remote_name, e);
-                }
+                let reason = match handle_session(transport, &remote_name, &state, &cancel).await {
+                    Ok(bye) => {
+                        info!("session with {} closed: {}", remote_name, bye.reason);
+                        Some(bye.reason)
+                    }
+                    Err(e) => {
+                        warn!("session with {} ended: {}", remote_name, e);
+                        None
+                    }
+                };
 
                 state.emit(ServerEvent::DeviceDisconnected {
                     name: remote_name.clone(),
+                    reason,
                 });
                 {
                     let mut connected = state.connected_device.write().await;
@@ -74,40 +171,88 @@ pub async fn run_server(
     }
 }
 
-/// Send image as chunked messages through the session channel.
+/// How long a shutdown waits for an outgoing image to finish sending.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Messages the session channel holds before senders wait for the connection to drain it.
+pub const OUTBOUND_CAPACITY: usize = 8;
+
+/// Queue text for the device through the session channel. The returned delivery
+/// resolves once the device acknowledges or refuses it.
+pub async fn send_text(
+    tx: &mpsc::Sender<Message>,
+    text: &str,
+    sensitive: bool,
+    state: &AppState,
+) -> Result<PendingDelivery> {
+    let pending = state.deliveries.register();
+    let meta = ClipboardMeta {
+        sensitive,
+        id: Some(pending.id),
+    };
+    let _sending = state.send_lock.lock().await;
+    let queued = match tx.send(Message::clipboard_meta(&meta)).await {
+        Ok(()) => tx.send(Message::clipboard_send(text)).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = queued {
+        state.deliveries.cancel(pending.id);
+        return Err(e.into());
+    }
+    Ok(pending)
+}
+
+/// Send an encoded image as chunked messages through the session channel. Chunks are
+/// produced as the connection drains the channel, so this returns once the last one is
+/// queued. The returned delivery resolves once the device acknowledges or refuses it.
 pub async fn send_image_chunks(
-    tx: &mpsc::UnboundedSender<Message>,
-    png_bytes: &[u8],
+    tx: &mpsc::Sender<Message>,
+    image_bytes: &[u8],
+    mime_type: &str,
     width: u32,
     height: u32,
     state: &AppState,
+) -> Result<PendingDelivery> {
+    let pending = state.deliveries.register();
+    let metadata = ImageMetadata {
+        width,
+        height,
+        total_bytes: image_bytes.len(),
+        mime_type: Some(mime_type.to_string()),
+        id: Some(pending.id),
+    };
+    if let Err(e) = queue_image_chunks(tx, image_bytes, &metadata, state).await {
+        state.deliveries.cancel(pending.id);
+        return Err(e);
+    }
+    Ok(pending)
+}
+
+async fn queue_image_chunks(
+    tx: &mpsc::Sender<Message>,
+    image_bytes: &[u8],
+    metadata: &ImageMetadata,
+    state: &AppState,
 ) -> Result<()> {
-    let total_bytes = png_bytes.len();
-    let metadata = serde_json::json!({
-        "width": width,
-        "height": height,
-        "totalBytes": total_bytes,
-        "mimeType": "image/png"
-    });
-    tx.send(Message::image_send_start(&metadata.to_string()))?;
-
-    let mut sent = 0usize;
-    for chunk in png_bytes.chunks(IMAGE_CHUNK_SIZE) {
-        tx.send(Message::image_chunk(chunk))?;
-        sent += chunk.len();
-        state.emit(ServerEvent::ImageTransferProgress {
-            bytes_transferred: sent as u64,
-            bytes_total: total_bytes as u64,
-        });
+    let total_bytes = image_bytes.len();
+    let chunk_size = state.settings().image_chunk_bytes;
+    let _sending = state.send_lock.lock().await;
+    tx.send(Message::image_send_start(&metadata.to_json()))
+        .await?;
+
+    // Progress is reported by the session as chunks are written, not here
+    for chunk in image_bytes.chunks(chunk_size) {
+        tx.send(Message::image_chunk(chunk)).await?;
     }
 
-    tx.send(Message::image_send_end())?;
+    tx.send(Message::image_send_end()).await?;
     info!(
-        "image send complete: {}x{}, {} bytes in {} chunks",
-        width,
-        height,
+        "image queued: {}x{}, {} bytes {} in {} chunks",
+        metadata.width,
+        metadata.height,
         total_bytes,
-        total_bytes.div_ceil(IMAGE_CHUNK_SIZE)
+        metadata.mime_type.as_deref().unwrap_or_default(),
+        total_bytes.div_ceil(chunk_size)
     );
     Ok(())
 }
@@ -117,117 +262,536 @@ struct ImageReceiveState {
     width: u32,
     height: u32,
     total_bytes: usize,
+    mime_type: Option<String>,
+    /// Message ID to echo in the ACK or ERROR.
+    id: Option<u64>,
     buffer: Vec<u8>,
 }
 
-/// Handle an authenticated session with a connected device.
-async fn handle_session(
-    mut transport: NoiseTransport,
+/// Incoming content held back until the user accepts or rejects it.
+struct ParkedItem {
+    id: u64,
+    content: ParkedContent,
+    decision: oneshot::Receiver<Decision>,
+    deadline: time::Instant,
+}
+
+enum ParkedContent {
+    Text {
+        text: String,
+        sensitive: bool,
+        id: Option<u64>,
+    },
+    Image(ImageReceiveState),
+}
+
+impl ParkedContent {
+    fn id(&self) -> Option<u64> {
+        match self {
+            Self::Text { id, .. } => *id,
+            Self::Image(img) => img.id,
+        }
+    }
+}
+
+/// An item we sent that the device has not acknowledged or refused yet.
+struct Unacknowledged {
+    kind: ContentKind,
+    id: Option<u64>,
+    /// Characters of text, bytes of an image.
+    size: usize,
+}
+
+/// The connected device's transfer policy, loaded when the session starts and reloaded
+/// whenever the device store changes on disk (the app and `uclip policy` both write it).
+struct SessionPolicy<'a> {
+    store: &'a DeviceStore,
+    device: &'a str,
+    policy: DevicePolicy,
+    /// Modification time of the store the policy was read from.
+    modified: Option<SystemTime>,
+}
+
+impl<'a> SessionPolicy<'a> {
+    fn load(store: &'a DeviceStore, device: &'a str) -> Self {
+        let mut policy = Self {
+            store,
+            device,
+            policy: DevicePolicy::default(),
+            modified: None,
+        };
+        policy.current();
+        policy
+    }
+
+    /// The policy, re-read first if the store changed. A store that cannot be read keeps
+    /// the policy already loaded, and is tried again next time.
+    fn current(&mut self) -> &DevicePolicy {
+        let modified = self.store.devices_modified();
+        if modified.is_some() && modified != self.modified {
+            match self.store.device_policy(self.device) {
+                Ok(policy) => {
+                    self.policy = policy;
+                    self.modified = modified;
+                }
+                Err(e) => warn!(
+                    "cannot read policy for {}, keeping the current one: {:#}",
+                    self.device, e
+                ),
+            }
+        }
+        &self.policy
+    }
+}
+
+/// What `msg` starts sending, for matching the device's ACK or ERROR to it.
+/// CLIPBOARD_META only notes the ID for the CLIPBOARD_SEND that follows it.
+fn track_outgoing(msg: &Message, text_id: &mut Option<u64>) -> Option<Unacknowledged> {
+    match msg.msg_type {
+        MessageType::ClipboardMeta => {
+            *text_id = msg
+                .payload_text()
+                .and_then(|json| ClipboardMeta::from_json(&json))
+                .ok()
+                .and_then(|meta| meta.id);
+            None
+        }
+        MessageType::ClipboardSend => Some(Unacknowledged {
+            kind: ContentKind::Text,
+            id: text_id.take(),
+            size: msg.payload_text().map_or(0, |text| text.chars().count()),
+        }),
+        MessageType::ImageSendStart => {
+            let meta = msg
+                .payload_text()
+                .and_then(|json| ImageMetadata::from_json(&json))
+                .unwrap_or_default();
+            Some(Unacknowledged {
+                kind: ContentKind::Image,
+                id: meta.id,
+                size: meta.total_bytes,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The sent item an ACK or ERROR refers to: the one with its ID, or for peers that do not
+/// echo IDs the oldest one (of `kind`, if given).
+fn take_unacknowledged(
+    sent: &mut VecDeque<Unacknowledged>,
+    id: Option<u64>,
+    kind: Option<ContentKind>,
+) -> Option<Unacknowledged> {
+    let position = match id {
+        Some(id) => sent.iter().position(|item| item.id == Some(id)),
+        None => sent
+            .iter()
+            .position(|item| kind.is_none_or(|kind| item.kind == kind)),
+    }?;
+    sent.remove(position)
+}
+
+/// Handle an authenticated session with a connected device until either side says BYE
+/// (returned) or the connection fails. Cancelling sends our own BYE.
+/// Generic over the transport so the session logic can also run without a socket.
+pub async fn handle_session(
+    transport: impl MessageTransport,
+    remote_name: &str,
     state: &AppState,
     cancel: &CancellationToken,
-) -> Result<()> {
+) -> Result<Bye> {
+    state.stats.start(remote_name);
+    let mut transport = CountingTransport::new(transport, &state.stats);
+
     // Create outbound message channel
-    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (tx, mut rx) = mpsc::channel(OUTBOUND_CAPACITY);
     {
         let mut session_tx = state.session_tx.write().await;
         *session_tx = Some(tx);
     }
 
-    let result = handle_session_loop(&mut transport, &mut rx, state, cancel).await;
+    let result = handle_session_loop(&mut transport, &mut rx, remote_name, state, cancel).await;
 
-    // Cleanup: clear the session sender
+    // Cleanup: clear the session sender and peer info
     {
         let mut session_tx = state.session_tx.write().await;
         *session_tx = None;
     }
+    *state.peer_info.write().await = None;
+    state.deliveries.disconnect_all();
+    state.stats.end();
 
     result
 }
 
+/// Refuse incoming content that the device's policy does not allow.
+async fn reject_incoming(
+    transport: &mut impl MessageTransport,
+    state: &AppState,
+    device: &str,
+    rejection: &Rejection,
+    id: Option<u64>,
+) -> Result<()> {
+    warn!("rejected content from {}: {}", device, rejection);
+    let reason = rejection.to_string();
+    let err = ProtocolError::Rejected(rejection.clone());
+    transport
+        .send_message(&Message::error_for(&err, id))
+        .await?;
+    state.emit(ServerEvent::TransferRejected {
+        device: device.to_string(),
+        incoming: true,
+        code: rejection.code().to_string(),
+        reason,
+    });
+    Ok(())
+}
+
+/// Write received text to the clipboard and acknowledge it.
+async fn apply_text(
+    transport: &mut impl MessageTransport,
+    state: &AppState,
+    remote_name: &str,
+    text: &str,
+    sensitive: bool,
+    id: Option<u64>,
+) -> Result<()> {
+    let chars = text.len();
+    let owned = text.to_string();
+    let result = clipboard::with_clipboard(&state.clipboard, move |cb| cb.set_text(&owned)).await;
+    if let Err(e) = result {
+        error!("failed to set clipboard: {}", e);
+        let err_msg = Message::error_for(&ProtocolError::Clipboard(e.to_string()), id);
+        transport.send_message(&err_msg).await?;
+        return Ok(());
+    }
+    transport.send_message(&Message::clipboard_ack(id)).await?;
+    let preview = if sensitive {
+        if let Some(after) = state.settings().sensitive.clear_after {
+            sensitive::schedule_clear(text, after, state.clipboard.clone(), state.event_tx.clone());
+        }
+        sensitive::redact(text)
+    } else {
+        state
+            .history
+            .write()
+            .await
+            .push(HistoryEntry::text(remote_name, text));
+        history::make_preview(text)
+    };
+    state.emit(ServerEvent::ClipboardReceived {
+        chars,
+        preview,
+        sensitive,
+    });
+    Ok(())
+}
+
+/// Write a reassembled image to the clipboard and acknowledge it.
+async fn apply_image(
+    transport: &mut impl MessageTransport,
+    state: &AppState,
+    remote_name: &str,
+    recv_state: ImageReceiveState,
+) -> Result<()> {
+    let ImageReceiveState {
+        width,
+        height,
+        mime_type,
+        id,
+        buffer,
+        ..
+    } = recv_state;
+    let bytes = buffer.len();
+    info!(
+        "image receive complete, writing to clipboard ({}x{}, {} bytes)",
+        width, height, bytes
+    );
+    let result = clipboard::with_clipboard(&state.clipboard, move |cb| {
+        cb.set_encoded_image(&buffer, mime_type.as_deref())
+    })
+    .await;
+    if let Err(e) = result {
+        error!("failed to set clipboard image: {}", e);
+        let err_msg = Message::error_for(&ProtocolError::Clipboard(e.to_string()), id);
+        transport.send_message(&err_msg).await?;
+        state.emit(ServerEvent::ImageTransferFailed {
+            reason: e.to_string(),
+        });
+        return Ok(());
+    }
+    transport.send_message(&Message::image_ack(id)).await?;
+    state
+        .history
+        .write()
+        .await
+        .push(HistoryEntry::image(remote_name, width, height, bytes));
+    state.emit(ServerEvent::ImageReceived {
+        width,
+        height,
+        bytes,
+    });
+    Ok(())
+}
+
+/// Hold incoming content and ask the user whether to apply it.
+fn park(state: &AppState, remote_name: &str, content: ParkedContent) -> ParkedItem {
+    let (id, decision) = state.confirmations.register();
+    let confirm_timeout = state.settings().confirm.timeout;
+    let (kind, preview, bytes) = match &content {
+        ParkedContent::Text {
+            text, sensitive, ..
+        } => {
+            let preview = if *sensitive {
+                sensitive::redact(text)
+            } else {
+                history::make_preview(text)
+            };
+            (ContentKind::Text, preview, text.len())
+        }
+        ParkedContent::Image(img) => {
+            let entry = HistoryEntry::image(remote_name, img.width, img.height, img.buffer.len());
+            (ContentKind::Image, entry.preview, img.buffer.len())
+        }
+    };
+    info!(
+        "waiting for confirmation of {} from {} (request {})",
+        kind, remote_name, id
+    );
+    state.emit(ServerEvent::ConfirmationRequested {
+        id,
+        device: remote_name.to_string(),
+        kind: kind.to_string(),
+        preview,
+        bytes,
+        timeout_secs: confirm_timeout.as_secs(),
+    });
+    ParkedItem {
+        id,
+        content,
+        decision,
+        deadline: time::Instant::now() + confirm_timeout,
+    }
+}
+
+/// Wait for the parked item's decision. `None` means it timed out.
+async fn wait_for_decision(parked: &mut Option<ParkedItem>) -> Option<Decision> {
+    let item = parked.as_mut()?;
+    time::timeout_at(item.deadline, &mut item.decision)
+        .await
+        .ok()?
+        .ok()
+}
+
 /// Inner message loop for an authenticated session.
+/// PINGs every `keepalive`, the first one `keepalive` from now.
+fn ping_schedule(keepalive: Duration) -> time::Interval {
+    let mut timer = time::interval_at(Instant::now() + keepalive, keepalive);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    timer
+}
+
 async fn handle_session_loop(
-    transport: &mut NoiseTransport,
-    rx: &mut mpsc::UnboundedReceiver<Message>,
+    transport: &mut impl MessageTransport,
+    rx: &mut mpsc::Receiver<Message>,
+    remote_name: &str,
     state: &AppState,
     cancel: &CancellationToken,
-) -> Result<()> {
+) -> Result<Bye> {
     // Exchange device info
-    let info_msg = Message::device_info(&state.device_name);
+    let info_msg = Message::device_info(&state.device_name, Some(RECEIVE_WINDOW));
     transport.send_message(&info_msg).await?;
 
+    let mut flow = OutboundFlow::default();
+    // Only a peer that advertises a window of its own understands WINDOW_UPDATE
+    let mut receive_window: Option<ReceiveWindow> = None;
     let remote_info = transport.recv_message().await?;
     if remote_info.msg_type == MessageType::DeviceInfo {
         let text = remote_info.payload_text()?;
         info!("remote device info: {}", text);
+        match DeviceInfo::from_json(&text) {
+            Ok(info) => {
+                if let Some(window) = info.window {
+                    flow.window = Some(SendWindow::new(window));
+                    receive_window = Some(ReceiveWindow::new(RECEIVE_WINDOW));
+                }
+                *state.peer_info.write().await = Some(info);
+            }
+            Err(e) => warn!("invalid remote device info: {}", e),
+        }
     }
+    // Anything queued while the device was away can go out now
+    state.queue.wake();
+
+    // PING on a fixed schedule; hearing nothing for `pong_timeout` after one means the peer
+    // is gone. Any message counts, so a peer busy sending an image is not cut off
+    let mut keepalive = state.settings().keepalive;
+    let mut ping_timer = ping_schedule(keepalive);
+    // A config reload may change the schedule
+    let mut events = state.subscribe();
+    let mut ping_sent: Option<Instant> = None;
+    let mut pong_deadline: Option<Instant> = None;
+    let mut stats_timer = time::interval_at(Instant::now() + STATS_INTERVAL, STATS_INTERVAL);
 
     // Main message loop
-    let keepalive = Duration::from_secs(30);
     let mut image_receive: Option<ImageReceiveState> = None;
-    let mut last_sent_image_bytes: Option<usize> = None;
+    let mut pending_meta: Option<ClipboardMeta> = None;
+    // Items we sent, oldest first, and the ID from our last CLIPBOARD_META
+    let mut unacknowledged: VecDeque<Unacknowledged> = VecDeque::new();
+    let mut outgoing_text_id: Option<u64> = None;
+    // Chunks of a rejected image (inbound or outbound) are dropped until its SEND_END
+    let mut discard_incoming_image = false;
+    let mut discard_outgoing_image = false;
+    // Between our IMAGE_SEND_START and IMAGE_SEND_END
+    let mut sending_image = false;
+    let mut parked: Option<ParkedItem> = None;
+    let mut policy = SessionPolicy::load(&state.store, remote_name);
 
     loop {
         tokio::select! {
             result = transport.recv_message() => {
                 let msg = result?;
+                if pong_deadline.is_some() {
+                    pong_deadline = Some(Instant::now() + state.settings().pong_timeout);
+                }
+                // CLIPBOARD_META only applies to a CLIPBOARD_SEND that directly follows it
+                let text_meta = pending_meta.take();
                 match msg.msg_type {
+                    MessageType::ClipboardMeta => {
+                        match msg.payload_text().and_then(|json| ClipboardMeta::from_json(&json)) {
+                            Ok(meta) => pending_meta = Some(meta),
+                            Err(e) => warn!("ignoring invalid clipboard metadata: {}", e),
+                        }
+                    }
                     MessageType::ClipboardSend => {
                         let text = msg.payload_text()?;
-                        let chars = text.len();
-                        info!("received clipboard content ({} chars)", chars);
-                        if let Err(e) = clipboard::set_clipboard_text(&text) {
-                            error!("failed to set clipboard: {}", e);
-                            let err_msg = Message::error(&format!("clipboard error: {}", e));
-                            transport.send_message(&err_msg).await?;
+                        let meta = text_meta.unwrap_or_default();
+                        if parked.is_some() {
+                            warn!("clipboard content rejected while awaiting confirmation");
+                            let err = Message::error_for(&ProtocolError::TransferInProgress, meta.id);
+                            transport.send_message(&err).await?;
+                            continue;
+                        }
+                        let device_policy = policy.current();
+                        if let Err(rejection) = device_policy.check_incoming(ContentKind::Text, text.len()) {
+                            reject_incoming(transport, state, remote_name, &rejection, meta.id).await?;
+                            continue;
+                        }
+                        let sensitive = meta.sensitive
+                            || (state.settings().sensitive.detect && sensitive::looks_sensitive(&text));
+                        info!("received clipboard content ({} chars, sensitive: {})", text.len(), sensitive);
+                        if state.settings().confirm.enabled || device_policy.require_confirmation {
+                            let content = ParkedContent::Text { text, sensitive, id: meta.id };
+                            parked = Some(park(state, remote_name, content));
                         } else {
-                            transport.send_message(&Message::clipboard_ack()).await?;
-                            state.emit(ServerEvent::ClipboardReceived { chars });
+                            apply_text(transport, state, remote_name, &text, sensitive, meta.id).await?;
                         }
                     }
                     MessageType::ClipboardAck => {
-                        info!("received clipboard ACK from remote");
-                        state.emit(ServerEvent::ClipboardSent { chars: 0 });
+                        let id = msg.ack_id();
+                        let sent = take_unacknowledged(&mut unacknowledged, id, Some(ContentKind::Text));
+                        info!("received clipboard ACK from remote (id {:?})", id);
+                        if let Some(id) = id.or(sent.as_ref().and_then(|item| item.id)) {
+                            state.deliveries.resolve(id, Delivery::Delivered);
+                        }
+                        state.emit(ServerEvent::ClipboardSent {
+                            chars: sent.map_or(0, |item| item.size),
+                        });
                     }
                     MessageType::Ping => {
                         transport.send_message(&Message::pong()).await?;
                     }
                     MessageType::Pong => {
-                        // keepalive response, ignore
+                        pong_deadline = None;
+                        if let Some(sent) = ping_sent.take() {
+                            state.stats.rtt(sent.elapsed());
+                        }
+                    }
+                    MessageType::WindowUpdate => {
+                        let credit = msg.window_credit()?;
+                        if let Some(window) = flow.window.as_mut() {
+                            window.granted(credit);
+                        }
+                        flow.release(transport, state).await?;
                     }
                     MessageType::Error => {
-                        let text = msg.payload_text().unwrap_or_default();
-                        warn!("remote error: {}", text);
-                        if image_receive.is_some() {
+                        let error = ErrorPayload::parse(&msg.payload_text().unwrap_or_default());
+                        warn!("remote error: {} ({})", error.message, error.code);
+                        // An ID names the item we sent; without a match, an image we are
+                        // receiving is what failed
+                        let matched = error.id.and_then(|id| take_unacknowledged(&mut unacknowledged, Some(id), None));
+                        if matched.is_none() && image_receive.is_some() {
                             info!("aborting in-progress image receive due to remote error");
                             image_receive = None;
                             state.emit(ServerEvent::ImageTransferFailed {
-                                reason: format!("remote error: {}", text),
+                                reason: format!("remote error: {}", error.message),
+                            });
+                        } else {
+                            // The remote refused something we sent
+                            let sent = match error.id {
+                                Some(_) => matched,
+                                None => take_unacknowledged(&mut unacknowledged, None, None),
+                            };
+                            if let Some(id) = error.id.or(sent.and_then(|item| item.id)) {
+                                state.deliveries.resolve(id, Delivery::Rejected {
+                                    code: error.code.clone(),
+                                    reason: error.message.clone(),
+                                });
+                            }
+                            state.emit(ServerEvent::TransferRejected {
+                                device: remote_name.to_string(),
+                                incoming: false,
+                                code: error.code,
+                                reason: error.message,
                             });
                         }
                     }
                     MessageType::ImageSendStart => {
-                        let json_str = msg.payload_text()?;
-                        let meta: serde_json::Value = serde_json::from_str(&json_str)?;
-                        let width = meta["width"].as_u64().unwrap_or(0) as u32;
-                        let height = meta["height"].as_u64().unwrap_or(0) as u32;
-                        let total_bytes = meta["totalBytes"].as_u64().unwrap_or(0) as usize;
-
-                        if total_bytes > MAX_IMAGE_SIZE {
-                            warn!("image too large: {} bytes (max {})", total_bytes, MAX_IMAGE_SIZE);
-                            transport.send_message(&Message::error("image too large")).await?;
+                        let meta = match msg.payload_text().and_then(|json| ImageMetadata::from_json(&json)) {
+                            Ok(meta) => meta,
+                            Err(e) => {
+                                warn!("invalid image metadata: {}", e);
+                                transport.send_message(&Message::error(&ProtocolError::InvalidImageMetadata)).await?;
+                                discard_incoming_image = true;
+                                continue;
+                            }
+                        };
+                        let ImageMetadata { width, height, total_bytes, mime_type, id } = meta;
+
+                        let max_image_bytes = state.settings().max_image_bytes;
+                        if total_bytes > max_image_bytes {
+                            warn!("image too large: {} bytes (max {})", total_bytes, max_image_bytes);
+                            let err = ProtocolError::ImageTooLarge { bytes: total_bytes, max: max_image_bytes };
+                            transport.send_message(&Message::error_for(&err, id)).await?;
+                            discard_incoming_image = true;
                             continue;
                         }
-                        if image_receive.is_some() {
+                        if image_receive.is_some() || parked.is_some() {
                             warn!("concurrent image transfer rejected");
-                            transport.send_message(&Message::error("transfer already in progress")).await?;
+                            let err = Message::error_for(&ProtocolError::TransferInProgress, id);
+                            transport.send_message(&err).await?;
+                            // With no image being received, every chunk that follows is this one's
+                            if image_receive.is_none() {
+                                discard_incoming_image = true;
+                            }
+                            continue;
+                        }
+                        if let Err(rejection) = policy.current().check_incoming(ContentKind::Image, total_bytes) {
+                            reject_incoming(transport, state, remote_name, &rejection, id).await?;
+                            discard_incoming_image = true;
                             continue;
                         }
+                        discard_incoming_image = false;
 
-                        info!("starting image receive: {}x{}, {} bytes", width, height, total_bytes);
+                        info!("starting image receive: {}x{}, {} bytes {}", width, height, total_bytes,
+                            mime_type.as_deref().unwrap_or("(no type)"));
                         image_receive = Some(ImageReceiveState {
                             width,
                             height,
                             total_bytes,
+                            mime_type,
+                            id,
                             buffer: Vec::with_capacity(total_bytes),
                         });
                         state.emit(ServerEvent::ImageTransferProgress {
@@ -236,11 +800,21 @@ async fn handle_session_loop(
                         });
                     }
                     MessageType::ImageChunk => {
+                        // Discarded chunks count too: the sender spent window on them
+                        let credit = receive_window.as_mut().and_then(|window| window.consumed(msg.payload.len()));
+                        if let Some(credit) = credit {
+                            transport.send_message(&Message::window_update(credit)).await?;
+                        }
                         if let Some(ref mut recv_state) = image_receive {
-                            if recv_state.buffer.len() + msg.payload.len() > MAX_IMAGE_SIZE {
+                            let bytes = recv_state.buffer.len() + msg.payload.len();
+                            let max = state.settings().max_image_bytes;
+                            if bytes > max {
                                 warn!("cumulative image data exceeds max size, aborting");
+                                let id = recv_state.id;
                                 image_receive = None;
-                                transport.send_message(&Message::error("image data exceeds max size")).await?;
+                                let err = ProtocolError::ImageTooLarge { bytes, max };
+                                transport.send_message(&Message::error_for(&err, id)).await?;
+                                discard_incoming_image = true;
                                 state.emit(ServerEvent::ImageTransferFailed {
                                     reason: "cumulative data exceeds max size".to_string(),
                                 });
@@ -251,61 +825,332 @@ async fn handle_session_loop(
                                 bytes_transferred: recv_state.buffer.len() as u64,
                                 bytes_total: recv_state.total_bytes as u64,
                             });
-                        } else {
+                        } else if !discard_incoming_image {
                             warn!("unexpected IMAGE_CHUNK without active transfer");
-                            transport.send_message(&Message::error("no active image transfer")).await?;
+                            transport.send_message(&Message::error(&ProtocolError::NoActiveTransfer)).await?;
                         }
                     }
                     MessageType::ImageSendEnd => {
+                        if let Some(credit) = receive_window.as_mut().and_then(ReceiveWindow::flush) {
+                            transport.send_message(&Message::window_update(credit)).await?;
+                        }
                         if let Some(recv_state) = image_receive.take() {
-                            info!("image receive complete, writing to clipboard ({}x{}, {} bytes)",
-                                recv_state.width, recv_state.height, recv_state.buffer.len());
-                            if let Err(e) = clipboard::set_clipboard_image(&recv_state.buffer) {
-                                error!("failed to set clipboard image: {}", e);
-                                transport.send_message(&Message::error(&format!("clipboard error: {}", e))).await?;
-                                state.emit(ServerEvent::ImageTransferFailed {
-                                    reason: e.to_string(),
-                                });
+                            if state.settings().confirm.enabled || policy.current().require_confirmation {
+                                parked = Some(park(state, remote_name, ParkedContent::Image(recv_state)));
                             } else {
-                                transport.send_message(&Message::image_ack()).await?;
-                                state.emit(ServerEvent::ImageReceived {
-                                    width: recv_state.width,
-                                    height: recv_state.height,
-                                    bytes: recv_state.buffer.len(),
-                                });
+                                apply_image(transport, state, remote_name, recv_state).await?;
                             }
+                        } else if discard_incoming_image {
+                            discard_incoming_image = false;
                         } else {
                             warn!("unexpected IMAGE_SEND_END without active transfer");
-                            transport.send_message(&Message::error("no active image transfer")).await?;
+                            transport.send_message(&Message::error(&ProtocolError::NoActiveTransfer)).await?;
                         }
                     }
                     MessageType::ImageAck => {
-                        let bytes = last_sent_image_bytes.take().unwrap_or(0);
-                        info!("received image ACK from remote ({} bytes)", bytes);
+                        let id = msg.ack_id();
+                        let sent = take_unacknowledged(&mut unacknowledged, id, Some(ContentKind::Image));
+                        if let Some(id) = id.or(sent.as_ref().and_then(|item| item.id)) {
+                            state.deliveries.resolve(id, Delivery::Delivered);
+                        }
+                        let bytes = sent.map_or(0, |item| item.size);
+                        info!("received image ACK from remote ({} bytes, id {:?})", bytes, id);
                         state.emit(ServerEvent::ImageSent { bytes });
                     }
+                    MessageType::Bye => {
+                        let bye = msg.payload_text().and_then(|json| Bye::from_json(&json)).unwrap_or_default();
+                        info!("{} said goodbye: {} {}", remote_name, bye.reason, bye.message);
+                        if image_receive.take().is_some() {
+                            state.emit(ServerEvent::ImageTransferFailed {
+                                reason: format!("{} disconnected", remote_name),
+                            });
+                        }
+                        resolve_parked(state, &mut parked);
+                        return Ok(bye);
+                    }
                     _ => {
                         warn!("unexpected message type: {:?}", msg.msg_type);
                     }
                 }
             }
-            Some(outbound_msg) = rx.recv() => {
-                if outbound_msg.msg_type == MessageType::ImageSendStart {
-                    if let Ok(json_str) = outbound_msg.payload_text() {
-                        if let Ok(meta) = serde_json::from_str::<serde_json::Value>(&json_str) {
-                            last_sent_image_bytes = meta["totalBytes"].as_u64().map(|b| b as usize);
+            // While a chunk waits for window the channel fills up and its senders wait
+            Some(outbound_msg) = rx.recv(), if flow.held.is_none() => {
+                let item = track_outgoing(&outbound_msg, &mut outgoing_text_id);
+                match outbound_msg.msg_type {
+                    MessageType::ClipboardMeta | MessageType::ClipboardSend | MessageType::ImageSendStart => {
+                        if let Err(rejection) = policy.current().check_outgoing() {
+                            discard_outgoing_image = outbound_msg.msg_type == MessageType::ImageSendStart;
+                            if let Some(item) = item {
+                                warn!("not sending to {}: {}", remote_name, rejection);
+                                if let Some(id) = item.id {
+                                    state.deliveries.resolve(id, Delivery::Rejected {
+                                        code: rejection.code().to_string(),
+                                        reason: rejection.to_string(),
+                                    });
+                                }
+                                state.emit(ServerEvent::TransferRejected {
+                                    device: remote_name.to_string(),
+                                    incoming: false,
+                                    code: rejection.code().to_string(),
+                                    reason: rejection.to_string(),
+                                });
+                            }
+                            continue;
                         }
+                        discard_outgoing_image = false;
+                    }
+                    MessageType::ImageChunk | MessageType::ImageSendEnd if discard_outgoing_image => {
+                        continue;
+                    }
+                    _ => {}
+                }
+                unacknowledged.extend(item);
+                sending_image = match outbound_msg.msg_type {
+                    MessageType::ImageSendStart => true,
+                    MessageType::ImageSendEnd => false,
+                    _ => sending_image,
+                };
+                if flow.must_hold(&outbound_msg) {
+                    flow.hold(outbound_msg, state.settings().window_stall_timeout);
+                    continue;
+                }
+                flow.write(transport, state, &outbound_msg).await?;
+            }
+            decision = wait_for_decision(&mut parked), if parked.is_some() => {
+                let item = parked.take().expect("branch is only enabled while an item is parked");
+                state.confirmations.cancel(item.id);
+                let accepted = decision == Some(Decision::Accept);
+                state.emit(ServerEvent::ConfirmationResolved { id: item.id, accepted });
+                let id = item.content.id();
+                match (decision, item.content) {
+                    (Some(Decision::Accept), ParkedContent::Text { text, sensitive, id }) => {
+                        apply_text(transport, state, remote_name, &text, sensitive, id).await?;
+                    }
+                    (Some(Decision::Accept), ParkedContent::Image(recv_state)) => {
+                        apply_image(transport, state, remote_name, recv_state).await?;
+                    }
+                    (decision, _) => {
+                        let rejection = if decision.is_none() {
+                            Rejection::ConfirmationTimedOut
+                        } else {
+                            Rejection::Declined
+                        };
+                        reject_incoming(transport, state, remote_name, &rejection, id).await?;
+                    }
+                }
+            }
+            _ = ping_timer.tick() => {
+                // Still waiting for the last PONG: its deadline stands
+                if ping_sent.is_none() {
+                    transport.send_message(&Message::ping()).await?;
+                    ping_sent = Some(Instant::now());
+                    pong_deadline = Some(Instant::now() + state.settings().pong_timeout);
+                }
+            }
+            _ = time::sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+                let timeout = state.settings().pong_timeout;
+                warn!("{} sent nothing for {:?} after a PING, closing session", remote_name, timeout);
+                state.emit(ServerEvent::PeerUnresponsive {
+                    name: remote_name.to_string(),
+                    timeout_secs: timeout.as_secs(),
+                });
+                if image_receive.take().is_some() {
+                    state.emit(ServerEvent::ImageTransferFailed {
+                        reason: format!("{} stopped responding", remote_name),
+                    });
+                }
+                resolve_parked(state, &mut parked);
+                return Err(anyhow!("nothing heard within {:?} of a PING", timeout));
+            }
+            _ = time::sleep_until(flow.stall_deadline.unwrap_or_else(Instant::now)), if flow.held.is_some() => {
+                let stall = state.settings().window_stall_timeout;
+                warn!("{} granted no window for {:?}, abandoning image", remote_name, stall);
+                flow.abandon();
+                // The rest of the image is dropped as it comes off the channel
+                discard_outgoing_image = true;
+                sending_image = false;
+                // Without an ID the peer discards the partial image it is receiving
+                transport.send_message(&Message::error(&ProtocolError::WindowStalled)).await?;
+                let sent = unacknowledged
+                    .iter()
+                    .rposition(|item| item.kind == ContentKind::Image)
+                    .and_then(|position| unacknowledged.remove(position));
+                if let Some(id) = sent.and_then(|item| item.id) {
+                    state.deliveries.resolve(id, Delivery::TimedOut);
+                }
+                state.emit(ServerEvent::ImageTransferFailed {
+                    reason: format!("{} granted no window for {:?}", remote_name, stall),
+                });
+            }
+            event = events.recv() => {
+                if matches!(event, Ok(ServerEvent::ConfigReloaded { .. }) | Err(broadcast::error::RecvError::Lagged(_))) {
+                    let current = state.settings().keepalive;
+                    if current != keepalive {
+                        debug!("keepalive changed to {:?}", current);
+                        keepalive = current;
+                        ping_timer = ping_schedule(keepalive);
                     }
                 }
-                transport.send_message(&outbound_msg).await?;
             }
-            _ = time::sleep(keepalive) => {
-                transport.send_message(&Message::ping()).await?;
+            _ = stats_timer.tick() => {
+                if let Some(stats) = state.stats.snapshot() {
+                    state.emit(ServerEvent::SessionStats(stats));
+                }
             }
             _ = cancel.cancelled() => {
-                info!("session cancelled");
-                return Ok(());
+                info!("session cancelled, saying goodbye to {}", remote_name);
+                if sending_image && !discard_outgoing_image {
+                    finish_outgoing_image(transport, rx, &mut flow, state).await;
+                }
+                if image_receive.take().is_some() {
+                    state.emit(ServerEvent::ImageTransferFailed {
+                        reason: "receiver shutting down".to_string(),
+                    });
+                }
+                resolve_parked(state, &mut parked);
+                let bye = Bye::new(BYE_SHUTDOWN, "receiver shutting down");
+                if let Err(e) = transport.send_message(&Message::bye(&bye)).await {
+                    debug!("could not send BYE: {}", e);
+                }
+                return Ok(bye);
+            }
+        }
+    }
+}
+
+/// Send the rest of an image that is already on its way, for up to `SHUTDOWN_GRACE`.
+/// Anything queued after it is dropped; the peer discards a partial image on BYE.
+/// The peer's window is not waited for: the grace period bounds what is sent instead.
+async fn finish_outgoing_image(
+    transport: &mut impl MessageTransport,
+    rx: &mut mpsc::Receiver<Message>,
+    flow: &mut OutboundFlow,
+    state: &AppState,
+) {
+    let flush = async {
+        if let Some(msg) = flow.held.take() {
+            flow.write(transport, state, &msg).await?;
+        }
+        while let Some(msg) = rx.recv().await {
+            if !matches!(
+                msg.msg_type,
+                MessageType::ImageChunk | MessageType::ImageSendEnd
+            ) {
+                break;
             }
+            flow.write(transport, state, &msg).await?;
+            if msg.msg_type == MessageType::ImageSendEnd {
+                info!("finished sending image before closing");
+                break;
+            }
+        }
+        anyhow::Ok(())
+    };
+    match time::timeout(SHUTDOWN_GRACE, flush).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("image send interrupted: {}", e),
+        Err(_) => warn!(
+            "image still sending after {:?}, aborting it",
+            SHUTDOWN_GRACE
+        ),
+    }
+}
+
+/// Outbound image chunks: the peer's window and what has been written of the current image.
+#[derive(Default)]
+struct OutboundFlow {
+    window: Option<SendWindow>,
+    /// A chunk waiting for the peer to grant more window.
+    held: Option<Message>,
+    /// When the held chunk stops waiting and its image is abandoned. Each grant of
+    /// credit moves it back, so a slow peer is waited for as long as it makes progress.
+    stall_deadline: Option<Instant>,
+    /// Bytes written and total of the image being sent.
+    progress: Option<(usize, usize)>,
+}
+
+impl OutboundFlow {
+    fn must_hold(&self, msg: &Message) -> bool {
+        msg.msg_type == MessageType::ImageChunk
+            && self
+                .window
+                .as_ref()
+                .is_some_and(|window| !window.allows(msg.payload.len()))
+    }
+
+    fn hold(&mut self, msg: Message, stall: Duration) {
+        self.held = Some(msg);
+        self.stall_deadline = Some(Instant::now() + stall);
+    }
+
+    /// Write the held chunk if the window now has room for it.
+    async fn release(
+        &mut self,
+        transport: &mut impl MessageTransport,
+        state: &AppState,
+    ) -> Result<()> {
+        match self.held.take() {
+            Some(msg) if self.must_hold(&msg) => {
+                self.hold(msg, state.settings().window_stall_timeout)
+            }
+            Some(msg) => {
+                self.stall_deadline = None;
+                self.write(transport, state, &msg).await?
+            }
+            None => {}
         }
+        Ok(())
+    }
+
+    /// Drop the held chunk and stop tracking the image it belongs to.
+    fn abandon(&mut self) {
+        self.held = None;
+        self.stall_deadline = None;
+        self.progress = None;
+    }
+
+    /// Write a message, reporting image progress by the bytes actually written.
+    async fn write(
+        &mut self,
+        transport: &mut impl MessageTransport,
+        state: &AppState,
+        msg: &Message,
+    ) -> Result<()> {
+        transport.send_message(msg).await?;
+        match msg.msg_type {
+            MessageType::ImageSendStart => {
+                let total = msg
+                    .payload_text()
+                    .and_then(|json| ImageMetadata::from_json(&json))
+                    .map_or(0, |meta| meta.total_bytes);
+                self.progress = Some((0, total));
+            }
+            MessageType::ImageChunk => {
+                if let Some(window) = self.window.as_mut() {
+                    window.sent(msg.payload.len());
+                }
+                if let Some((written, total)) = self.progress.as_mut() {
+                    *written += msg.payload.len();
+                    state.emit(ServerEvent::ImageTransferProgress {
+                        bytes_transferred: *written as u64,
+                        bytes_total: *total as u64,
+                    });
+                }
+            }
+            MessageType::ImageSendEnd => self.progress = None,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Drop content waiting for confirmation when the session ends.
+fn resolve_parked(state: &AppState, parked: &mut Option<ParkedItem>) {
+    if let Some(item) = parked.take() {
+        state.confirmations.cancel(item.id);
+        state.emit(ServerEvent::ConfirmationResolved {
+            id: item.id,
+            accepted: false,
+        });
     }
 }
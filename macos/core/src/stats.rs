@@ -0,0 +1,193 @@
+//! Counters for the current session: traffic, transfer outcomes and PING round trips.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::MessageTransport;
+use crate::events::ServerEvent;
+use crate::history::now_millis;
+use crate::protocol::Message;
+
+/// How often a `SessionStats` event reports the counters while a device is connected.
+pub const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Snapshot of the counters of one session.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionStats {
+    pub device: String,
+    /// When the session started, in milliseconds since the epoch.
+    pub connected_at: u64,
+    /// Protocol messages and their bytes (headers included, before encryption).
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+    /// Items sent or received that were acknowledged or applied.
+    pub transfers_completed: u64,
+    /// Items refused by either side or aborted mid-transfer.
+    pub transfers_failed: u64,
+    /// Round trip of the last answered PING, in milliseconds.
+    pub rtt_ms: Option<u64>,
+    /// Mean round trip of the session's answered PINGs, in milliseconds.
+    pub avg_rtt_ms: Option<u64>,
+}
+
+struct Current {
+    stats: SessionStats,
+    rtt_total: Duration,
+    rtt_samples: u32,
+}
+
+/// Counters of the connected device's session, if any.
+#[derive(Default)]
+pub struct SessionCounters {
+    current: Mutex<Option<Current>>,
+}
+
+impl SessionCounters {
+    /// Start counting from zero for a new session with `device`.
+    pub fn start(&self, device: &str) {
+        *self.current.lock().unwrap() = Some(Current {
+            stats: SessionStats {
+                device: device.to_string(),
+                connected_at: now_millis(),
+                ..Default::default()
+            },
+            rtt_total: Duration::ZERO,
+            rtt_samples: 0,
+        });
+    }
+
+    pub fn end(&self) {
+        *self.current.lock().unwrap() = None;
+    }
+
+    /// The counters so far, or `None` while no device is connected.
+    pub fn snapshot(&self) -> Option<SessionStats> {
+        let current = self.current.lock().unwrap();
+        current.as_ref().map(|current| current.stats.clone())
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Current)) {
+        if let Some(current) = self.current.lock().unwrap().as_mut() {
+            f(current);
+        }
+    }
+
+    pub fn received(&self, msg: &Message) {
+        self.update(|current| {
+            current.stats.messages_in += 1;
+            current.stats.bytes_in += msg.wire_len() as u64;
+        });
+    }
+
+    pub fn sent(&self, msg: &Message) {
+        self.update(|current| {
+            current.stats.messages_out += 1;
+            current.stats.bytes_out += msg.wire_len() as u64;
+        });
+    }
+
+    /// A PONG arrived `rtt` after its PING.
+    pub fn rtt(&self, rtt: Duration) {
+        self.update(|current| {
+            current.rtt_total += rtt;
+            current.rtt_samples += 1;
+            current.stats.rtt_ms = Some(rtt.as_millis() as u64);
+            current.stats.avg_rtt_ms =
+                Some((current.rtt_total / current.rtt_samples).as_millis() as u64);
+        });
+    }
+
+    /// Count the transfer outcome an event reports.
+    pub fn observe(&self, event: &ServerEvent) {
+        match event {
+            ServerEvent::ClipboardReceived { .. }
+            | ServerEvent::ClipboardSent { .. }
+            | ServerEvent::ImageReceived { .. }
+            | ServerEvent::ImageSent { .. } => {
+                self.update(|current| current.stats.transfers_completed += 1);
+            }
+            ServerEvent::TransferRejected { .. } | ServerEvent::ImageTransferFailed { .. } => {
+                self.update(|current| current.stats.transfers_failed += 1);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A transport that counts the messages going through it into `counters`.
+pub struct CountingTransport<'a, T> {
+    inner: T,
+    counters: &'a SessionCounters,
+}
+
+impl<'a, T: MessageTransport> CountingTransport<'a, T> {
+    pub fn new(inner: T, counters: &'a SessionCounters) -> Self {
+        Self { inner, counters }
+    }
+}
+
+impl<T: MessageTransport> MessageTransport for CountingTransport<'_, T> {
+    async fn send_message(&mut self, msg: &Message) -> Result<()> {
+        self.inner.send_message(msg).await?;
+        self.counters.sent(msg);
+        Ok(())
+    }
+
+    async fn recv_message(&mut self) -> Result<Message> {
+        let msg = self.inner.recv_message().await?;
+        self.counters.received(&msg);
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_only_during_a_session() {
+        let counters = SessionCounters::default();
+        counters.sent(&Message::ping());
+        assert_eq!(counters.snapshot(), None);
+
+        counters.start("phone");
+        counters.sent(&Message::ping());
+        counters.received(&Message::clipboard_send("hello"));
+        counters.observe(&ServerEvent::ClipboardSent { chars: 5 });
+        counters.observe(&ServerEvent::ImageTransferFailed {
+            reason: "remote error".to_string(),
+        });
+        counters.observe(&ServerEvent::SensitiveCleared);
+        let stats = counters.snapshot().unwrap();
+        assert_eq!(stats.device, "phone");
+        assert_eq!((stats.messages_out, stats.bytes_out), (1, 5));
+        assert_eq!((stats.messages_in, stats.bytes_in), (1, 10));
+        assert_eq!(stats.transfers_completed, 1);
+        assert_eq!(stats.transfers_failed, 1);
+
+        counters.end();
+        assert_eq!(counters.snapshot(), None);
+    }
+
+    #[test]
+    fn test_rtt_last_and_average() {
+        let counters = SessionCounters::default();
+        counters.start("phone");
+        assert_eq!(counters.snapshot().unwrap().rtt_ms, None);
+
+        counters.rtt(Duration::from_millis(10));
+        counters.rtt(Duration::from_millis(30));
+        let stats = counters.snapshot().unwrap();
+        assert_eq!(stats.rtt_ms, Some(30));
+        assert_eq!(stats.avg_rtt_ms, Some(20));
+
+        // A new session starts over
+        counters.start("phone");
+        assert_eq!(counters.snapshot().unwrap().avg_rtt_ms, None);
+    }
+}
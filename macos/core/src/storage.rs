@@ -1,9 +1,11 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use crate::crypto::Identity;
+use crate::policy::DevicePolicy;
 
 /// Persistent storage for device identity and paired devices.
 pub struct DeviceStore {
@@ -19,6 +21,8 @@ struct StoredIdentity {
 #[derive(serde::Serialize, serde::Deserialize)]
 struct PairedDevices {
     devices: HashMap<String, String>, // name -> public_key (hex)
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    policies: HashMap<String, DevicePolicy>, // name -> policy
 }
 
 impl DeviceStore {
@@ -35,6 +39,11 @@ impl DeviceStore {
         Self::new(dir.data_dir().to_path_buf())
     }
 
+    /// Directory holding the identity, pairings and the daemon's control socket.
+    pub fn base_dir(&self) -> &Path {
+        &self.base_dir
+    }
+
     fn identity_path(&self) -> PathBuf {
         self.base_dir.join("identity.json")
     }
@@ -71,6 +80,7 @@ impl DeviceStore {
         if !path.exists() {
             return Ok(PairedDevices {
                 devices: HashMap::new(),
+                policies: HashMap::new(),
             });
         }
         let data = fs::read_to_string(&path)?;
@@ -112,11 +122,35 @@ impl DeviceStore {
     pub fn remove_paired_device(&self, name: &str) -> Result<bool> {
         let mut devices = self.load_paired_devices()?;
         let removed = devices.devices.remove(name).is_some();
+        devices.policies.remove(name);
         if removed {
             self.save_paired_devices(&devices)?;
         }
         Ok(removed)
     }
+
+    /// When the pairings and policies were last written, or `None` if they never were.
+    pub fn devices_modified(&self) -> Option<SystemTime> {
+        fs::metadata(self.devices_path())
+            .and_then(|meta| meta.modified())
+            .ok()
+    }
+
+    /// Transfer policy for a device, or the default policy if none was set.
+    pub fn device_policy(&self, name: &str) -> Result<DevicePolicy> {
+        let devices = self.load_paired_devices()?;
+        Ok(devices.policies.get(name).cloned().unwrap_or_default())
+    }
+
+    pub fn set_device_policy(&self, name: &str, policy: &DevicePolicy) -> Result<()> {
+        let mut devices = self.load_paired_devices()?;
+        if !devices.devices.contains_key(name) {
+            bail!("device not found: {}", name);
+        }
+        devices.policies.insert(name.to_string(), policy.clone());
+        self.save_paired_devices(&devices)?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -219,4 +253,54 @@ mod tests {
         // Should NOT find with old key
         assert!(store.find_device_by_key(&[1, 2, 3]).unwrap().is_none());
     }
+
+    #[test]
+    fn test_device_policy_roundtrip() {
+        let (_dir, store) = test_store();
+
+        // Unknown devices cannot get a policy
+        assert!(store
+            .set_device_policy("phone", &DevicePolicy::default())
+            .is_err());
+
+        store.save_paired_device("phone", &[1, 2, 3]).unwrap();
+        assert_eq!(
+            store.device_policy("phone").unwrap(),
+            DevicePolicy::default()
+        );
+
+        let policy = DevicePolicy {
+            allow_images: false,
+            max_bytes: Some(1024),
+            direction: crate::policy::Direction::ReceiveOnly,
+            ..Default::default()
+        };
+        store.set_device_policy("phone", &policy).unwrap();
+        assert_eq!(store.device_policy("phone").unwrap(), policy);
+
+        // Re-pairing keeps the policy; unpairing drops it
+        store.save_paired_device("phone", &[4, 5, 6]).unwrap();
+        assert_eq!(store.device_policy("phone").unwrap(), policy);
+        store.remove_paired_device("phone").unwrap();
+        store.save_paired_device("phone", &[1, 2, 3]).unwrap();
+        assert_eq!(
+            store.device_policy("phone").unwrap(),
+            DevicePolicy::default()
+        );
+    }
+
+    #[test]
+    fn test_paired_devices_without_policies_load() {
+        let (dir, store) = test_store();
+        fs::write(
+            dir.path().join("paired_devices.json"),
+            r#"{"devices":{"phone":"010203"}}"#,
+        )
+        .unwrap();
+        assert_eq!(store.list_paired_devices().unwrap().len(), 1);
+        assert_eq!(
+            store.device_policy("phone").unwrap(),
+            DevicePolicy::default()
+        );
+    }
 }
@@ -0,0 +1,1234 @@
+//! End-to-end tests: `run_server` on a loopback socket, driven by a scripted client
+//! that plays the Android side of the protocol.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tempfile::TempDir;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+use uclip_core::clipboard::{encode_rgba_to_png, ClipboardContent, MemoryClipboard};
+use uclip_core::config::Settings;
+use uclip_core::crypto::{self, Identity, NoiseTransport};
+use uclip_core::delivery::Delivery;
+use uclip_core::events::{AppState, ServerEvent};
+use uclip_core::net::BindTarget;
+use uclip_core::policy::DevicePolicy;
+use uclip_core::protocol::{
+    Bye, ClipboardMeta, ErrorPayload, ImageMetadata, Message, MessageType, BYE_SHUTDOWN,
+    IMAGE_CHUNK_SIZE, MAX_IMAGE_SIZE,
+};
+use uclip_core::queue::{self, Outbound, OutboundContent};
+use uclip_core::server;
+use uclip_core::storage::DeviceStore;
+
+const PAIRING_CODE: &str = "123456";
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A running server with a fake clipboard and a subscription to its events.
+struct Harness {
+    addr: SocketAddr,
+    state: Arc<AppState>,
+    clipboard: Arc<MemoryClipboard>,
+    events: broadcast::Receiver<ServerEvent>,
+    cancel: CancellationToken,
+    server: JoinHandle<Result<()>>,
+    _dir: TempDir,
+}
+
+/// Server state on `port` with a fresh identity stored in `dir`.
+fn new_state(dir: &TempDir, port: u16) -> AppState {
+    let store = DeviceStore::new(dir.path().to_path_buf()).unwrap();
+    let identity = Identity::load_or_generate(&store).unwrap();
+    AppState::new(
+        identity,
+        PAIRING_CODE.to_string(),
+        "test-mac".to_string(),
+        store,
+        port,
+    )
+}
+
+impl Harness {
+    async fn start() -> Self {
+        let dir = TempDir::new().unwrap();
+        let clipboard = Arc::new(MemoryClipboard::default());
+
+        let mut state = new_state(&dir, 0);
+        state.clipboard = clipboard.clone();
+        let state = Arc::new(state);
+        let events = state.subscribe();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let server = tokio::spawn(server::run_server(
+            vec![listener],
+            state.clone(),
+            cancel.clone(),
+        ));
+
+        let mut harness = Self {
+            addr,
+            state,
+            clipboard,
+            events,
+            cancel,
+            server,
+            _dir: dir,
+        };
+        harness
+            .expect_event(
+                |e| matches!(e, ServerEvent::ServerStarted { port, .. } if *port == addr.port()),
+            )
+            .await;
+        assert_eq!(harness.state.listen_port(), Some(addr.port()));
+        harness
+    }
+
+    /// Wait for the first event matching `pred`, skipping any others.
+    async fn expect_event(&mut self, pred: impl Fn(&ServerEvent) -> bool) -> ServerEvent {
+        timeout(TIMEOUT, async {
+            loop {
+                let event = self.events.recv().await.expect("event channel closed");
+                if pred(&event) {
+                    return event;
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for event")
+    }
+
+    async fn stop(self) {
+        self.cancel.cancel();
+        let result = timeout(TIMEOUT, self.server)
+            .await
+            .expect("server did not stop");
+        result.unwrap().unwrap();
+    }
+}
+
+/// A paired phone that is not connected.
+struct Offline {
+    identity: Identity,
+    server_key: Vec<u8>,
+    _dir: TempDir,
+}
+
+impl Offline {
+    async fn connect(self, addr: SocketAddr) -> Client {
+        let Self {
+            identity,
+            server_key,
+            _dir,
+        } = self;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let transport = crypto::handshake_paired_initiator(stream, &identity, &server_key)
+            .await
+            .unwrap();
+        let mut client = Client {
+            identity,
+            transport,
+            server_key,
+            _dir,
+        };
+        client.exchange_device_info(None).await;
+        client
+    }
+}
+
+/// The phone side of a session.
+struct Client {
+    identity: Identity,
+    transport: NoiseTransport,
+    /// The server's static key, learned while pairing.
+    server_key: Vec<u8>,
+    _dir: TempDir,
+}
+
+impl Client {
+    fn identity() -> (Identity, TempDir) {
+        let dir = TempDir::new().unwrap();
+        let store = DeviceStore::new(dir.path().to_path_buf()).unwrap();
+        (Identity::load_or_generate(&store).unwrap(), dir)
+    }
+
+    async fn pair(addr: SocketAddr) -> Self {
+        Self::pair_with_window(addr, None).await
+    }
+
+    /// Pair, advertising a receive window for image chunks in DEVICE_INFO.
+    async fn pair_with_window(addr: SocketAddr, window: Option<usize>) -> Self {
+        let (identity, dir) = Self::identity();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (transport, server_key) =
+            crypto::handshake_pairing_initiator(stream, &identity, PAIRING_CODE)
+                .await
+                .unwrap();
+        let mut client = Self {
+            identity,
+            transport,
+            server_key,
+            _dir: dir,
+        };
+        client.exchange_device_info(window).await;
+        client
+    }
+
+    /// Close the session and reconnect with the same identity (Noise KK).
+    async fn reconnect(self, addr: SocketAddr) -> Self {
+        self.hang_up().connect(addr).await
+    }
+
+    /// Close the connection, keeping what is needed to reconnect.
+    fn hang_up(self) -> Offline {
+        let Self {
+            identity,
+            transport,
+            server_key,
+            _dir,
+        } = self;
+        drop(transport);
+        Offline {
+            identity,
+            server_key,
+            _dir,
+        }
+    }
+
+    async fn exchange_device_info(&mut self, window: Option<usize>) {
+        let info = self.recv().await;
+        assert_eq!(info.msg_type, MessageType::DeviceInfo);
+        assert!(info.payload_text().unwrap().contains("test-mac"));
+        self.send(Message::device_info("test-phone", window)).await;
+    }
+
+    async fn send(&mut self, msg: Message) {
+        self.transport.send_message(&msg).await.unwrap();
+    }
+
+    async fn recv(&mut self) -> Message {
+        timeout(TIMEOUT, self.transport.recv_message())
+            .await
+            .expect("timed out waiting for message")
+            .unwrap()
+    }
+
+    async fn recv_error(&mut self) -> ErrorPayload {
+        let msg = self.recv().await;
+        assert_eq!(msg.msg_type, MessageType::Error);
+        serde_json::from_str(&msg.payload_text().unwrap()).expect("ERROR payload is JSON")
+    }
+
+    async fn send_image(&mut self, png: &[u8], width: u32, height: u32) {
+        let metadata = serde_json::json!({
+            "width": width,
+            "height": height,
+            "totalBytes": png.len(),
+            "mimeType": "image/png"
+        });
+        self.send(Message::image_send_start(&metadata.to_string()))
+            .await;
+        for chunk in png.chunks(1000) {
+            self.send(Message::image_chunk(chunk)).await;
+        }
+        self.send(Message::image_send_end()).await;
+    }
+}
+
+fn connected_name(event: &ServerEvent) -> Option<String> {
+    match event {
+        ServerEvent::DeviceConnected { name } => Some(name.clone()),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn test_pairing_stores_device_and_receives_text() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+    let name = connected_name(&event).unwrap();
+    assert!(name.starts_with("device-"));
+    assert!(h
+        .state
+        .store
+        .find_device_by_key(&client.identity.public_key)
+        .unwrap()
+        .is_some());
+    assert_eq!(client.server_key, h.state.identity.public_key);
+
+    client
+        .send(Message::clipboard_send("hello from phone"))
+        .await;
+    assert_eq!(client.recv().await.msg_type, MessageType::ClipboardAck);
+    assert_eq!(
+        h.clipboard.content(),
+        Some(ClipboardContent::Text("hello from phone".to_string()))
+    );
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::ClipboardReceived { .. }))
+        .await;
+    assert!(matches!(
+        event,
+        ServerEvent::ClipboardReceived {
+            chars: 16,
+            sensitive: false,
+            ..
+        }
+    ));
+
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_reconnect_with_paired_key() {
+    let mut h = Harness::start().await;
+    let client = Client::pair(h.addr).await;
+    let paired = connected_name(
+        &h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+            .await,
+    )
+    .unwrap();
+
+    let mut client = client.reconnect(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceDisconnected { .. }))
+        .await;
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+    assert_eq!(connected_name(&event).unwrap(), paired);
+    assert_eq!(h.state.store.list_paired_devices().unwrap().len(), 1);
+
+    client.send(Message::clipboard_send("again")).await;
+    assert_eq!(client.recv().await.msg_type, MessageType::ClipboardAck);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_dropped_connection_before_handshake() {
+    let mut h = Harness::start().await;
+    drop(TcpStream::connect(h.addr).await.unwrap());
+    h.expect_event(|e| matches!(e, ServerEvent::HandshakeFailed { .. }))
+        .await;
+
+    // The server keeps accepting afterwards
+    let _client = Client::pair(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_wrong_pairing_code_is_rejected() {
+    let mut h = Harness::start().await;
+    let (identity, _dir) = Client::identity();
+    let stream = TcpStream::connect(h.addr).await.unwrap();
+    let result = crypto::handshake_pairing_initiator(stream, &identity, "654321").await;
+    if let Ok((mut transport, _)) = result {
+        // The responder only notices on the final message; it then drops the connection
+        assert!(transport.recv_message().await.is_err());
+    }
+    h.expect_event(|e| matches!(e, ServerEvent::HandshakeFailed { .. }))
+        .await;
+    assert!(h.state.store.list_paired_devices().unwrap().is_empty());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_pairing_refused_while_window_closed() {
+    let mut h = Harness::start().await;
+    h.state.pairing.close();
+    let (identity, _dir) = Client::identity();
+    let stream = TcpStream::connect(h.addr).await.unwrap();
+    let result =
+        crypto::handshake_pairing_initiator(stream, &identity, &h.state.pairing_code).await;
+    assert!(result.is_err());
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::HandshakeFailed { .. }))
+        .await;
+    assert!(matches!(
+        event,
+        ServerEvent::HandshakeFailed { reason, .. } if reason.contains("pairing is closed")
+    ));
+    assert!(h.state.store.list_paired_devices().unwrap().is_empty());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_unknown_device_cannot_reconnect() {
+    let mut h = Harness::start().await;
+    let (identity, _dir) = Client::identity();
+    let stream = TcpStream::connect(h.addr).await.unwrap();
+    let result =
+        crypto::handshake_paired_initiator(stream, &identity, &h.state.identity.public_key).await;
+    assert!(result.is_err());
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::HandshakeFailed { .. }))
+        .await;
+    assert!(matches!(
+        event,
+        ServerEvent::HandshakeFailed { reason, .. } if reason.contains("unknown device")
+    ));
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_receives_chunked_image() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+
+    let rgba: Vec<u8> = (0..40 * 30 * 4).map(|i| (i % 251) as u8).collect();
+    let png = encode_rgba_to_png(&rgba, 40, 30).unwrap();
+    client.send_image(&png, 40, 30).await;
+
+    assert_eq!(client.recv().await.msg_type, MessageType::ImageAck);
+    assert_eq!(
+        h.clipboard.content(),
+        Some(ClipboardContent::Image {
+            rgba,
+            width: 40,
+            height: 30,
+        })
+    );
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::ImageReceived { .. }))
+        .await;
+    assert!(matches!(
+        event,
+        ServerEvent::ImageReceived { width: 40, height: 30, bytes } if bytes == png.len()
+    ));
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_image_rejected_while_text_is_parked() {
+    let mut h = Harness::start().await;
+    let mut settings = h.state.settings();
+    settings.confirm.enabled = true;
+    h.state.set_settings(settings);
+    let mut client = Client::pair(h.addr).await;
+
+    client.send(Message::clipboard_send("awaiting")).await;
+    h.expect_event(|e| matches!(e, ServerEvent::ConfirmationRequested { .. }))
+        .await;
+
+    let rgba: Vec<u8> = (0..40 * 30 * 4).map(|i| (i % 251) as u8).collect();
+    let png = encode_rgba_to_png(&rgba, 40, 30).unwrap();
+    client.send_image(&png, 40, 30).await;
+    assert_eq!(client.recv_error().await.code, "transfer_in_progress");
+
+    // Its chunks and end are dropped quietly, not answered with id-less errors
+    client.send(Message::ping()).await;
+    let reply = loop {
+        let msg = client.recv().await;
+        if msg.msg_type != MessageType::Ping {
+            break msg;
+        }
+    };
+    assert_eq!(reply.msg_type, MessageType::Pong);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_receive_window_credit_is_granted() {
+    let h = Harness::start().await;
+    let mut client = Client::pair_with_window(h.addr, Some(IMAGE_CHUNK_SIZE)).await;
+
+    let rgba: Vec<u8> = (0..40 * 30 * 4).map(|i| (i % 251) as u8).collect();
+    let png = encode_rgba_to_png(&rgba, 40, 30).unwrap();
+    client.send_image(&png, 40, 30).await;
+
+    // Less than a quarter of the window: all credit comes back at the end of the image
+    let update = client.recv().await;
+    assert_eq!(update.msg_type, MessageType::WindowUpdate);
+    assert_eq!(update.window_credit().unwrap(), png.len());
+    assert_eq!(client.recv().await.msg_type, MessageType::ImageAck);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_outbound_chunks_wait_for_window() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair_with_window(h.addr, Some(IMAGE_CHUNK_SIZE)).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+
+    let tx = h.state.session_tx.read().await.clone().unwrap();
+    let image = vec![7u8; 3 * IMAGE_CHUNK_SIZE];
+    let state = h.state.clone();
+    let sender = tokio::spawn(async move {
+        server::send_image_chunks(&tx, &image, "image/png", 1, 1, &state)
+            .await
+            .unwrap()
+    });
+
+    assert_eq!(client.recv().await.msg_type, MessageType::ImageSendStart);
+    for chunk in 1..=3 {
+        let msg = client.recv().await;
+        assert_eq!(msg.msg_type, MessageType::ImageChunk);
+        let event = h
+            .expect_event(|e| matches!(e, ServerEvent::ImageTransferProgress { .. }))
+            .await;
+        assert!(matches!(
+            event,
+            ServerEvent::ImageTransferProgress { bytes_transferred, bytes_total }
+                if bytes_transferred as usize == chunk * IMAGE_CHUNK_SIZE
+                    && bytes_total as usize == 3 * IMAGE_CHUNK_SIZE
+        ));
+        if chunk < 3 {
+            // The window is full: nothing more until we grant credit
+            let held = timeout(Duration::from_millis(200), client.transport.recv_message()).await;
+            assert!(held.is_err(), "chunk sent without window");
+            client.send(Message::window_update(msg.payload.len())).await;
+        }
+    }
+    assert_eq!(client.recv().await.msg_type, MessageType::ImageSendEnd);
+    sender.await.unwrap();
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_image_fails_when_window_stalls() {
+    let mut h = Harness::start().await;
+    h.state.set_settings(Settings {
+        window_stall_timeout: Duration::from_millis(300),
+        ..Default::default()
+    });
+    let mut client = Client::pair_with_window(h.addr, Some(IMAGE_CHUNK_SIZE)).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+
+    let tx = h.state.session_tx.read().await.clone().unwrap();
+    let image = vec![7u8; 3 * IMAGE_CHUNK_SIZE];
+    let state = h.state.clone();
+    let sender = tokio::spawn(async move {
+        let pending = server::send_image_chunks(&tx, &image, "image/png", 1, 1, &state)
+            .await
+            .unwrap();
+        server::send_text(&tx, "after", false, &state)
+            .await
+            .unwrap();
+        pending
+    });
+
+    assert_eq!(client.recv().await.msg_type, MessageType::ImageSendStart);
+    assert_eq!(client.recv().await.msg_type, MessageType::ImageChunk);
+    // Never grant credit: the image is abandoned and what was queued behind it goes out
+    assert_eq!(client.recv_error().await.code, "window_stalled");
+    h.expect_event(|e| matches!(e, ServerEvent::ImageTransferFailed { .. }))
+        .await;
+    let pending = sender.await.unwrap();
+    assert_eq!(pending.outcome(TIMEOUT).await, Delivery::TimedOut);
+    assert_eq!(client.recv().await.msg_type, MessageType::ClipboardMeta);
+    let text = client.recv().await;
+    assert_eq!(text.msg_type, MessageType::ClipboardSend);
+    assert_eq!(text.payload_text().unwrap(), "after");
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_protocol_errors_keep_session_open() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+
+    client.send(Message::image_chunk(&[1, 2, 3])).await;
+    assert_eq!(client.recv_error().await.code, "no_active_transfer");
+
+    client.send(Message::image_send_start("{not json")).await;
+    assert_eq!(client.recv_error().await.code, "invalid_metadata");
+
+    let metadata = serde_json::json!({
+        "width": 1,
+        "height": 1,
+        "totalBytes": MAX_IMAGE_SIZE + 1,
+        "mimeType": "image/png"
+    });
+    client
+        .send(Message::image_send_start(&metadata.to_string()))
+        .await;
+    assert_eq!(client.recv_error().await.code, "too_large");
+
+    // Garbage that isn't an image still gets an ERROR, not a dropped session
+    client.send_image(&[0u8; 64], 1, 1).await;
+    assert_eq!(client.recv_error().await.code, "clipboard_error");
+    h.expect_event(|e| matches!(e, ServerEvent::ImageTransferFailed { .. }))
+        .await;
+
+    client.send(Message::ping()).await;
+    assert_eq!(client.recv().await.msg_type, MessageType::Pong);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_policy_rejection_is_reported() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+    let name = connected_name(
+        &h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+            .await,
+    )
+    .unwrap();
+    let policy = DevicePolicy {
+        allow_text: false,
+        ..Default::default()
+    };
+    h.state.store.set_device_policy(&name, &policy).unwrap();
+
+    client.send(Message::clipboard_send("blocked")).await;
+    let error = client.recv_error().await;
+    assert_eq!(error.code, "content_type_not_allowed");
+    assert_eq!(error.message, "text content is not allowed");
+    assert_eq!(h.clipboard.content(), None);
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::TransferRejected { .. }))
+        .await;
+    assert!(matches!(
+        event,
+        ServerEvent::TransferRejected { incoming: true, code, .. } if code == "content_type_not_allowed"
+    ));
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_unreadable_policy_keeps_session_open() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+
+    // The policy loaded at connect stays in force
+    let devices = h.state.store.base_dir().join("paired_devices.json");
+    std::fs::write(&devices, "{not json").unwrap();
+    client.send(Message::clipboard_send("still here")).await;
+    assert_eq!(client.recv().await.msg_type, MessageType::ClipboardAck);
+    assert_eq!(
+        h.clipboard.content(),
+        Some(ClipboardContent::Text("still here".to_string()))
+    );
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_outbound_text_reaches_client() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+
+    let tx = h.state.session_tx.read().await.clone().unwrap();
+    tx.send(Message::clipboard_send("from mac")).await.unwrap();
+    let msg = client.recv().await;
+    assert_eq!(msg.msg_type, MessageType::ClipboardSend);
+    assert_eq!(msg.payload_text().unwrap(), "from mac");
+
+    client.send(Message::clipboard_ack(None)).await;
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::ClipboardSent { .. }))
+        .await;
+    assert!(matches!(event, ServerEvent::ClipboardSent { chars: 8 }));
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_ack_resolves_delivery_by_id() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+
+    let tx = h.state.session_tx.read().await.clone().unwrap();
+    let first = server::send_text(&tx, "first", false, &h.state)
+        .await
+        .unwrap();
+    let second = server::send_text(&tx, "second", false, &h.state)
+        .await
+        .unwrap();
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let meta = client.recv().await;
+        assert_eq!(meta.msg_type, MessageType::ClipboardMeta);
+        ids.push(
+            ClipboardMeta::from_json(&meta.payload_text().unwrap())
+                .unwrap()
+                .id
+                .unwrap(),
+        );
+        assert_eq!(client.recv().await.msg_type, MessageType::ClipboardSend);
+    }
+    assert_eq!(ids, [first.id, second.id]);
+
+    // Answer out of order: the IDs say which is which
+    client.send(Message::clipboard_ack(Some(second.id))).await;
+    let refusal = ErrorPayload::new("declined", "user declined").with_id(Some(first.id));
+    client.send(Message::error_payload(&refusal)).await;
+
+    assert_eq!(second.outcome(TIMEOUT).await, Delivery::Delivered);
+    assert_eq!(
+        first.outcome(TIMEOUT).await,
+        Delivery::Rejected {
+            code: "declined".to_string(),
+            reason: "user declined".to_string()
+        }
+    );
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::ClipboardSent { .. }))
+        .await;
+    assert!(matches!(event, ServerEvent::ClipboardSent { chars: 6 }));
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_ack_echoes_incoming_id() {
+    let h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+
+    let meta = ClipboardMeta {
+        sensitive: false,
+        id: Some(41),
+    };
+    client.send(Message::clipboard_meta(&meta)).await;
+    client.send(Message::clipboard_send("with id")).await;
+    let ack = client.recv().await;
+    assert_eq!(ack.msg_type, MessageType::ClipboardAck);
+    assert_eq!(ack.ack_id(), Some(41));
+
+    // Without CLIPBOARD_META the ACK stays empty
+    client.send(Message::clipboard_send("legacy")).await;
+    let ack = client.recv().await;
+    assert_eq!(ack.msg_type, MessageType::ClipboardAck);
+    assert!(ack.payload.is_empty());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_metadata_applies_to_the_next_message_only() {
+    let h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+
+    // Invalid metadata is ignored, not fatal
+    client
+        .send(Message::new(
+            MessageType::ClipboardMeta,
+            b"{not json".to_vec(),
+        ))
+        .await;
+    client.send(Message::clipboard_send("first")).await;
+    let ack = client.recv().await;
+    assert_eq!(ack.msg_type, MessageType::ClipboardAck);
+    assert_eq!(ack.ack_id(), None);
+
+    // Metadata followed by anything but CLIPBOARD_SEND is dropped
+    let meta = ClipboardMeta {
+        sensitive: true,
+        id: Some(9),
+    };
+    client.send(Message::clipboard_meta(&meta)).await;
+    client.send(Message::ping()).await;
+    assert_eq!(client.recv().await.msg_type, MessageType::Pong);
+    client.send(Message::clipboard_send("second")).await;
+    let ack = client.recv().await;
+    assert_eq!(ack.msg_type, MessageType::ClipboardAck);
+    assert_eq!(ack.ack_id(), None);
+    assert_eq!(h.state.history.read().await.len(), 2);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_empty_ack_resolves_oldest_delivery() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+
+    let tx = h.state.session_tx.read().await.clone().unwrap();
+    let png = encode_rgba_to_png(&[0u8; 4 * 2 * 2], 2, 2).unwrap();
+    let image = server::send_image_chunks(&tx, &png, "image/png", 2, 2, &h.state)
+        .await
+        .unwrap();
+    let text = server::send_text(&tx, "hi", false, &h.state).await.unwrap();
+    let start = client.recv().await;
+    let meta = ImageMetadata::from_json(&start.payload_text().unwrap()).unwrap();
+    assert_eq!(meta.id, Some(image.id));
+
+    // A peer without message IDs: empty ACKs matched by kind, oldest first
+    client.send(Message::clipboard_ack(None)).await;
+    assert_eq!(text.outcome(TIMEOUT).await, Delivery::Delivered);
+    client.send(Message::image_ack(None)).await;
+    assert_eq!(image.outcome(TIMEOUT).await, Delivery::Delivered);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_unacknowledged_delivery_fails_on_disconnect() {
+    let mut h = Harness::start().await;
+    let client = Client::pair(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+
+    let tx = h.state.session_tx.read().await.clone().unwrap();
+    let pending = server::send_text(&tx, "lost", false, &h.state)
+        .await
+        .unwrap();
+    drop(client);
+    assert_eq!(pending.outcome(TIMEOUT).await, Delivery::Disconnected);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_outbound_refusal_is_reported() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+
+    let tx = h.state.session_tx.read().await.clone().unwrap();
+    tx.send(Message::clipboard_send("from mac")).await.unwrap();
+    client.recv().await;
+    client
+        .send(Message::error_with_code("declined", "user declined"))
+        .await;
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::TransferRejected { .. }))
+        .await;
+    assert!(matches!(
+        event,
+        ServerEvent::TransferRejected { incoming: false, code, reason, .. }
+            if code == "declined" && reason == "user declined"
+    ));
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_refusal_by_id_leaves_inbound_image_running() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+
+    let rgba = vec![7u8; 4 * 3 * 2];
+    let png = encode_rgba_to_png(&rgba, 3, 2).unwrap();
+    let metadata = serde_json::json!({
+        "width": 3,
+        "height": 2,
+        "totalBytes": png.len(),
+        "mimeType": "image/png"
+    });
+    client
+        .send(Message::image_send_start(&metadata.to_string()))
+        .await;
+    h.expect_event(|e| matches!(e, ServerEvent::ImageTransferProgress { .. }))
+        .await;
+
+    // Refuse our text by its ID while the phone is still sending its image
+    let tx = h.state.session_tx.read().await.clone().unwrap();
+    let pending = server::send_text(&tx, "héllo", false, &h.state)
+        .await
+        .unwrap();
+    assert_eq!(client.recv().await.msg_type, MessageType::ClipboardMeta);
+    assert_eq!(client.recv().await.msg_type, MessageType::ClipboardSend);
+    let refusal = ErrorPayload::new("declined", "user declined").with_id(Some(pending.id));
+    client.send(Message::error_payload(&refusal)).await;
+    assert!(matches!(
+        pending.outcome(TIMEOUT).await,
+        Delivery::Rejected { code, .. } if code == "declined"
+    ));
+
+    for chunk in png.chunks(1000) {
+        client.send(Message::image_chunk(chunk)).await;
+    }
+    client.send(Message::image_send_end()).await;
+    assert_eq!(client.recv().await.msg_type, MessageType::ImageAck);
+    assert_eq!(
+        h.clipboard.content(),
+        Some(ClipboardContent::Image {
+            rgba,
+            width: 3,
+            height: 2,
+        })
+    );
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_sent_text_is_counted_in_chars() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+
+    let tx = h.state.session_tx.read().await.clone().unwrap();
+    tx.send(Message::clipboard_send("naïve café"))
+        .await
+        .unwrap();
+    client.recv().await;
+    client.send(Message::clipboard_ack(None)).await;
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::ClipboardSent { .. }))
+        .await;
+    assert!(matches!(event, ServerEvent::ClipboardSent { chars: 10 }));
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_queued_items_delivered_in_order_on_reconnect() {
+    let mut h = Harness::start().await;
+    let mut settings = h.state.settings();
+    settings.queue.enabled = true;
+    h.state.set_settings(settings);
+    tokio::spawn(queue::run_delivery(h.state.clone(), h.cancel.clone()));
+
+    let offline = Client::pair(h.addr).await.hang_up();
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceDisconnected { .. }))
+        .await;
+    for text in ["first", "second"] {
+        let content = OutboundContent::Text {
+            text: text.to_string(),
+            sensitive: false,
+        };
+        let outbound = queue::send_or_queue(&h.state, None, content, None)
+            .await
+            .unwrap();
+        assert!(matches!(outbound, Some(Outbound::Queued(_))));
+    }
+
+    // Each item goes out once the previous one is acknowledged
+    let mut client = offline.connect(h.addr).await;
+    for expected in ["first", "second"] {
+        let meta = client.recv().await;
+        assert_eq!(meta.msg_type, MessageType::ClipboardMeta);
+        let id = ClipboardMeta::from_json(&meta.payload_text().unwrap())
+            .unwrap()
+            .id;
+        let msg = client.recv().await;
+        assert_eq!(msg.msg_type, MessageType::ClipboardSend);
+        assert_eq!(msg.payload_text().unwrap(), expected);
+        client.send(Message::clipboard_ack(id)).await;
+    }
+    h.expect_event(|e| matches!(e, ServerEvent::QueueChanged { pending: 0, .. }))
+        .await;
+    assert!(h.state.queue.items(None).unwrap().is_empty());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_concurrent_sends_do_not_interleave() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+
+    // More messages than the session channel holds, so the senders wait on each other
+    let sends: Vec<_> = (0..12usize)
+        .map(|i| {
+            let state = h.state.clone();
+            tokio::spawn(async move {
+                let content = OutboundContent::Text {
+                    text: format!("text {}", i),
+                    sensitive: i.is_multiple_of(2),
+                };
+                match queue::send_or_queue(&state, None, content, None).await {
+                    Ok(Some(Outbound::Sent { pending, .. })) => pending.outcome(TIMEOUT).await,
+                    _ => panic!("text {} was not sent", i),
+                }
+            })
+        })
+        .collect();
+
+    // Every META is followed by its own text
+    for _ in 0..12 {
+        let meta = loop {
+            let msg = client.recv().await;
+            if msg.msg_type != MessageType::Ping {
+                break msg;
+            }
+        };
+        assert_eq!(meta.msg_type, MessageType::ClipboardMeta);
+        let meta = ClipboardMeta::from_json(&meta.payload_text().unwrap()).unwrap();
+        let msg = client.recv().await;
+        assert_eq!(msg.msg_type, MessageType::ClipboardSend);
+        let text = msg.payload_text().unwrap();
+        let i: usize = text.strip_prefix("text ").unwrap().parse().unwrap();
+        assert_eq!(
+            meta.sensitive,
+            i.is_multiple_of(2),
+            "meta of another text before {}",
+            text
+        );
+        client.send(Message::clipboard_ack(meta.id)).await;
+    }
+    for send in sends {
+        assert_eq!(send.await.unwrap(), Delivery::Delivered);
+    }
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_unreadable_queued_item_is_dropped() {
+    let mut h = Harness::start().await;
+    let mut settings = h.state.settings();
+    settings.queue.enabled = true;
+    h.state.set_settings(settings);
+    tokio::spawn(queue::run_delivery(h.state.clone(), h.cancel.clone()));
+
+    let offline = Client::pair(h.addr).await.hang_up();
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceDisconnected { .. }))
+        .await;
+    let image = OutboundContent::Image {
+        bytes: vec![1, 2, 3],
+        mime_type: "image/png".to_string(),
+        width: 1,
+        height: 1,
+    };
+    let Some(Outbound::Queued(item)) = queue::send_or_queue(&h.state, None, image, None)
+        .await
+        .unwrap()
+    else {
+        panic!("image not queued");
+    };
+    let text = OutboundContent::Text {
+        text: "after".to_string(),
+        sensitive: false,
+    };
+    queue::send_or_queue(&h.state, None, text, None)
+        .await
+        .unwrap();
+    let data = h
+        .state
+        .store
+        .base_dir()
+        .join("queue")
+        .join(format!("{}.bin", item.id));
+    std::fs::remove_file(data).unwrap();
+
+    // The missing image is dropped instead of blocking the text behind it
+    let mut client = offline.connect(h.addr).await;
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::TransferRejected { .. }))
+        .await;
+    assert!(matches!(
+        event,
+        ServerEvent::TransferRejected { incoming: false, code, .. } if code == "undeliverable"
+    ));
+    assert_eq!(client.recv().await.msg_type, MessageType::ClipboardMeta);
+    let msg = client.recv().await;
+    assert_eq!(msg.payload_text().unwrap(), "after");
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_cancel_ends_active_session() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+
+    h.cancel.cancel();
+    let bye = client.recv().await;
+    assert_eq!(bye.msg_type, MessageType::Bye);
+    let bye = Bye::from_json(&bye.payload_text().unwrap()).unwrap();
+    assert_eq!(bye.reason, BYE_SHUTDOWN);
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::DeviceDisconnected { .. }))
+        .await;
+    assert!(matches!(
+        event,
+        ServerEvent::DeviceDisconnected { reason: Some(reason), .. } if reason == BYE_SHUTDOWN
+    ));
+    assert!(timeout(TIMEOUT, client.transport.recv_message())
+        .await
+        .unwrap()
+        .is_err());
+    assert!(h.state.session_tx.read().await.is_none());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_unanswered_ping_closes_session() {
+    let mut h = Harness::start().await;
+    h.state.set_settings(Settings {
+        keepalive: Duration::from_millis(100),
+        pong_timeout: Duration::from_millis(300),
+        ..Default::default()
+    });
+    let mut client = Client::pair(h.addr).await;
+
+    // PINGs come on schedule and answering them keeps the session up
+    for _ in 0..3 {
+        assert_eq!(client.recv().await.msg_type, MessageType::Ping);
+        client.send(Message::pong()).await;
+    }
+    assert_eq!(client.recv().await.msg_type, MessageType::Ping);
+
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::PeerUnresponsive { .. }))
+        .await;
+    assert!(matches!(
+        event,
+        ServerEvent::PeerUnresponsive { name, .. } if name.starts_with("device-")
+    ));
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::DeviceDisconnected { .. }))
+        .await;
+    assert!(matches!(
+        event,
+        ServerEvent::DeviceDisconnected { reason: None, .. }
+    ));
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_any_message_counts_as_alive() {
+    let h = Harness::start().await;
+    h.state.set_settings(Settings {
+        keepalive: Duration::from_millis(100),
+        pong_timeout: Duration::from_millis(300),
+        ..Default::default()
+    });
+    let mut client = Client::pair(h.addr).await;
+    assert_eq!(client.recv().await.msg_type, MessageType::Ping);
+
+    // No PONG, but a steady stream of other messages for well past the timeout
+    for _ in 0..8 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client.send(Message::ping()).await;
+        assert_eq!(client.recv().await.msg_type, MessageType::Pong);
+    }
+    assert!(h.state.session_tx.read().await.is_some());
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_reload_reschedules_pings() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+
+    // The default schedule would not PING within the test timeout
+    h.state.set_settings(Settings {
+        keepalive: Duration::from_millis(100),
+        ..Default::default()
+    });
+    h.state.emit(ServerEvent::ConfigReloaded {
+        restart_required: Vec::new(),
+    });
+    assert_eq!(client.recv().await.msg_type, MessageType::Ping);
+    client.send(Message::pong()).await;
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_session_stats_track_traffic_and_rtt() {
+    let mut h = Harness::start().await;
+    h.state.set_settings(Settings {
+        keepalive: Duration::from_millis(100),
+        ..Default::default()
+    });
+    let mut client = Client::pair(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+
+    assert_eq!(client.recv().await.msg_type, MessageType::Ping);
+    client.send(Message::pong()).await;
+    client.send(Message::clipboard_send("counted")).await;
+    assert_eq!(client.recv().await.msg_type, MessageType::ClipboardAck);
+    // The ACK goes out before the transfer is counted; the event is emitted after
+    h.expect_event(|e| matches!(e, ServerEvent::ClipboardReceived { .. }))
+        .await;
+
+    let stats = h.state.stats.snapshot().unwrap();
+    assert!(stats.device.starts_with("device-"));
+    // DEVICE_INFO, PONG and the text in; DEVICE_INFO, PING and the ACK (and maybe the next
+    // PING) out
+    assert_eq!(stats.messages_in, 3);
+    assert!(stats.messages_out >= 3);
+    assert!(stats.bytes_in > "counted".len() as u64);
+    assert_eq!(stats.transfers_completed, 1);
+    assert_eq!(stats.transfers_failed, 0);
+    assert!(stats.rtt_ms.is_some());
+
+    drop(client);
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceDisconnected { .. }))
+        .await;
+    assert_eq!(h.state.stats.snapshot(), None);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_peer_bye_is_clean_disconnect() {
+    let mut h = Harness::start().await;
+    let mut client = Client::pair(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+
+    client
+        .send(Message::bye(&Bye::new(BYE_SHUTDOWN, "phone going away")))
+        .await;
+    let event = h
+        .expect_event(|e| matches!(e, ServerEvent::DeviceDisconnected { .. }))
+        .await;
+    assert!(matches!(
+        event,
+        ServerEvent::DeviceDisconnected { reason: Some(reason), .. } if reason == BYE_SHUTDOWN
+    ));
+
+    // The server keeps accepting sessions
+    let _client = client.reconnect(h.addr).await;
+    h.expect_event(|e| matches!(e, ServerEvent::DeviceConnected { .. }))
+        .await;
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn test_start_falls_back_to_free_port() {
+    let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let taken_port = taken.local_addr().unwrap().port();
+    let dir = TempDir::new().unwrap();
+    let mut state = new_state(&dir, taken_port);
+    state.bind = vec![BindTarget::Addr("127.0.0.1".parse().unwrap())];
+    let state = Arc::new(state);
+    let mut events = state.subscribe();
+    let cancel = CancellationToken::new();
+    let server = tokio::spawn(server::start(state.clone(), cancel.clone()));
+
+    let started = timeout(TIMEOUT, events.recv()).await.unwrap().unwrap();
+    let ServerEvent::ServerStarted { port, .. } = started else {
+        panic!("unexpected event: {:?}", started);
+    };
+    assert_ne!(port, taken_port);
+    assert_eq!(state.listen_port(), Some(port));
+    TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+
+    cancel.cancel();
+    timeout(TIMEOUT, server).await.unwrap().unwrap().unwrap();
+    assert_eq!(state.listen_port(), None);
+}
+
+#[tokio::test]
+async fn test_bind_failure_is_reported() {
+    let dir = TempDir::new().unwrap();
+    let mut state = new_state(&dir, 0);
+    state.bind = vec![BindTarget::Interface("no-such-iface0".to_string())];
+    let state = Arc::new(state);
+    let mut events = state.subscribe();
+
+    let result = server::start(state.clone(), CancellationToken::new()).await;
+    assert!(result.is_err());
+    match events.try_recv().unwrap() {
+        ServerEvent::BindFailed { reason, .. } => assert!(reason.contains("no-such-iface0")),
+        other => panic!("unexpected event: {:?}", other),
+    }
+    assert_eq!(state.listen_port(), None);
+    assert!(state.bind_error().unwrap().contains("no-such-iface0"));
+}